@@ -0,0 +1,365 @@
+/* This module implements a memory budget shared by all mappings in the process.
+ *
+ * Handlers keep each of their mappings under a limit of their own (see MAX_LOADED_SIZE in
+ * heuristics.rs), but with many mappings open that adds up to more than the machine has. The
+ * budget caps what all mappings together keep loaded.
+ *
+ * Every page put into a mapping is recorded here along with a stamp of when it was last used: when
+ * it was loaded, or later when the mapping finds out it is still being used (the same accesses its
+ * eviction policy hears about, see MMapHandler::accessed()). Every page evicted is forgotten. When
+ * the pages of all mappings together go over the budget, the pages least recently used, of
+ * whichever mappings they are, go until the total is a bit below the budget. A mapping whose pages
+ * are all in use keeps them while another has pages nobody has touched in a while. Each mapping
+ * is told which of its pages go (MMapHandler::budget_evicted()) so that it can forget them.
+ *
+ * Pages that cannot be evicted (modified or pinned) stay and still count, so the total can stay
+ * over the budget for a while. They go to the back of the line so that they are not picked again
+ * right away.
+ *
+ * Pages are tracked whether there is a budget or not, so that what each mapping uses can be
+ * reported. Copies of mappings in forked children are not tracked; they are not our memory.
+ */
+
+use rand::{thread_rng, Rng};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Weak};
+
+lazy_static! {
+    static ref BUDGET: Mutex<Budget> = Mutex::new(Budget::new());
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(1);
+}
+
+// Something that has pages the budget can evict.
+pub trait BudgetTarget: Send + Sync {
+    // Evicts these pages, those of them it can, and reports what it evicted with evicted() and
+    // what it had to keep with touched().
+    fn evict_for_budget(&self, pages: BTreeSet<usize>);
+}
+
+struct Budget {
+    // In bytes. None means there is no budget.
+    limit: Option<usize>,
+    // Bytes loaded in all mappings together.
+    used: usize,
+    mappings: BTreeMap<u64, Usage>,
+    next_stamp: u64,
+    // Whether some thread is evicting for the budget right now. Others leave it to that one
+    // rather than evicting the same amount again.
+    enforcing: bool,
+}
+
+struct Usage {
+    page_size: usize,
+    // page -> stamp of when it was last used
+    pages: BTreeMap<usize, u64>,
+    // The same the other way around, least recently used first.
+    order: BTreeMap<u64, usize>,
+    target: Weak<dyn BudgetTarget>,
+}
+
+// Sets how many bytes all mappings together may keep loaded. None means no limit (the default).
+// Mappings that are over the new budget are trimmed the next time a page is loaded.
+pub fn set_memory_budget(limit: Option<usize>) {
+    BUDGET.lock().unwrap().limit = limit;
+}
+
+pub fn memory_budget() -> Option<usize> {
+    BUDGET.lock().unwrap().limit
+}
+
+// How many bytes all mappings together have loaded.
+pub fn memory_used() -> usize {
+    BUDGET.lock().unwrap().used
+}
+
+// Returns an id to register a mapping with.
+pub(crate) fn new_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) fn register(id: u64, page_size: usize, target: Weak<dyn BudgetTarget>) {
+    BUDGET.lock().unwrap().register(id, page_size, target);
+}
+
+// Forgets a mapping and everything it has loaded.
+pub(crate) fn unregister(id: u64) {
+    BUDGET.lock().unwrap().unregister(id);
+}
+
+// Records that pages [start_page, end_page) of a mapping have been loaded. Pages already loaded
+// keep their place in line.
+pub(crate) fn loaded(id: u64, start_page: usize, end_page: usize) {
+    BUDGET.lock().unwrap().loaded(id, start_page, end_page);
+}
+
+// Records that pages [start_page, end_page) of a mapping have been used, or could not be evicted.
+// They go to the back of the line. Pages that aren't loaded are left alone.
+pub(crate) fn touched(id: u64, start_page: usize, end_page: usize) {
+    BUDGET.lock().unwrap().touched(id, start_page, end_page);
+}
+
+pub(crate) fn evicted(id: u64, pages: &BTreeSet<usize>) {
+    BUDGET.lock().unwrap().evicted(id, pages);
+}
+
+// Bytes a mapping has loaded.
+pub(crate) fn usage(id: u64) -> usize {
+    BUDGET.lock().unwrap().usage(id)
+}
+
+// Picks up to `count` loaded pages of a mapping, roughly at random.
+pub(crate) fn sample(id: u64, count: usize) -> BTreeSet<usize> {
+    BUDGET.lock().unwrap().sample(id, count)
+}
+
+// Evicts pages if all mappings together are over the budget. This calls back into the mappings, so
+// it must not be called while holding anything that evicting pages needs.
+pub(crate) fn enforce() {
+    let victims = BUDGET.lock().unwrap().pick_victims();
+    if victims.is_empty() {
+        return;
+    }
+    for (target, pages) in victims {
+        if let Some(target) = target.upgrade() {
+            target.evict_for_budget(pages);
+        }
+    }
+    BUDGET.lock().unwrap().enforcing = false;
+}
+
+impl Budget {
+    fn new() -> Self {
+        Budget {
+            limit: None,
+            used: 0,
+            mappings: BTreeMap::new(),
+            next_stamp: 0,
+            enforcing: false,
+        }
+    }
+
+    fn register(&mut self, id: u64, page_size: usize, target: Weak<dyn BudgetTarget>) {
+        self.mappings.insert(
+            id,
+            Usage {
+                page_size,
+                pages: BTreeMap::new(),
+                order: BTreeMap::new(),
+                target,
+            },
+        );
+    }
+
+    fn unregister(&mut self, id: u64) {
+        if let Some(usage) = self.mappings.remove(&id) {
+            self.used -= usage.pages.len() * usage.page_size;
+        }
+    }
+
+    fn loaded(&mut self, id: u64, start_page: usize, end_page: usize) {
+        let usage = match self.mappings.get_mut(&id) {
+            Some(usage) => usage,
+            None => return,
+        };
+        for page in start_page..end_page {
+            if usage.pages.contains_key(&page) {
+                continue;
+            }
+            let stamp = self.next_stamp;
+            self.next_stamp += 1;
+            usage.pages.insert(page, stamp);
+            usage.order.insert(stamp, page);
+            self.used += usage.page_size;
+        }
+    }
+
+    fn touched(&mut self, id: u64, start_page: usize, end_page: usize) {
+        let usage = match self.mappings.get_mut(&id) {
+            Some(usage) => usage,
+            None => return,
+        };
+        let pages: Vec<usize> = usage
+            .pages
+            .range(start_page..end_page)
+            .map(|(page, _)| *page)
+            .collect();
+        for page in pages {
+            let stamp = self.next_stamp;
+            self.next_stamp += 1;
+            if let Some(old_stamp) = usage.pages.insert(page, stamp) {
+                usage.order.remove(&old_stamp);
+            }
+            usage.order.insert(stamp, page);
+        }
+    }
+
+    fn evicted(&mut self, id: u64, pages: &BTreeSet<usize>) {
+        if let Some(usage) = self.mappings.get_mut(&id) {
+            for page in pages.iter() {
+                if let Some(stamp) = usage.pages.remove(page) {
+                    usage.order.remove(&stamp);
+                    self.used -= usage.page_size;
+                }
+            }
+        }
+    }
+
+    fn usage(&self, id: u64) -> usize {
+        match self.mappings.get(&id) {
+            Some(usage) => usage.pages.len() * usage.page_size,
+            None => 0,
+        }
+    }
+
+    // Pages right after a gap are more likely to be picked than others. That's fine for looking at
+    // a few pages now and then.
+    fn sample(&self, id: u64, count: usize) -> BTreeSet<usize> {
+        let pages = match self.mappings.get(&id) {
+            Some(usage) => &usage.pages,
+            None => return BTreeSet::new(),
+        };
+        let last = match pages.keys().next_back() {
+            Some(last) => *last,
+            None => return BTreeSet::new(),
+        };
+        let mut rng = thread_rng();
+        (0..count)
+            .filter_map(|_| pages.range(rng.gen_range(0, last + 1)..).next())
+            .map(|(page, _)| *page)
+            .collect()
+    }
+
+    // Picks the pages to evict for the total to go a bit below the limit, so that we don't have to
+    // come back for every page loaded: the least recently used ones of all mappings, oldest first.
+    // Returns nothing if we're under the limit or somebody is already at it.
+    fn pick_victims(&mut self) -> Vec<(Weak<dyn BudgetTarget>, BTreeSet<usize>)> {
+        let limit = match self.limit {
+            Some(limit) if self.used > limit && !self.enforcing => limit,
+            _ => return vec![],
+        };
+        let excess = self.used - (limit - limit / 64);
+
+        // The least recently used page of each mapping that has any, the least recently used of
+        // them all on top.
+        let mut heads: BinaryHeap<Reverse<(u64, u64)>> = self
+            .mappings
+            .iter()
+            .filter_map(|(id, usage)| {
+                usage
+                    .order
+                    .keys()
+                    .next()
+                    .map(|stamp| Reverse((*stamp, *id)))
+            })
+            .collect();
+        let mut picked: BTreeMap<u64, BTreeSet<usize>> = BTreeMap::new();
+        let mut freed = 0;
+        while freed < excess {
+            let (stamp, id) = match heads.pop() {
+                Some(Reverse(head)) => head,
+                None => break,
+            };
+            let usage = &self.mappings[&id];
+            picked
+                .entry(id)
+                .or_insert_with(BTreeSet::new)
+                .insert(usage.order[&stamp]);
+            freed += usage.page_size;
+            if let Some(next) = usage.order.range(stamp + 1..).next() {
+                heads.push(Reverse((*next.0, id)));
+            }
+        }
+
+        let victims: Vec<(Weak<dyn BudgetTarget>, BTreeSet<usize>)> = picked
+            .into_iter()
+            .map(|(id, pages)| (self.mappings[&id].target.clone(), pages))
+            .collect();
+        self.enforcing = !victims.is_empty();
+        victims
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct Nothing;
+
+    impl BudgetTarget for Nothing {
+        fn evict_for_budget(&self, _pages: BTreeSet<usize>) {}
+    }
+
+    // The process-wide budget is shared with every other test that maps something, so these work
+    // on budgets of their own.
+    #[test]
+    fn usage_is_tracked() {
+        let target: Arc<dyn BudgetTarget> = Arc::new(Nothing);
+        let mut budget = Budget::new();
+        budget.register(1, 4096, Arc::downgrade(&target));
+        budget.register(2, 8192, Arc::downgrade(&target));
+
+        budget.loaded(1, 0, 100);
+        budget.loaded(2, 0, 100);
+        // Loading again doesn't count twice.
+        budget.loaded(1, 0, 10);
+        assert_eq!(budget.usage(1), 100 * 4096);
+        assert_eq!(budget.usage(2), 100 * 8192);
+        assert_eq!(budget.used, 100 * 4096 + 100 * 8192);
+        assert!(budget.pick_victims().is_empty());
+
+        budget.evicted(1, &(0..5).collect());
+        assert_eq!(budget.usage(1), 95 * 4096);
+        assert_eq!(budget.usage(1), budget.mappings[&1].order.len() * 4096);
+
+        // Samples are of loaded pages only.
+        let sample = budget.sample(2, 10);
+        assert!(!sample.is_empty() && sample.len() <= 10);
+        assert!(sample.iter().all(|page| *page < 100));
+        assert!(budget.sample(3, 10).is_empty());
+
+        budget.unregister(1);
+        assert_eq!(budget.usage(1), 0);
+        assert_eq!(budget.used, 100 * 8192);
+    }
+
+    #[test]
+    fn least_recently_used_pages_go_first() {
+        let target: Arc<dyn BudgetTarget> = Arc::new(Nothing);
+        let mut budget = Budget::new();
+        budget.register(1, 4096, Arc::downgrade(&target));
+        budget.register(2, 4096, Arc::downgrade(&target));
+
+        // The first mapping is loaded first but is in use; nobody has touched the second since.
+        budget.loaded(1, 0, 100);
+        budget.loaded(2, 100, 200);
+        budget.touched(1, 0, 100);
+        // Touching pages that aren't loaded does nothing.
+        budget.touched(1, 200, 300);
+        assert_eq!(budget.usage(1), 100 * 4096);
+
+        // Over the budget by 50 pages. Only the cold mapping gives any up, oldest first.
+        let limit = budget.used - 50 * 4096;
+        budget.limit = Some(limit);
+        let victims = budget.pick_victims();
+        assert_eq!(victims.len(), 1);
+        let pages = &victims[0].1;
+        assert!(budget.used - pages.len() * 4096 <= limit - limit / 64);
+        assert_eq!(*pages, (100..100 + pages.len()).collect());
+        // Somebody is already at it.
+        assert!(budget.pick_victims().is_empty());
+        budget.enforcing = false;
+
+        // Once those are gone, and the pages of the second mapping are used again, the first
+        // mapping's turn comes.
+        budget.evicted(2, pages);
+        budget.touched(2, 100, 200);
+        budget.limit = Some(budget.used - 10 * 4096);
+        let victims = budget.pick_victims();
+        assert_eq!(victims.len(), 1);
+        assert!(victims[0].1.contains(&0));
+        assert!(victims[0].1.len() >= 10 && victims[0].1.len() < 100);
+    }
+}
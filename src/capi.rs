@@ -1,11 +1,17 @@
 // This module implements a C API for the S3 mapper.
 
-use crate::userfaultfd::{mmap_with_userfault, MMap};
+use crate::budget::{memory_used, set_memory_budget};
+use crate::diskcache::{set_disk_cache, DiskCache};
+use crate::heuristics::HeuristicsConfig;
+use crate::pagecache::{receive_fd, send_fd, PageCache};
+use crate::policy::{EvictionPolicyFactory, ReadaheadPolicyFactory};
+use crate::registry::mmap_shared;
+use crate::userfaultfd::{Advice, ErrorPolicy, MMap, MMapMode, MMapOptions};
 use crate::userfaultfd_s3::{MMapS3, S3Failure};
-use libc::{c_char, c_int, c_void, size_t};
+use libc::{c_char, c_int, c_uint, c_void, size_t};
 use std::collections::BTreeMap;
 use std::ffi::CStr;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 // Keep in sync with mmapurl.h
 const MMAP_S3_OK: c_int = 0;
@@ -17,6 +23,46 @@ const MMAP_S3_PERMISSION_ERROR: c_int = 5;
 const MMAP_S3_NO_BODY_RETURNED: c_int = 6;
 const MMAP_S3_INVALID_S3URL: c_int = 7;
 const MMAP_S3_UNKNOWN: c_int = 8;
+const MMAP_S3_OBJECT_MODIFIED: c_int = 9;
+const MMAP_S3_NOT_MAPPED: c_int = 10;
+const MMAP_S3_NOT_CACHED: c_int = 11;
+
+const MMAP_S3_POLICY_ABORT: c_int = 0;
+const MMAP_S3_POLICY_RETRY: c_int = 1;
+const MMAP_S3_POLICY_ZEROFILL: c_int = 2;
+const MMAP_S3_POLICY_SIGBUS: c_int = 3;
+
+const MMAP_S3_MODE_READONLY: c_int = 0;
+const MMAP_S3_MODE_WRITEBACK: c_int = 1;
+const MMAP_S3_MODE_PRIVATE: c_int = 2;
+
+const MMAP_S3_ADVICE_NORMAL: c_int = 0;
+const MMAP_S3_ADVICE_SEQUENTIAL: c_int = 1;
+const MMAP_S3_ADVICE_RANDOM: c_int = 2;
+const MMAP_S3_ADVICE_WILLNEED: c_int = 3;
+const MMAP_S3_ADVICE_DONTNEED: c_int = 4;
+
+const MMAP_S3_HEURISTIC_LEVEL1_SLICE_SIZE: c_int = 0;
+const MMAP_S3_HEURISTIC_LEVEL2_SLICE_SIZE: c_int = 1;
+const MMAP_S3_HEURISTIC_LEVEL1_READAHEAD: c_int = 2;
+const MMAP_S3_HEURISTIC_LEVEL2_READAHEAD: c_int = 3;
+const MMAP_S3_HEURISTIC_MAX_LOADED_SIZE: c_int = 4;
+const MMAP_S3_HEURISTIC_EVICT_BELOW_MAX_LOADED_SIZE: c_int = 5;
+const MMAP_S3_HEURISTIC_SYNC_READ_SIZE: c_int = 6;
+const MMAP_S3_HEURISTIC_BACKGROUND_READ_SIZE: c_int = 7;
+const MMAP_S3_HEURISTIC_MIN_REQUEST_SIZE: c_int = 8;
+const MMAP_S3_HEURISTIC_MAX_REQUEST_SIZE: c_int = 9;
+const MMAP_S3_HEURISTIC_READAHEAD_POLICY: c_int = 10;
+const MMAP_S3_HEURISTIC_EVICTION_POLICY: c_int = 11;
+
+const MMAP_S3_READAHEAD_TWO_LEVEL: size_t = 0;
+const MMAP_S3_READAHEAD_ONDEMAND: size_t = 1;
+const MMAP_S3_READAHEAD_NONE: size_t = 2;
+
+const MMAP_S3_EVICTION_FIFO: size_t = 0;
+const MMAP_S3_EVICTION_LRU: size_t = 1;
+const MMAP_S3_EVICTION_CLOCK: size_t = 2;
+const MMAP_S3_EVICTION_ARC: size_t = 3;
 
 const MMAP_S3_OK_STR: &'static [u8] = b"MMAP_S3_OK\0";
 const MMAP_S3_ERRNO_STR: &'static [u8] = b"MMAP_S3_ERRNO\0";
@@ -28,15 +74,319 @@ const MMAP_S3_PERMISSION_ERROR_STR: &'static [u8] = b"MMAP_S3_PERMISSION_ERROR\0
 const MMAP_S3_NO_BODY_RETURNED_STR: &'static [u8] = b"MMAP_S3_NO_BODY_RETURNED\0";
 const MMAP_S3_INVALID_S3URL_STR: &'static [u8] = b"MMAP_S3_INVALID_S3URL\0";
 const MMAP_S3_UNKNOWN_STR: &'static [u8] = b"MMAP_S3_UNKNOWN\0";
+const MMAP_S3_OBJECT_MODIFIED_STR: &'static [u8] = b"MMAP_S3_OBJECT_MODIFIED\0";
+const MMAP_S3_NOT_MAPPED_STR: &'static [u8] = b"MMAP_S3_NOT_MAPPED\0";
+const MMAP_S3_NOT_CACHED_STR: &'static [u8] = b"MMAP_S3_NOT_CACHED\0";
 
 lazy_static! {
     // We need to keep track of pointers we have mapped so munmap_s3 knows which MMap handles
-    // correspond to which pointers.
-    static ref mmapped_s3s: RwLock<BTreeMap<u64, MMap<MMapS3>>> = RwLock::new(BTreeMap::new());
+    // correspond to which pointers. The same object can be handed out several times (see
+    // registry.rs); the count says how many munmap_s3() calls it takes to let go of it.
+    static ref mmapped_s3s: RwLock<BTreeMap<u64, (Arc<MMap<MMapS3>>, usize)>> =
+        RwLock::new(BTreeMap::new());
 }
 
 #[no_mangle]
 pub extern "C" fn mmap_s3(url: *const c_char, sz: *mut size_t, err: *mut c_int) -> *const c_void {
+    mmap_s3_with_options(url, MMapOptions::new(), sz, err)
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_opts(
+    url: *const c_char,
+    opts: *const MMapOptions,
+    sz: *mut size_t,
+    err: *mut c_int,
+) -> *const c_void {
+    let options = if opts.is_null() {
+        MMapOptions::new()
+    } else {
+        unsafe { (*opts).clone() }
+    };
+    mmap_s3_with_options(url, options, sz, err)
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_new() -> *mut MMapOptions {
+    Box::into_raw(Box::new(MMapOptions::new()))
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_free(opts: *mut MMapOptions) {
+    if !opts.is_null() {
+        unsafe {
+            drop(Box::from_raw(opts));
+        }
+    }
+}
+
+// The options a setter was given, or None (with errno set) if it was given NULL.
+fn options_mut<'a>(opts: *mut MMapOptions) -> Option<&'a mut MMapOptions> {
+    let opts = unsafe { opts.as_mut() };
+    if opts.is_none() {
+        set_errno(libc::EINVAL);
+    }
+    opts
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_error_policy(
+    opts: *mut MMapOptions,
+    policy: c_int,
+    retries: c_uint,
+) -> c_int {
+    let opts = match options_mut(opts) {
+        Some(opts) => opts,
+        None => return -1,
+    };
+    let error_policy = match policy {
+        MMAP_S3_POLICY_ABORT => ErrorPolicy::Abort,
+        MMAP_S3_POLICY_RETRY => ErrorPolicy::Retry(retries as u32),
+        MMAP_S3_POLICY_ZEROFILL => ErrorPolicy::ZeroFill,
+        MMAP_S3_POLICY_SIGBUS => ErrorPolicy::SigBus,
+        _ => return -1,
+    };
+    opts.error_policy = error_policy;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_mode(opts: *mut MMapOptions, mode: c_int) -> c_int {
+    let opts = match options_mut(opts) {
+        Some(opts) => opts,
+        None => return -1,
+    };
+    let mode = match mode {
+        MMAP_S3_MODE_READONLY => MMapMode::ReadOnly,
+        MMAP_S3_MODE_WRITEBACK => MMapMode::WriteBack,
+        MMAP_S3_MODE_PRIVATE => MMapMode::Private,
+        _ => return -1,
+    };
+    opts.mode = mode;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_pin_limit(opts: *mut MMapOptions, limit: size_t) -> c_int {
+    match options_mut(opts) {
+        Some(opts) => {
+            opts.pin_limit = limit;
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_access_sample_size(
+    opts: *mut MMapOptions,
+    pages: size_t,
+) -> c_int {
+    match options_mut(opts) {
+        Some(opts) => {
+            opts.access_sample_size = pages;
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_huge_pages(
+    opts: *mut MMapOptions,
+    huge_pages: c_int,
+) -> c_int {
+    match options_mut(opts) {
+        Some(opts) => {
+            opts.huge_pages = huge_pages != 0;
+            0
+        }
+        None => -1,
+    }
+}
+
+fn set_errno(errno: c_int) {
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+}
+
+// Sets one knob of a config. Returns false if the knob (or the policy) is not recognized.
+fn set_heuristic(config: &mut HeuristicsConfig, knob: c_int, value: size_t) -> bool {
+    match knob {
+        MMAP_S3_HEURISTIC_READAHEAD_POLICY => {
+            config.readahead_policy = match value {
+                MMAP_S3_READAHEAD_TWO_LEVEL => ReadaheadPolicyFactory::two_level(),
+                MMAP_S3_READAHEAD_ONDEMAND => ReadaheadPolicyFactory::ondemand(),
+                MMAP_S3_READAHEAD_NONE => ReadaheadPolicyFactory::none(),
+                _ => return false,
+            };
+            return true;
+        }
+        MMAP_S3_HEURISTIC_EVICTION_POLICY => {
+            config.eviction_policy = match value {
+                MMAP_S3_EVICTION_FIFO => EvictionPolicyFactory::fifo(),
+                MMAP_S3_EVICTION_LRU => EvictionPolicyFactory::lru(),
+                MMAP_S3_EVICTION_CLOCK => EvictionPolicyFactory::clock(),
+                MMAP_S3_EVICTION_ARC => EvictionPolicyFactory::arc(),
+                _ => return false,
+            };
+            return true;
+        }
+        _ => {}
+    }
+    let field = match knob {
+        MMAP_S3_HEURISTIC_LEVEL1_SLICE_SIZE => &mut config.level1_slice_size,
+        MMAP_S3_HEURISTIC_LEVEL2_SLICE_SIZE => &mut config.level2_slice_size,
+        MMAP_S3_HEURISTIC_LEVEL1_READAHEAD => &mut config.level1_readahead,
+        MMAP_S3_HEURISTIC_LEVEL2_READAHEAD => &mut config.level2_readahead,
+        MMAP_S3_HEURISTIC_MAX_LOADED_SIZE => &mut config.max_loaded_size,
+        MMAP_S3_HEURISTIC_EVICT_BELOW_MAX_LOADED_SIZE => &mut config.evict_below_max_loaded_size,
+        MMAP_S3_HEURISTIC_SYNC_READ_SIZE => &mut config.sync_read_size,
+        MMAP_S3_HEURISTIC_BACKGROUND_READ_SIZE => &mut config.background_read_size,
+        MMAP_S3_HEURISTIC_MIN_REQUEST_SIZE => &mut config.min_request_size,
+        MMAP_S3_HEURISTIC_MAX_REQUEST_SIZE => &mut config.max_request_size,
+        _ => return false,
+    };
+    *field = value;
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_heuristic(
+    opts: *mut MMapOptions,
+    knob: c_int,
+    value: size_t,
+) -> c_int {
+    let opts = match options_mut(opts) {
+        Some(opts) => opts,
+        None => return -1,
+    };
+    if set_heuristic(&mut opts.heuristics, knob, value) {
+        0
+    } else {
+        -1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_page_cache_create() -> c_int {
+    let cache = match PageCache::new() {
+        Ok(cache) => cache,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    let fd = unsafe { libc::dup(cache.fd()) };
+    if fd == -1 {
+        return -1;
+    }
+    fd
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_options_set_page_cache(opts: *mut MMapOptions, fd: c_int) -> c_int {
+    let opts = match options_mut(opts) {
+        Some(opts) => opts,
+        None => return -1,
+    };
+    if fd < 0 {
+        opts.page_cache = None;
+        return 0;
+    }
+    // The options keep a descriptor of their own; the caller can close theirs.
+    let own_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if own_fd == -1 {
+        return -1;
+    }
+    match PageCache::from_fd(own_fd) {
+        Ok(cache) => {
+            opts.page_cache = Some(Arc::new(cache));
+            0
+        }
+        Err(errno) => {
+            unsafe {
+                libc::close(own_fd);
+            }
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_page_cache_send(socket: c_int, fd: c_int) -> c_int {
+    match send_fd(socket, fd) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_page_cache_receive(socket: c_int) -> c_int {
+    match receive_fd(socket) {
+        Ok(fd) => fd,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_disk_cache(
+    dir: *const c_char,
+    max_size: size_t,
+    offline: c_int,
+) -> c_int {
+    if dir.is_null() {
+        set_disk_cache(None);
+        return 0;
+    }
+    let dir = match unsafe { CStr::from_ptr(dir) }.to_str() {
+        Ok(dir) => dir,
+        Err(_) => {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+    };
+    match DiskCache::new(dir, max_size) {
+        Ok(mut cache) => {
+            cache.set_offline(offline != 0);
+            set_disk_cache(Some(cache));
+            0
+        }
+        Err(err) => {
+            set_errno(err.raw_os_error().unwrap_or(libc::EIO));
+            -1
+        }
+    }
+}
+
+fn s3failure_to_err(s3failure: S3Failure) -> c_int {
+    match s3failure {
+        S3Failure::InvalidS3Url => MMAP_S3_INVALID_S3URL,
+        S3Failure::ContentLengthNotReturned => MMAP_S3_CONTENT_LENGTH_NOT_RETURNED,
+        S3Failure::NoBodyReturned => MMAP_S3_NO_BODY_RETURNED,
+        S3Failure::S3NotFound => MMAP_S3_NOT_FOUND,
+        S3Failure::S3PermissionError => MMAP_S3_PERMISSION_ERROR,
+        S3Failure::IOError => MMAP_S3_IOERROR,
+        S3Failure::ObjectModified => MMAP_S3_OBJECT_MODIFIED,
+        S3Failure::NotCached => MMAP_S3_NOT_CACHED,
+        _ => MMAP_S3_UNKNOWN,
+    }
+}
+
+fn mmap_s3_with_options(
+    url: *const c_char,
+    options: MMapOptions,
+    sz: *mut size_t,
+    err: *mut c_int,
+) -> *const c_void {
     unsafe {
         let mut sz: *mut size_t = sz;
         let mut err: *mut c_int = err;
@@ -63,41 +413,175 @@ pub extern "C" fn mmap_s3(url: *const c_char, sz: *mut size_t, err: *mut c_int)
         }
         .to_owned();
 
-        let result: Result<MMap<MMapS3>, Result<c_int, S3Failure>> = mmap_with_userfault(s3url);
+        let result: Result<Arc<MMap<MMapS3>>, Result<c_int, S3Failure>> =
+            mmap_shared(s3url, options);
         match result {
             Ok(mmapped) => {
                 let mut mmapped_pointers = mmapped_s3s.write().unwrap();
                 *sz = mmapped.len();
                 let ptr = mmapped.as_ptr();
-                mmapped_pointers.insert(ptr as u64, mmapped);
+                mmapped_pointers.entry(ptr as u64).or_insert((mmapped, 0)).1 += 1;
                 return ptr;
             }
-            Err(Ok(_errno)) => {
+            Err(Ok(errno)) => {
+                set_errno(errno);
                 *err = MMAP_S3_ERRNO;
             }
-            Err(Err(s3failure)) => match s3failure {
-                S3Failure::InvalidS3Url => *err = MMAP_S3_INVALID_S3URL,
-                S3Failure::ContentLengthNotReturned => *err = MMAP_S3_CONTENT_LENGTH_NOT_RETURNED,
-                S3Failure::NoBodyReturned => *err = MMAP_S3_NO_BODY_RETURNED,
-                S3Failure::S3NotFound => *err = MMAP_S3_NOT_FOUND,
-                S3Failure::S3PermissionError => *err = MMAP_S3_PERMISSION_ERROR,
-                S3Failure::IOError => *err = MMAP_S3_IOERROR,
-                _ => *err = MMAP_S3_UNKNOWN,
-            },
+            Err(Err(s3failure)) => *err = s3failure_to_err(s3failure),
         };
         libc::MAP_FAILED
     }
 }
 
+// The mapping at ptr, if there is one. Anything that can take a while (like talking to S3) is done
+// with the returned handle after the lock has been let go, so that it doesn't hold up everyone
+// else using the C API.
+fn mapped(ptr: *const c_void) -> Option<Arc<MMap<MMapS3>>> {
+    let mmapped_pointers = mmapped_s3s.read().unwrap();
+    mmapped_pointers
+        .get(&(ptr as u64))
+        .map(|(mmapped, _)| mmapped.clone())
+}
+
 #[no_mangle]
 pub extern "C" fn munmap_s3(ptr: *const c_void) -> c_int {
-    let mut mmapped_pointers = mmapped_s3s.write().unwrap();
-    if !mmapped_pointers.contains_key(&(ptr as u64)) {
-        return -1;
+    let last = {
+        let mut mmapped_pointers = mmapped_s3s.write().unwrap();
+        let references = match mmapped_pointers.get_mut(&(ptr as u64)) {
+            None => return -1,
+            Some((_, references)) => {
+                *references -= 1;
+                *references
+            }
+        };
+        if references == 0 {
+            mmapped_pointers.remove(&(ptr as u64))
+        } else {
+            None
+        }
+    };
+    // Unmapping writes back modified pages; that happens here, outside the lock.
+    drop(last);
+    return 0;
+}
+
+#[no_mangle]
+pub extern "C" fn msync_s3(ptr: *const c_void) -> c_int {
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => match mmapped.flush() {
+            Ok(()) => MMAP_S3_OK,
+            Err(s3failure) => s3failure_to_err(s3failure),
+        },
     }
+}
 
-    mmapped_pointers.remove(&(ptr as u64));
-    return 0;
+#[no_mangle]
+pub extern "C" fn madvise_s3(
+    ptr: *const c_void,
+    offset: size_t,
+    len: size_t,
+    advice: c_int,
+) -> c_int {
+    let advice = match advice {
+        MMAP_S3_ADVICE_NORMAL => Advice::Normal,
+        MMAP_S3_ADVICE_SEQUENTIAL => Advice::Sequential,
+        MMAP_S3_ADVICE_RANDOM => Advice::Random,
+        MMAP_S3_ADVICE_WILLNEED => Advice::WillNeed,
+        MMAP_S3_ADVICE_DONTNEED => Advice::DontNeed,
+        _ => {
+            set_errno(libc::EINVAL);
+            return MMAP_S3_ERRNO;
+        }
+    };
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => {
+            mmapped.advise(offset..offset.saturating_add(len), advice);
+            MMAP_S3_OK
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_set_heuristic(ptr: *const c_void, knob: c_int, value: size_t) -> c_int {
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => {
+            let mut config = mmapped.heuristics();
+            if !set_heuristic(&mut config, knob, value) {
+                set_errno(libc::EINVAL);
+                return MMAP_S3_ERRNO;
+            }
+            match mmapped.set_heuristics(config) {
+                Ok(()) => MMAP_S3_OK,
+                Err(errno) => {
+                    set_errno(errno);
+                    MMAP_S3_ERRNO
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn prefetch_s3(ptr: *const c_void, offset: size_t, len: size_t) -> c_int {
+    madvise_s3(ptr, offset, len, MMAP_S3_ADVICE_WILLNEED)
+}
+
+#[no_mangle]
+pub extern "C" fn evict_s3(ptr: *const c_void, offset: size_t, len: size_t) -> c_int {
+    madvise_s3(ptr, offset, len, MMAP_S3_ADVICE_DONTNEED)
+}
+
+#[no_mangle]
+pub extern "C" fn pin_s3(ptr: *const c_void, offset: size_t, len: size_t) -> c_int {
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => match mmapped.pin(offset..offset.saturating_add(len)) {
+            Ok(()) => MMAP_S3_OK,
+            Err(errno) => {
+                set_errno(errno);
+                MMAP_S3_ERRNO
+            }
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn unpin_s3(ptr: *const c_void, offset: size_t, len: size_t) -> c_int {
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => {
+            mmapped.unpin(offset..offset.saturating_add(len));
+            MMAP_S3_OK
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_set_memory_budget(limit: size_t) {
+    set_memory_budget(if limit == 0 { None } else { Some(limit) });
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_memory_used() -> size_t {
+    memory_used()
+}
+
+#[no_mangle]
+pub extern "C" fn mmap_s3_resident_size(ptr: *const c_void, size: *mut size_t) -> c_int {
+    match mapped(ptr) {
+        None => MMAP_S3_NOT_MAPPED,
+        Some(mmapped) => {
+            if !size.is_null() {
+                unsafe {
+                    *size = mmapped.resident_size();
+                }
+            }
+            MMAP_S3_OK
+        }
+    }
 }
 
 #[no_mangle]
@@ -111,6 +595,9 @@ pub extern "C" fn mmap_s3_errstr(err: c_int) -> *const c_char {
         MMAP_S3_PERMISSION_ERROR => MMAP_S3_PERMISSION_ERROR_STR,
         MMAP_S3_NO_BODY_RETURNED => MMAP_S3_NO_BODY_RETURNED_STR,
         MMAP_S3_INVALID_S3URL => MMAP_S3_INVALID_S3URL_STR,
+        MMAP_S3_OBJECT_MODIFIED => MMAP_S3_OBJECT_MODIFIED_STR,
+        MMAP_S3_NOT_MAPPED => MMAP_S3_NOT_MAPPED_STR,
+        MMAP_S3_NOT_CACHED => MMAP_S3_NOT_CACHED_STR,
         _ => MMAP_S3_UNKNOWN_STR,
     }
     .as_ptr() as *const c_char
@@ -0,0 +1,568 @@
+/* This module implements a cache of S3 objects on local disk, kept between runs.
+ *
+ * Objects are stored in fixed size blocks, one file per block, named after the bucket, key and
+ * ETag of the object and the number of the block. A replaced object has a different ETag, so its
+ * old blocks are simply never read again and age out. Each block file ends with a checksum of the
+ * block; a block that does not match it is thrown away and fetched again.
+ *
+ * Reads go through the cache a block at a time. A block that's not there is fetched from S3 along
+ * with the missing blocks right after it (in one request), stored and handed over. Even a single
+ * page costs a whole block the first time.
+ *
+ * The cache is kept under a size cap by deleting the least recently used blocks; a block is used
+ * when it's read or written (modification time). Garbage is collected when the cache is opened
+ * and every time another eighth of the cap has been written.
+ *
+ * Several processes can use the same directory at once. Blocks are written to a temporary file and
+ * renamed into place, so a block is either there in full or not at all. Only one process collects
+ * garbage at a time (flock() on gc.lock); a block deleted while somebody is reading it stays
+ * readable for them.
+ *
+ * Offline, the cache never talks to S3. Objects can still be opened if they have been opened
+ * online before (the size and ETag are remembered under objects/), and touching anything that's not
+ * in the cache fails with S3Failure::NotCached.
+ */
+
+use crate::userfaultfd_s3::S3Failure;
+use std::cmp;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Objects are cached in blocks this large (the last one of an object can be shorter). Same as a
+// first level read-ahead slice, so sequential reads don't fetch much they wouldn't anyway.
+pub const DISK_CACHE_BLOCK_SIZE: usize = 262144;
+
+// At most this many missing blocks are fetched with one request.
+const MAX_FETCH_BLOCKS: usize = 32;
+
+// Temporary files this old were left behind by a process that died while writing them.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3600);
+
+lazy_static! {
+    static ref DISK_CACHE: Mutex<Option<Arc<DiskCache>>> = Mutex::new(None);
+    static ref TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+}
+
+// Sets the disk cache S3 mappings made from now on use. None turns it off. Mappings that already
+// exist keep what they have.
+pub fn set_disk_cache(cache: Option<DiskCache>) {
+    *DISK_CACHE.lock().unwrap() = cache.map(Arc::new);
+}
+
+pub(crate) fn disk_cache() -> Option<Arc<DiskCache>> {
+    DISK_CACHE.lock().unwrap().clone()
+}
+
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: usize,
+    offline: bool,
+    // Bytes written since garbage was last collected.
+    written: AtomicUsize,
+}
+
+// What is remembered of an object so it can be opened offline.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct CachedObject {
+    pub etag: String,
+    pub size: usize,
+}
+
+impl DiskCache {
+    // Uses `dir` for the cache, making it if it's not there, and keeps it under about `max_size`
+    // bytes.
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("blocks"))?;
+        fs::create_dir_all(dir.join("objects"))?;
+        let cache = DiskCache {
+            dir,
+            max_size,
+            offline: false,
+            written: AtomicUsize::new(0),
+        };
+        cache.collect_garbage();
+        Ok(cache)
+    }
+
+    // Serve only from the cache and never ask S3 for anything.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    // Deletes the least recently used blocks until the cache is at 90% of its cap, if it's over the
+    // cap. Does nothing if another process is already at it.
+    pub fn collect_garbage(&self) {
+        let lock = match File::create(self.dir.join("gc.lock")) {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        // The lock goes away with the file.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            return;
+        }
+        let entries = match fs::read_dir(self.dir.join("blocks")) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let now = SystemTime::now();
+        let mut total = 0;
+        let mut blocks = vec![];
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            // Can be gone already; somebody else may be deleting things we don't know about.
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let mtime = metadata.modified().unwrap_or(now);
+            if entry.file_name().to_string_lossy().starts_with("tmp-") {
+                let stale = now
+                    .duration_since(mtime)
+                    .map(|age| age > STALE_TEMP_AGE)
+                    .unwrap_or(false);
+                if stale {
+                    let _ = fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            total += metadata.len() as usize;
+            blocks.push((mtime, metadata.len() as usize, entry.path()));
+        }
+        if total <= self.max_size {
+            return;
+        }
+
+        blocks.sort();
+        let target = self.max_size / 10 * 9;
+        for (_, size, path) in blocks {
+            if total <= target {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= size;
+            }
+        }
+    }
+
+    pub(crate) fn remember_object(&self, bucket: &str, key: &str, object: &CachedObject) {
+        let contents = format!("{}\n{}\n{}\n{}\n", bucket, key, object.etag, object.size);
+        let _ = self.write_atomically(&self.object_path(bucket, key), contents.as_bytes());
+    }
+
+    pub(crate) fn lookup_object(&self, bucket: &str, key: &str) -> Option<CachedObject> {
+        let contents = fs::read_to_string(self.object_path(bucket, key)).ok()?;
+        let lines: Vec<&str> = contents.lines().collect();
+        // A name with a newline in it (or a hash collision) doesn't come out right; then it's
+        // just not cached.
+        if lines.len() != 4 || lines[0] != bucket || lines[1] != key {
+            return None;
+        }
+        Some(CachedObject {
+            etag: lines[2].to_owned(),
+            size: lines[3].parse().ok()?,
+        })
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> PathBuf {
+        let name = format!("{}\0{}", bucket, key);
+        self.dir
+            .join("objects")
+            .join(format!("{:016x}", checksum(name.as_bytes())))
+    }
+
+    fn block_path(&self, object: u64, index: usize) -> PathBuf {
+        self.dir.join("blocks").join(format!(
+            "{:016x}-{}-{}",
+            object, DISK_CACHE_BLOCK_SIZE, index
+        ))
+    }
+
+    fn has_block(&self, object: u64, index: usize) -> bool {
+        self.block_path(object, index).exists()
+    }
+
+    // Returns the block if it's in the cache and intact. Broken blocks are deleted.
+    fn load_block(&self, object: u64, index: usize, len: usize) -> Option<Vec<u8>> {
+        let path = self.block_path(object, index);
+        let mut file = File::open(&path).ok()?;
+        let mut contents = Vec::with_capacity(len + 8);
+        file.read_to_end(&mut contents).ok()?;
+        if contents.len() != len + 8 || checksum(&contents[..len]) != read_u64(&contents[len..]) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        // Garbage collection goes by modification time.
+        unsafe {
+            libc::futimens(file.as_raw_fd(), std::ptr::null());
+        }
+        contents.truncate(len);
+        Some(contents)
+    }
+
+    // Failing to store a block only means it has to be fetched again next time.
+    fn store_block(&self, object: u64, index: usize, contents: &[u8]) {
+        let mut file_contents = Vec::with_capacity(contents.len() + 8);
+        file_contents.extend_from_slice(contents);
+        file_contents.extend_from_slice(&write_u64(checksum(contents)));
+        if self
+            .write_atomically(&self.block_path(object, index), &file_contents)
+            .is_err()
+        {
+            return;
+        }
+
+        let written = self
+            .written
+            .fetch_add(file_contents.len(), Ordering::Relaxed)
+            + file_contents.len();
+        if written >= self.max_size / 8 {
+            self.written.store(0, Ordering::Relaxed);
+            self.collect_garbage();
+        }
+    }
+
+    fn write_atomically(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let temp_path = self.dir.join("blocks").join(format!(
+            "tmp-{}-{}",
+            unsafe { libc::getpid() },
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = File::create(&temp_path)
+            .and_then(|mut file| file.write_all(contents))
+            .and_then(|_| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
+
+// Names the blocks of one version of an object.
+pub(crate) fn object_id(bucket: &str, key: &str, etag: &str) -> u64 {
+    checksum(format!("{}\0{}\0{}", bucket, key, etag).as_bytes())
+}
+
+// 64-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value: u64 = 0;
+    for (i, byte) in bytes.iter().take(8).enumerate() {
+        value |= (*byte as u64) << (i * 8);
+    }
+    value
+}
+
+fn write_u64(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+// Reads [offset, offset+len) of an object through the cache. Missing blocks are fetched with
+// `fetch(offset, len)`, which starts a download and returns the body to read it from.
+pub(crate) struct CachedRange<F> {
+    cache: Arc<DiskCache>,
+    object: u64,
+    object_size: usize,
+    pos: usize,
+    end: usize,
+    fetch: F,
+    // The block in hand and its contents.
+    block: Option<(usize, Vec<u8>)>,
+    // The download in progress, the block it gives next and the block it ends at.
+    fetching: Option<(Box<dyn Read + Send>, usize, usize)>,
+}
+
+impl<F> CachedRange<F>
+where
+    F: FnMut(usize, usize) -> Result<Box<dyn Read + Send>, S3Failure> + Send,
+{
+    // The first download (if any) is started right away so that its failure comes out here, like
+    // it would without the cache.
+    pub fn open(
+        cache: Arc<DiskCache>,
+        object: u64,
+        object_size: usize,
+        offset: usize,
+        len: usize,
+        fetch: F,
+    ) -> Result<Self, S3Failure> {
+        let mut range = CachedRange {
+            cache,
+            object,
+            object_size,
+            pos: offset,
+            end: cmp::min(offset + len, object_size),
+            fetch,
+            block: None,
+            fetching: None,
+        };
+        if range.pos >= range.end {
+            return Ok(range);
+        }
+
+        let first = range.pos / DISK_CACHE_BLOCK_SIZE;
+        let last = (range.end - 1) / DISK_CACHE_BLOCK_SIZE;
+        if range.cache.offline {
+            if (first..=last).all(|index| range.cache.has_block(object, index)) {
+                return Ok(range);
+            }
+            return Err(S3Failure::NotCached);
+        }
+        if let Some(contents) = range.load(first) {
+            range.block = Some((first, contents));
+        } else {
+            range.start_fetch(first)?;
+        }
+        Ok(range)
+    }
+
+    fn block_len(&self, index: usize) -> usize {
+        cmp::min(
+            DISK_CACHE_BLOCK_SIZE,
+            self.object_size - index * DISK_CACHE_BLOCK_SIZE,
+        )
+    }
+
+    fn load(&self, index: usize) -> Option<Vec<u8>> {
+        self.cache
+            .load_block(self.object, index, self.block_len(index))
+    }
+
+    // Starts downloading block `index` and the missing blocks in the range right after it.
+    fn start_fetch(&mut self, index: usize) -> Result<(), S3Failure> {
+        let last = (self.end - 1) / DISK_CACHE_BLOCK_SIZE;
+        let mut fetch_end = index + 1;
+        while fetch_end <= last
+            && fetch_end - index < MAX_FETCH_BLOCKS
+            && !self.cache.has_block(self.object, fetch_end)
+        {
+            fetch_end += 1;
+        }
+        let start = index * DISK_CACHE_BLOCK_SIZE;
+        let len = cmp::min(fetch_end * DISK_CACHE_BLOCK_SIZE, self.object_size) - start;
+        let body = (self.fetch)(start, len)?;
+        self.fetching = Some((body, index, fetch_end));
+        Ok(())
+    }
+
+    fn get_block(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let fetched_next = match self.fetching {
+            Some((_, next, fetch_end)) => next == index && index < fetch_end,
+            None => false,
+        };
+        if !fetched_next {
+            self.fetching = None;
+            if let Some(contents) = self.load(index) {
+                return Ok(contents);
+            }
+            // Somebody deleted it after open() checked.
+            if self.cache.offline {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "block is not in the disk cache",
+                ));
+            }
+            if let Err(failure) = self.start_fetch(index) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{:?}", failure),
+                ));
+            }
+        }
+
+        let mut contents = vec![0; self.block_len(index)];
+        if let Some((ref mut body, ref mut next, _)) = self.fetching {
+            if let Err(err) = body.read_exact(&mut contents) {
+                self.fetching = None;
+                return Err(err);
+            }
+            *next += 1;
+        }
+        self.cache.store_block(self.object, index, &contents);
+        Ok(contents)
+    }
+}
+
+impl<F> Read for CachedRange<F>
+where
+    F: FnMut(usize, usize) -> Result<Box<dyn Read + Send>, S3Failure> + Send,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.end || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / DISK_CACHE_BLOCK_SIZE;
+        let in_hand = match self.block {
+            Some((block, _)) => block == index,
+            None => false,
+        };
+        if !in_hand {
+            let contents = self.get_block(index)?;
+            self.block = Some((index, contents));
+        }
+
+        let block_start = index * DISK_CACHE_BLOCK_SIZE;
+        let contents = &self.block.as_ref().unwrap().1;
+        let start = self.pos - block_start;
+        let end = cmp::min(contents.len(), self.end - block_start);
+        let n = cmp::min(buf.len(), end - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::AtomicUsize;
+
+    const OBJECT_SIZE: usize = DISK_CACHE_BLOCK_SIZE * 5 + 1000;
+
+    fn byte_at(offset: usize) -> u8 {
+        ((offset * 7) & 0xFF) as u8
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mmapurl-diskcache-{}-{}", name, unsafe {
+            libc::getpid()
+        }));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Reads [offset, offset+len) through the cache and counts how many bytes had to be fetched.
+    fn read_range(
+        cache: &Arc<DiskCache>,
+        offset: usize,
+        len: usize,
+        fetched: &Arc<AtomicUsize>,
+    ) -> Result<Vec<u8>, S3Failure> {
+        let fetched = fetched.clone();
+        let mut range = CachedRange::open(
+            cache.clone(),
+            object_id("bucket", "key", "\"etag\""),
+            OBJECT_SIZE,
+            offset,
+            len,
+            move |offset, len| {
+                fetched.fetch_add(len, Ordering::Relaxed);
+                let bytes: Vec<u8> = (offset..offset + len).map(byte_at).collect();
+                Ok(Box::new(Cursor::new(bytes)) as Box<dyn Read + Send>)
+            },
+        )?;
+        let mut contents = vec![];
+        range.read_to_end(&mut contents).unwrap();
+        Ok(contents)
+    }
+
+    #[test]
+    fn blocks_are_cached() {
+        let dir = temp_dir("blocks");
+        let cache = Arc::new(DiskCache::new(&dir, 1 << 30).unwrap());
+        let fetched = Arc::new(AtomicUsize::new(0));
+
+        let offset = DISK_CACHE_BLOCK_SIZE + 100;
+        let contents = read_range(&cache, offset, DISK_CACHE_BLOCK_SIZE * 2, &fetched).unwrap();
+        assert_eq!(contents.len(), DISK_CACHE_BLOCK_SIZE * 2);
+        for (i, byte) in contents.iter().enumerate() {
+            assert_eq!(*byte, byte_at(offset + i));
+        }
+        // Whole blocks, all three in one go.
+        assert_eq!(fetched.load(Ordering::Relaxed), DISK_CACHE_BLOCK_SIZE * 3);
+
+        // Again, from the disk this time.
+        assert_eq!(
+            read_range(&cache, offset, DISK_CACHE_BLOCK_SIZE * 2, &fetched).unwrap(),
+            contents
+        );
+        assert_eq!(fetched.load(Ordering::Relaxed), DISK_CACHE_BLOCK_SIZE * 3);
+
+        // A broken block is noticed and fetched again.
+        let object = object_id("bucket", "key", "\"etag\"");
+        let path = cache.block_path(object, 2);
+        let mut broken = fs::read(&path).unwrap();
+        broken[10] ^= 0xFF;
+        fs::write(&path, broken).unwrap();
+        assert_eq!(
+            read_range(&cache, offset, DISK_CACHE_BLOCK_SIZE * 2, &fetched).unwrap(),
+            contents
+        );
+        assert_eq!(fetched.load(Ordering::Relaxed), DISK_CACHE_BLOCK_SIZE * 4);
+
+        // The short last block.
+        let contents = read_range(&cache, OBJECT_SIZE - 10, 4096, &fetched).unwrap();
+        assert_eq!(contents.len(), 10);
+        assert_eq!(contents[9], byte_at(OBJECT_SIZE - 1));
+
+        // Offline, what's there is there and the rest is not.
+        let mut offline = DiskCache::new(&dir, 1 << 30).unwrap();
+        offline.set_offline(true);
+        let offline = Arc::new(offline);
+        let before = fetched.load(Ordering::Relaxed);
+        assert!(read_range(&offline, offset, 4096, &fetched).is_ok());
+        assert_eq!(
+            read_range(&offline, 0, 4096, &fetched).err(),
+            Some(S3Failure::NotCached)
+        );
+        assert_eq!(fetched.load(Ordering::Relaxed), before);
+
+        let object = CachedObject {
+            etag: "\"etag\"".to_owned(),
+            size: OBJECT_SIZE,
+        };
+        cache.remember_object("bucket", "key", &object);
+        assert_eq!(offline.lookup_object("bucket", "key"), Some(object));
+        assert_eq!(offline.lookup_object("bucket", "other"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let dir = temp_dir("gc");
+        let cache = Arc::new(DiskCache::new(&dir, DISK_CACHE_BLOCK_SIZE * 3).unwrap());
+        let fetched = Arc::new(AtomicUsize::new(0));
+
+        for index in 0..5 {
+            read_range(&cache, index * DISK_CACHE_BLOCK_SIZE, 1, &fetched).unwrap();
+            // Modification times need to differ for there to be an order.
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        cache.collect_garbage();
+
+        // The oldest ones went, the newest ones stayed.
+        let object = object_id("bucket", "key", "\"etag\"");
+        assert!(!cache.has_block(object, 0));
+        assert!(cache.has_block(object, 4));
+        let blocks = fs::read_dir(dir.join("blocks")).unwrap().count();
+        assert!(blocks <= 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,6 +3,17 @@
 // This module has been written to be completely agnostic of actual page loading gritty details.
 //
 /*
+ * PageHeuristics keeps track of which pages are loaded, pinned and advised. How much to read ahead
+ * and which pages to evict is up to a ReadaheadPolicy and an EvictionPolicy (see policy.rs). By
+ * default read-ahead is the two-level scheme below and eviction is FIFO: pages go in the order
+ * they were loaded.
+ *
+ * Which pages are used is found out by sampling, if the mapping asks for it (see
+ * MMapOptions::access_sample_size). Handlers pass what they hear on with accessed(), so that with
+ * a policy that takes use into account, such as CLOCK, a page that keeps being used survives a
+ * long scan of pages that are read once. Sampling is off by default, and without it those policies
+ * hear of no use and behave like FIFO, so FIFO is the default.
+ *
  * We slice the S3 file into slices, on two levels. Lower level is sliced to 256 kilobytes. Higher
  * level is sliced to 256*128 = ~32 megabytes.
  *
@@ -12,34 +23,475 @@
  * For 256kb sized slices, we read ahead 16 extra sizes (~4 megabytes).
  * For 32MB sized slices, we read ahead 2 extra slices (~64 megabytes).
  *
- * All these numbers are configurable by tuning the knobs below.
+ * Pinned pages are never evicted. If the eviction policy picks one, it's dropped from the policy
+ * and handed back to it when the page is unpinned.
+ *
+ * Faults that go backwards, or that are a constant number of pages apart (reading every Nth
+ * record), get no help from the slices. After STRIDE_HISTORY such faults in a row the pages they
+ * are heading for are read in the background instead, STRIDE_INITIAL_DEPTH strides ahead at first
+ * and twice as far every time the reader gets to the end of what was read, up to as much as level1
+ * read-ahead reads. Forward sequential reading with read-ahead also faults a constant number of
+ * pages apart, but there the pages right after each fault have been read; that's how the two are
+ * told apart.
+ *
+ * Several threads can be reading the same mapping at once, each through a part of its own. Their
+ * faults are told apart by the thread that faulted: each thread is a stream with its own stride
+ * and its own read-ahead window (ReadaheadPolicy::readahead() gets the stream too), so one reader
+ * jumping around doesn't break another reader's sequence. Up to MAX_STREAMS streams are followed;
+ * past that the one that faulted least recently is forgotten. The two-level slices go by what is
+ * loaded and are shared by all streams; the window is where a stream's last read-ahead went, and a
+ * fault in it on a page that was evicted before the reader got there reads ahead again right away.
+ *
+ * Ranges can be advised to be read sequentially (read ahead right away, without waiting for a slice
+ * to fill up) or randomly (no read-ahead at all). See MMap::advise().
+ *
+ * Only the first SYNC_READ_SIZE pages of a read-ahead are read while the faulting thread waits. The
+ * rest is cut into BACKGROUND_READ_SIZE pieces that are read in the background, ahead of the
+ * reader.
+ *
+ * All these numbers are knobs in a HeuristicsConfig. The defaults are the constants below. A
+ * mapping can be given a config of its own when it's made (MMapOptions::heuristics) and a new one
+ * at any time after that (MMap::set_heuristics()). The policies are part of the config too. A
+ * config whose knobs contradict each other (see HeuristicsConfig::consistent()) is refused with
+ * EINVAL rather than quietly bent into shape.
+ *
+ * Besides the knobs above, a config can put a floor and a cap on how much one fault reads in total
+ * (min_request_size and max_request_size).
+ *
+ * Pages are whatever size the mapping is made of. The knobs are in bytes and are turned into pages
+ * when PageHeuristics is made or given a new config. With huge pages that can make a slice less
+ * than a page; slices are never made smaller than MIN_SLICE_PAGES pages, so read-ahead gets
+ * coarser rather than going away.
  */
 
-use crate::mmaputil::PAGESIZE_USIZE;
+use crate::mmaputil::round_up_to;
+use crate::policy::{
+    EvictionPolicy, EvictionPolicyFactory, ReadaheadPolicy, ReadaheadPolicyFactory,
+};
+use crate::userfaultfd::Advice;
 use std::cmp;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem;
+
+// These are in bytes. The page counts are what they come to with 4096 byte pages.
+const LEVEL1_SLICE_SIZE: usize = 262144; // 64 pages (~256kb)
+const LEVEL2_SLICE_SIZE: usize = 33554432; // 8192 pages (~32mb)
+const LEVEL1_READAHEAD: usize = 16; // in slices
+const LEVEL2_READAHEAD: usize = 2; // in slices
+const MAX_LOADED_SIZE: usize = 134217728; // 32768 pages (~128mb), should be larger than level2 readahead
+const EVICT_BELOW_MAX_LOADED_SIZE: usize = 2048000; // 500 pages, how far to go below MAX_LOADED_SIZE when too many pages have been loaded
+const SYNC_READ_SIZE: usize = 262144; // 64 pages (~256kb), read while the faulting thread waits
+const BACKGROUND_READ_SIZE: usize = 8388608; // 2048 pages (~8mb), size of one background read
+
+// Read-ahead rounding aims for the second to last page of a slice so a slice needs a few pages.
+const MIN_SLICE_PAGES: usize = 4;
+
+// Runs of faults it takes to recognize a stride, and how many strides ahead to read at first and
+// at most.
+const STRIDE_HISTORY: usize = 3;
+const STRIDE_INITIAL_DEPTH: usize = 4;
+const STRIDE_MAX_DEPTH: usize = 64;
+
+// Readers (faulting threads) followed at once, per mapping.
+const MAX_STREAMS: usize = 32;
+
+// The read-ahead and eviction knobs of a mapping. Sizes are in bytes; they are rounded down to
+// whole pages (but never below one page) when they are used.
+#[derive(Debug, Clone)]
+pub struct HeuristicsConfig {
+    pub level1_slice_size: usize,
+    pub level2_slice_size: usize,
+    // In slices.
+    pub level1_readahead: usize,
+    pub level2_readahead: usize,
+    // How much a mapping keeps loaded, and how far below that it goes when it has to evict.
+    pub max_loaded_size: usize,
+    pub evict_below_max_loaded_size: usize,
+    pub sync_read_size: usize,
+    pub background_read_size: usize,
+    // A fault reads at least this much...
+    pub min_request_size: usize,
+    // ...and at most this much, read-ahead included.
+    pub max_request_size: usize,
+    // The policies used by the mapping. Giving a live mapping a config with other policies starts
+    // them from what is loaded right now.
+    pub readahead_policy: ReadaheadPolicyFactory,
+    pub eviction_policy: EvictionPolicyFactory,
+}
+
+impl HeuristicsConfig {
+    pub fn new() -> Self {
+        HeuristicsConfig {
+            level1_slice_size: LEVEL1_SLICE_SIZE,
+            level2_slice_size: LEVEL2_SLICE_SIZE,
+            level1_readahead: LEVEL1_READAHEAD,
+            level2_readahead: LEVEL2_READAHEAD,
+            max_loaded_size: MAX_LOADED_SIZE,
+            evict_below_max_loaded_size: EVICT_BELOW_MAX_LOADED_SIZE,
+            sync_read_size: SYNC_READ_SIZE,
+            background_read_size: BACKGROUND_READ_SIZE,
+            min_request_size: 0,
+            max_request_size: usize::max_value(),
+            readahead_policy: ReadaheadPolicyFactory::two_level(),
+            eviction_policy: EvictionPolicyFactory::fifo(),
+        }
+    }
 
-// These are multiplied by page which is 4096 bytes.
-const LEVEL1_SLICE_SIZE: usize = 64; // 262144 (~256kb)
-const LEVEL2_SLICE_SIZE: usize = 8192; // 33554432 (~32mb)
-const LEVEL1_READAHEAD: usize = 16;
-const LEVEL2_READAHEAD: usize = 2;
-const MAX_LOADED_PAGES: usize = 32768; // ~128 megabytes, should be larger than level2 readahead
-const NUM_PAGES_TO_GO_BELOW_MAX_LOADED_PAGES_ON_EVICT: usize = 500; // How many pages to go below MAX_LOADED_PAGES when too many pages have been loaded. 500 = ~2 megabytes.
+    // Slice sizes in pages, as the two-level read-ahead uses them.
+    fn slice_pages(&self, page_size: usize) -> (usize, usize) {
+        let level1_slice_size = cmp::max(self.level1_slice_size / page_size, MIN_SLICE_PAGES);
+        let level2_slice_size = cmp::max(self.level2_slice_size / page_size, level1_slice_size);
+        (level1_slice_size, level2_slice_size)
+    }
+
+    // Whether the knobs make sense together for a mapping of `page_size` pages. Two-level
+    // read-ahead must be able to keep twice its level2 read-ahead loaded, or it would evict what
+    // it just read; nothing can go further below the limit than the limit itself; and the smallest
+    // read can't be larger than the largest.
+    pub(crate) fn consistent(&self, page_size: usize) -> bool {
+        let max_loaded_pages = self.max_loaded_size / page_size;
+        let two_level = ReadaheadPolicyFactory::two_level();
+        if self.readahead_policy.same(&two_level) {
+            let (_, level2_slice_size) = self.slice_pages(page_size);
+            let level2_pages = cmp::max(self.level2_readahead, 1)
+                .saturating_mul(level2_slice_size)
+                .saturating_mul(2);
+            if max_loaded_pages < level2_pages {
+                return false;
+            }
+        }
+        max_loaded_pages >= 1
+            && self.evict_below_max_loaded_size <= self.max_loaded_size
+            && self.min_request_size <= self.max_request_size
+    }
+
+    // Whether both have the same knobs and policies.
+    pub(crate) fn same(&self, other: &Self) -> bool {
+        self.level1_slice_size == other.level1_slice_size
+            && self.level2_slice_size == other.level2_slice_size
+            && self.level1_readahead == other.level1_readahead
+            && self.level2_readahead == other.level2_readahead
+            && self.max_loaded_size == other.max_loaded_size
+            && self.evict_below_max_loaded_size == other.evict_below_max_loaded_size
+            && self.sync_read_size == other.sync_read_size
+            && self.background_read_size == other.background_read_size
+            && self.min_request_size == other.min_request_size
+            && self.max_request_size == other.max_request_size
+            && self.readahead_policy.same(&other.readahead_policy)
+            && self.eviction_policy.same(&other.eviction_policy)
+    }
+}
+
+impl Default for HeuristicsConfig {
+    fn default() -> Self {
+        HeuristicsConfig::new()
+    }
+}
 
 pub struct PageHeuristics {
-    level1slices: BTreeMap<usize, Slice>,
-    level2slices: BTreeMap<usize, Slice>,
+    page_size: usize,
+    config: HeuristicsConfig,
+    // The knobs of the config that are used here, in pages.
+    max_loaded_pages: usize,
+    evict_below_pages: usize,
+    sync_read_size: usize,
+    background_read_size: usize,
+    min_request_size: usize,
+    max_request_size: usize,
+
+    readahead: Box<dyn ReadaheadPolicy>,
+    eviction: Box<dyn EvictionPolicy>,
+
+    loaded: BTreeSet<usize>,
+
+    // start page -> (end page, Advice::Sequential or Advice::Random). Pages not in here have had
+    // no advice or Advice::Normal.
+    patterns: BTreeMap<usize, (usize, Advice)>,
+
+    pinned: BTreeSet<usize>,
+
+    // Faulting thread -> what it has been doing.
+    streams: BTreeMap<u32, Stream>,
+    // Counts faults, to know which stream faulted least recently.
+    fault_count: u64,
+    // Most pages to read ahead for a stride, all strides together.
+    stride_pages: usize,
+}
 
-    evict_queue: VecDeque<usize>,
+// One reader of the mapping.
+struct Stream {
+    // Recent faults as (start page, end page) runs of faults on consecutive pages, newest last.
+    faults: VecDeque<(usize, usize)>,
+    stride: Option<Stride>,
+    // (start page, end page) ranges predicted by the last fault, for stride_readahead().
+    predicted: Vec<(usize, usize)>,
+    // fault_count when the stream last faulted.
+    last_fault: u64,
+}
+
+impl Stream {
+    fn new() -> Self {
+        Stream {
+            faults: VecDeque::new(),
+            stride: None,
+            predicted: vec![],
+            last_fault: 0,
+        }
+    }
+
+    // Records a fault and returns whether it follows a stride. If it does, the pages the faults
+    // are heading for are put in `predicted`.
+    // `loaded` are the loaded pages of the mapping and `stride_pages` is
+    // PageHeuristics::stride_pages.
+    fn follow_stride(
+        &mut self,
+        pagenum: usize,
+        loaded: &BTreeSet<usize>,
+        stride_pages: usize,
+    ) -> bool {
+        match self.faults.back_mut() {
+            Some(last) if last.1 == pagenum => last.1 += 1,
+            _ => {
+                if self.faults.len() == STRIDE_HISTORY {
+                    self.faults.pop_front();
+                }
+                self.faults.push_back((pagenum, pagenum + 1));
+            }
+        }
+
+        // The reader got past what was read ahead for it. Read twice as far this time.
+        if let Some(mut stride) = self.stride {
+            if stride.next == pagenum as isize {
+                stride.depth *= 2;
+                self.predict(pagenum, stride, stride_pages);
+                return true;
+            }
+        }
+
+        self.stride = None;
+        if self.faults.len() < STRIDE_HISTORY {
+            return false;
+        }
+        let (a, b, c) = (self.faults[0], self.faults[1], self.faults[2]);
+        let run = a.1 - a.0;
+        let stride = b.0 as isize - a.0 as isize;
+        let is_stride = c.0 == pagenum
+            && b.1 - b.0 == run
+            && c.0 as isize - b.0 as isize == stride
+            && stride.abs() >= run as isize
+            && (stride < 0 || !loaded.contains(&a.1) && !loaded.contains(&b.1));
+        if !is_stride {
+            return false;
+        }
+        let stride = Stride {
+            stride,
+            run,
+            depth: STRIDE_INITIAL_DEPTH,
+            next: 0,
+        };
+        self.predict(pagenum, stride, stride_pages);
+        true
+    }
+
+    // Puts the rest of the run starting at `pagenum` and the next `depth` runs in `predicted`.
+    fn predict(&mut self, pagenum: usize, mut stride: Stride, stride_pages: usize) {
+        let max_depth = cmp::max(stride_pages / stride.run, 1);
+        stride.depth = cmp::min(stride.depth, cmp::min(max_depth, STRIDE_MAX_DEPTH));
+
+        let mut ranges = vec![(pagenum + 1, pagenum + stride.run)];
+        for k in 1..=stride.depth as isize {
+            let start = pagenum as isize + k * stride.stride;
+            let end = start + stride.run as isize;
+            if end <= 0 {
+                break;
+            }
+            ranges.push((cmp::max(start, 0) as usize, end as usize));
+        }
+        ranges.sort();
+        for (start, end) in ranges {
+            if start >= end {
+                continue;
+            }
+            match self.predicted.last_mut() {
+                Some(last) if last.1 >= start => last.1 = cmp::max(last.1, end),
+                _ => self.predicted.push((start, end)),
+            }
+        }
+
+        stride.next = pagenum as isize + (stride.depth as isize + 1) * stride.stride;
+        self.stride = Some(stride);
+    }
+}
+
+// Faults going backwards or a constant number of pages apart.
+#[derive(Clone, Copy)]
+struct Stride {
+    // Pages from the start of one run of faults to the start of the next. Negative goes backwards.
+    stride: isize,
+    // Pages in each run.
+    run: usize,
+    // How many strides ahead are read.
+    depth: usize,
+    // Where the run after the ones read ahead starts.
+    next: isize,
 }
 
 impl PageHeuristics {
-    pub fn new() -> Self {
-        PageHeuristics {
-            level1slices: BTreeMap::new(),
-            level2slices: BTreeMap::new(),
-            evict_queue: VecDeque::new(),
+    // `page_size` is the size of the pages of the mapping, in bytes.
+    pub fn new(page_size: usize) -> Self {
+        PageHeuristics::with_config(page_size, &HeuristicsConfig::new())
+    }
+
+    pub fn with_config(page_size: usize, config: &HeuristicsConfig) -> Self {
+        let mut heuristics = PageHeuristics {
+            page_size,
+            config: config.clone(),
+            max_loaded_pages: 0,
+            evict_below_pages: 0,
+            sync_read_size: 0,
+            background_read_size: 0,
+            min_request_size: 0,
+            max_request_size: 0,
+            readahead: config.readahead_policy.make(),
+            eviction: config.eviction_policy.make(),
+            loaded: BTreeSet::new(),
+            patterns: BTreeMap::new(),
+            pinned: BTreeSet::new(),
+            streams: BTreeMap::new(),
+            fault_count: 0,
+            stride_pages: 0,
+        };
+        heuristics.set_config(config);
+        heuristics
+    }
+
+    // Switches to another config. What has been loaded, advised and pinned is kept. New policies
+    // are told about everything that is loaded.
+    pub fn set_config(&mut self, config: &HeuristicsConfig) {
+        let page_size = self.page_size;
+        // Configs are checked with consistent() before they get here.
+        let max_loaded_pages = cmp::max(config.max_loaded_size / page_size, 1);
+
+        self.max_loaded_pages = max_loaded_pages;
+        self.evict_below_pages = cmp::min(
+            cmp::max(config.evict_below_max_loaded_size / page_size, 1),
+            max_loaded_pages,
+        );
+        self.sync_read_size = cmp::max(config.sync_read_size / page_size, 1);
+        self.background_read_size = cmp::max(config.background_read_size / page_size, 1);
+        self.min_request_size = cmp::max(config.min_request_size / page_size, 1);
+        self.max_request_size = cmp::max(config.max_request_size / page_size, 1);
+        let level1_readahead = config
+            .level1_readahead
+            .saturating_mul(config.level1_slice_size);
+        self.stride_pages = cmp::max(level1_readahead / page_size, 1);
+
+        if !config.readahead_policy.same(&self.config.readahead_policy) {
+            self.readahead = config.readahead_policy.make();
+            self.readahead.configure(page_size, config);
+            for pagenum in self.loaded.iter() {
+                self.readahead.loaded(*pagenum);
+            }
+        } else {
+            self.readahead.configure(page_size, config);
+        }
+
+        if !config.eviction_policy.same(&self.config.eviction_policy) {
+            self.eviction = config.eviction_policy.make();
+            self.eviction.set_capacity(max_loaded_pages);
+            for pagenum in self.loaded.iter() {
+                if !self.pinned.contains(pagenum) {
+                    self.eviction.insert(*pagenum);
+                }
+            }
+        } else {
+            self.eviction.set_capacity(max_loaded_pages);
+        }
+
+        self.config = config.clone();
+    }
+
+    pub fn pin_pages(&mut self, start_page: usize, end_page: usize) {
+        self.pinned.extend(start_page..end_page);
+    }
+
+    pub fn unpin_pages(&mut self, start_page: usize, end_page: usize) {
+        for pagenum in start_page..end_page {
+            if !self.pinned.remove(&pagenum) {
+                continue;
+            }
+            if self.loaded.contains(&pagenum) && !self.eviction.contains(pagenum) {
+                self.eviction.insert(pagenum);
+            }
+        }
+    }
+
+    // Records that some loaded pages have been used. Pages that are not loaded (anymore) or are
+    // pinned are left alone.
+    pub fn accessed(&mut self, start_page: usize, end_page: usize) {
+        for pagenum in start_page..end_page {
+            if self.loaded.contains(&pagenum) && !self.pinned.contains(&pagenum) {
+                self.eviction.access(pagenum);
+            }
+        }
+    }
+
+    // Takes advice for the bytes [offset, offset+sz). WillNeed marks the pages as read and returns
+    // the (offset, length) pieces to read in the background; pages to evict to make room are put
+    // in `evictions`. DontNeed only forgets the pages; evicting them is up to the caller.
+    pub fn advise(
+        &mut self,
+        offset: usize,
+        sz: usize,
+        advice: Advice,
+        evictions: &mut BTreeSet<usize>,
+    ) -> Vec<(usize, usize)> {
+        let start_page = offset / self.page_size;
+        let end_page = (offset + round_up_to(sz, self.page_size)) / self.page_size;
+        match advice {
+            Advice::Normal | Advice::Sequential | Advice::Random => {
+                self.set_pattern(start_page, end_page, advice);
+                vec![]
+            }
+            Advice::WillNeed => {
+                self.mark_pages_as_read(start_page, end_page);
+                self.evict_pages_if_needed(evictions);
+                self.split_background(offset, sz)
+            }
+            Advice::DontNeed => {
+                for pagenum in start_page..end_page {
+                    self.forget(pagenum);
+                }
+                vec![]
+            }
+        }
+    }
+
+    fn set_pattern(&mut self, start_page: usize, end_page: usize, advice: Advice) {
+        let overlapping: Vec<(usize, usize, Advice)> = self
+            .patterns
+            .range(..end_page)
+            .filter(|(_, (end, _))| *end > start_page)
+            .map(|(start, (end, advice))| (*start, *end, *advice))
+            .collect();
+        for (start, end, old_advice) in overlapping {
+            self.patterns.remove(&start);
+            if start < start_page {
+                self.patterns.insert(start, (start_page, old_advice));
+            }
+            if end > end_page {
+                self.patterns.insert(end_page, (end, old_advice));
+            }
+        }
+        if advice != Advice::Normal {
+            self.patterns.insert(start_page, (end_page, advice));
+        }
+    }
+
+    fn pattern(&self, pagenum: usize) -> Advice {
+        match self.patterns.range(..=pagenum).next_back() {
+            Some((_, (end, advice))) if *end > pagenum => *advice,
+            _ => Advice::Normal,
         }
     }
 
@@ -47,22 +499,22 @@ impl PageHeuristics {
     // The range is not inclusive so 'end_page' itself is not included.
     pub fn mark_pages_as_read(&mut self, start_page: usize, end_page: usize) {
         for pagenum in start_page..end_page {
-            self.evict_queue.push_back(pagenum);
-
-            let slice1num = pagenum / LEVEL1_SLICE_SIZE;
-            let slice2num = pagenum / LEVEL2_SLICE_SIZE;
-            let slice1page = pagenum % LEVEL1_SLICE_SIZE;
-            let slice2page = pagenum % LEVEL2_SLICE_SIZE;
+            if self.loaded.insert(pagenum) {
+                self.readahead.loaded(pagenum);
+            }
+            self.eviction.insert(pagenum);
+        }
+    }
 
-            {
-                let slice1entry = self.level1slices.entry(slice1num);
-                let s1e = slice1entry.or_insert_with(|| Slice::new(LEVEL1_SLICE_SIZE));
-                s1e.add_page(slice1page);
+    // Takes back pages that were picked for eviction but could not be evicted (modified or
+    // pinned). They are still loaded and go to the back of the line.
+    pub fn kept(&mut self, start_page: usize, end_page: usize) {
+        for pagenum in start_page..end_page {
+            if self.loaded.insert(pagenum) {
+                self.readahead.loaded(pagenum);
             }
-            {
-                let slice2entry = self.level2slices.entry(slice2num);
-                let s2e = slice2entry.or_insert_with(|| Slice::new(LEVEL2_SLICE_SIZE));
-                s2e.add_page(slice2page);
+            if !self.pinned.contains(&pagenum) {
+                self.eviction.insert(pagenum);
             }
         }
     }
@@ -71,32 +523,40 @@ impl PageHeuristics {
     //
     // It puts the pages it wants to evict in the given BTreeSet.
     pub fn evict_pages_if_needed(&mut self, evictions: &mut BTreeSet<usize>) {
-        if self.evict_queue.len() > MAX_LOADED_PAGES {
-            // evict so that we are 100 pages below maximum
-            for _ in 0..(self.evict_queue.len() - MAX_LOADED_PAGES
-                + NUM_PAGES_TO_GO_BELOW_MAX_LOADED_PAGES_ON_EVICT)
-            {
-                let page_evict = self.evict_queue.pop_front().unwrap();
-                let slice1num = page_evict / LEVEL1_SLICE_SIZE;
-                let slice2num = page_evict / LEVEL2_SLICE_SIZE;
-                let slice1page = page_evict % LEVEL1_SLICE_SIZE;
-                let slice2page = page_evict % LEVEL2_SLICE_SIZE;
-                {
-                    let slice1entry = self.level1slices.entry(slice1num);
-                    let s1e = slice1entry.or_insert_with(|| Slice::new(LEVEL1_SLICE_SIZE));
-                    s1e.remove_page(slice1page);
-                }
-                {
-                    let slice2entry = self.level2slices.entry(slice2num);
-                    let s2e = slice2entry.or_insert_with(|| Slice::new(LEVEL2_SLICE_SIZE));
-                    s2e.remove_page(slice2page);
+        if self.eviction.len() > self.max_loaded_pages {
+            // evict so that we are some pages below maximum
+            while self.eviction.len() > self.max_loaded_pages - self.evict_below_pages {
+                let page_evict = match self.eviction.pick_victim() {
+                    Some(page_evict) => page_evict,
+                    None => break,
+                };
+                if self.pinned.contains(&page_evict) {
+                    continue;
                 }
-
+                self.loaded.remove(&page_evict);
+                self.readahead.evicted(page_evict);
                 evictions.insert(page_evict);
             }
         }
     }
 
+    // Forgets pages that are being evicted for the process-wide memory budget. Pinned pages stay.
+    pub fn budget_evicted(&mut self, pages: &BTreeSet<usize>) {
+        for pagenum in pages.iter() {
+            self.forget(*pagenum);
+        }
+    }
+
+    fn forget(&mut self, pagenum: usize) {
+        if self.pinned.contains(&pagenum) {
+            return;
+        }
+        if self.loaded.remove(&pagenum) {
+            self.readahead.evicted(pagenum);
+            self.eviction.remove(pagenum);
+        }
+    }
+
     // Same as evict_pages_if_needed but returns a BTreeSet instead of mutating one.
     pub fn evict_pages_if_needed2(&mut self) -> BTreeSet<usize> {
         let mut evictions = BTreeSet::new();
@@ -107,41 +567,355 @@ impl PageHeuristics {
     // Checks if we can do a read-ahead heuristic.
     //
     // You give this function the offset and the number of bytes you want to read at minimum (which is usually 1
-    // page, so 4096 bytes) and this function may or may not tell you to read a lot more.
+    // page) and this function may or may not tell you to read a lot more.
     //
     // Caution: the heuristics doesn't know how large the actual underlying resource is so this can
     // tell you to read more data than there is. Just check the value against actual size and cap
     // it off as needed. As long as you use mark_pages_as_read() with the actual pages you read the
     // heuristics will be in good staet.
-    pub fn readahead_heuristic(&mut self, offset: usize, actual_read_sz: usize) -> usize {
+    //
+    // The result is kept between the config's min_request_size and max_request_size.
+    //
+    // `stream` is the thread that faulted. Faults that follow a stride only read what they need
+    // here; what they are heading for comes from stride_readahead().
+    pub fn readahead_heuristic(
+        &mut self,
+        stream: u32,
+        offset: usize,
+        actual_read_sz: usize,
+    ) -> usize {
+        let pagenum = offset / self.page_size;
+        let advice = self.pattern(pagenum);
+        self.make_room_for_stream(stream);
+        self.fault_count += 1;
+        let follows_stride = {
+            let stream = self.streams.entry(stream).or_insert_with(Stream::new);
+            stream.last_fault = self.fault_count;
+            stream.predicted.clear();
+            advice == Advice::Normal
+                && stream.follow_stride(pagenum, &self.loaded, self.stride_pages)
+        };
+        let actual_read_sz = match advice {
+            Advice::Random => actual_read_sz,
+            _ if follows_stride => actual_read_sz,
+            advice => self
+                .readahead
+                .readahead(stream, offset, actual_read_sz, advice),
+        };
+        let actual_read_sz = cmp::max(actual_read_sz, self.min_request_size * self.page_size);
+        cmp::min(actual_read_sz, self.max_request_size * self.page_size)
+    }
+
+    // Forgets the stream that faulted least recently if `stream` is new and there are too many.
+    fn make_room_for_stream(&mut self, stream: u32) {
+        if self.streams.len() < MAX_STREAMS || self.streams.contains_key(&stream) {
+            return;
+        }
+        let oldest = self
+            .streams
+            .iter()
+            .min_by_key(|(_, stream)| stream.last_fault)
+            .map(|(id, _)| *id);
+        if let Some(oldest) = oldest {
+            self.streams.remove(&oldest);
+            self.readahead.stream_ended(oldest);
+        }
+    }
+
+    // The pages the last readahead_heuristic() of `stream` found a stride heading for, as (offset,
+    // length) pieces to read in the background. Pages from `end` bytes on (the end of the
+    // underlying resource) and pages that are already loaded are left out. The rest are marked as
+    // read.
+    pub fn stride_readahead(&mut self, stream: u32, end: usize) -> Vec<(usize, usize)> {
+        let end_page = round_up_to(end, self.page_size) / self.page_size;
+        let predicted = match self.streams.get_mut(&stream) {
+            Some(stream) => mem::take(&mut stream.predicted),
+            None => return vec![],
+        };
+        let mut background = vec![];
+        for (start, stop) in predicted {
+            let stop = cmp::min(stop, end_page);
+            let mut pagenum = start;
+            while pagenum < stop {
+                if self.loaded.contains(&pagenum) {
+                    pagenum += 1;
+                    continue;
+                }
+                let missing_start = pagenum;
+                while pagenum < stop && !self.loaded.contains(&pagenum) {
+                    pagenum += 1;
+                }
+                self.mark_pages_as_read(missing_start, pagenum);
+                background.extend(self.split_background(
+                    missing_start * self.page_size,
+                    (pagenum - missing_start) * self.page_size,
+                ));
+            }
+        }
+        background
+    }
+
+    // Splits a read decided by readahead_heuristic() into the part the faulting thread waits for
+    // and the (offset, length) pieces that can be read in the background.
+    //
+    // The returned length and pieces together cover exactly [offset, offset+sz).
+    pub fn split_readahead(&self, offset: usize, sz: usize) -> (usize, Vec<(usize, usize)>) {
+        let sync_sz = cmp::min(sz, self.sync_read_size * self.page_size);
+        (
+            sync_sz,
+            self.split_background(offset + sync_sz, sz - sync_sz),
+        )
+    }
+
+    // Cuts [offset, offset+sz) into pieces of at most BACKGROUND_READ_SIZE.
+    fn split_background(&self, offset: usize, sz: usize) -> Vec<(usize, usize)> {
+        let mut background = vec![];
+        let mut cursor = offset;
+        while cursor < offset + sz {
+            let len = cmp::min(
+                offset + sz - cursor,
+                self.background_read_size * self.page_size,
+            );
+            background.push((cursor, len));
+            cursor += len;
+        }
+        background
+    }
+}
+
+// The default read-ahead policy: reads ahead when a slice is about to fill up.
+pub struct TwoLevelReadahead {
+    page_size: usize,
+    // In pages.
+    level1_slice_size: usize,
+    level2_slice_size: usize,
+    // In slices.
+    level1_readahead: usize,
+    level2_readahead: usize,
+
+    level1slices: BTreeMap<usize, Slice>,
+    level2slices: BTreeMap<usize, Slice>,
+    // Where the last read-ahead of each stream went, in pages: [start, end).
+    windows: BTreeMap<u32, (usize, usize)>,
+}
+
+impl TwoLevelReadahead {
+    pub fn new() -> Self {
+        TwoLevelReadahead {
+            page_size: 0,
+            level1_slice_size: 0,
+            level2_slice_size: 0,
+            level1_readahead: 0,
+            level2_readahead: 0,
+            level1slices: BTreeMap::new(),
+            level2slices: BTreeMap::new(),
+            windows: BTreeMap::new(),
+        }
+    }
+
+    // (level1, level2) slice sizes in pages. Copied out so that they can be used while the slices
+    // are borrowed.
+    fn slice_sizes(&self) -> (usize, usize) {
+        (self.level1_slice_size, self.level2_slice_size)
+    }
+
+    fn add_to_slices(&mut self, pagenum: usize) {
+        let (level1_slice_size, level2_slice_size) = self.slice_sizes();
+        let slice1num = pagenum / level1_slice_size;
+        let slice2num = pagenum / level2_slice_size;
+        let slice1page = pagenum % level1_slice_size;
+        let slice2page = pagenum % level2_slice_size;
+
+        {
+            let slice1entry = self.level1slices.entry(slice1num);
+            let s1e = slice1entry.or_insert_with(|| Slice::new(level1_slice_size));
+            s1e.add_page(slice1page);
+        }
+        {
+            let slice2entry = self.level2slices.entry(slice2num);
+            let s2e = slice2entry.or_insert_with(|| Slice::new(level2_slice_size));
+            s2e.add_page(slice2page);
+        }
+    }
+
+    fn remove_from_slices(&mut self, pagenum: usize) {
+        let (level1_slice_size, level2_slice_size) = self.slice_sizes();
+        if let Some(s1e) = self.level1slices.get_mut(&(pagenum / level1_slice_size)) {
+            s1e.remove_page(pagenum % level1_slice_size);
+        }
+        if let Some(s2e) = self.level2slices.get_mut(&(pagenum / level2_slice_size)) {
+            s2e.remove_page(pagenum % level2_slice_size);
+        }
+    }
+
+    // Given an offset and a read size, extend the read size until level1 read-ahead is met.
+    //
+    // Twist: extend the read-ahead so that if we read the next page, we immediately trigger a
+    // second read-ahead. We do this by extending the read-ahead so that we almost completely read
+    // the next slice in full as well.
+    //
+    // If level2 slice lines up well we may extend to that instead.
+    fn extend_readahead1(&self, offset: usize, minsz: usize) -> usize {
+        let level1_readahead = self
+            .level1_readahead
+            .saturating_mul(self.level1_slice_size)
+            .saturating_mul(self.page_size);
+        // but what if we extended to next level2 boundary? (so next read will trigger level2
+        // read-ahead)
+        let minsz_page = (offset + minsz - 1) / self.page_size;
+        let minsz_level2_page = minsz_page % self.level2_slice_size;
+        if minsz_level2_page == self.level2_slice_size - 2 {
+            return minsz;
+        } else if minsz_level2_page < self.level2_slice_size - 2 {
+            let missing_pages = (self.level2_slice_size - 2) - minsz_level2_page;
+            let new_sz = minsz + missing_pages * self.page_size;
+            // Round to level2 boundary if the amount of reading would be less than level1
+            // readahead
+            if new_sz <= level1_readahead {
+                return new_sz;
+            }
+        }
+        self.roundup_slice1(offset, level1_readahead)
+    }
+
+    fn roundup_slice1(&self, offset: usize, sz: usize) -> usize {
+        let final_page = (offset + sz - 1) / self.page_size;
+        let level1_page = final_page % self.level1_slice_size;
+        if level1_page == self.level1_slice_size - 2 {
+            sz
+        } else if level1_page == self.level1_slice_size - 1 {
+            sz + (self.level1_slice_size - 1) * self.page_size
+        } else {
+            let missing_pages = (self.level1_slice_size - 2) - level1_page;
+            sz + missing_pages * self.page_size
+        }
+    }
+}
+
+impl ReadaheadPolicy for TwoLevelReadahead {
+    // If the slice sizes change, loaded pages are sorted into the new slices.
+    fn configure(&mut self, page_size: usize, config: &HeuristicsConfig) {
+        let (level1_slice_size, level2_slice_size) = config.slice_pages(page_size);
+        if (page_size, level1_slice_size, level2_slice_size)
+            != (
+                self.page_size,
+                self.level1_slice_size,
+                self.level2_slice_size,
+            )
+        {
+            let loaded: Vec<usize> = self
+                .level1slices
+                .iter()
+                .flat_map(|(slice1num, s1e)| {
+                    let base = slice1num * self.level1_slice_size;
+                    s1e.loaded_pages.iter().map(move |page| base + page)
+                })
+                .collect();
+            self.level1slices.clear();
+            self.level2slices.clear();
+            self.windows.clear();
+            self.page_size = page_size;
+            self.level1_slice_size = level1_slice_size;
+            self.level2_slice_size = level2_slice_size;
+            for pagenum in loaded {
+                self.add_to_slices(pagenum);
+            }
+        }
+        self.level1_readahead = cmp::max(config.level1_readahead, 1);
+        self.level2_readahead = cmp::max(config.level2_readahead, 1);
+    }
+
+    // Slices fill up the same whichever streams the pages were loaded by. What each stream has
+    // of its own is the window its last read-ahead went to.
+    fn readahead(
+        &mut self,
+        stream: u32,
+        offset: usize,
+        actual_read_sz: usize,
+        advice: Advice,
+    ) -> usize {
+        let (level1_slice_size, level2_slice_size) = self.slice_sizes();
+        let asked = actual_read_sz;
         let mut actual_read_sz = actual_read_sz;
-        let slice1num = offset / *PAGESIZE_USIZE / LEVEL1_SLICE_SIZE;
-        let slice2num = offset / *PAGESIZE_USIZE / LEVEL2_SLICE_SIZE;
-        let slice1page = (offset / *PAGESIZE_USIZE) % LEVEL1_SLICE_SIZE;
-        let slice2page = (offset / *PAGESIZE_USIZE) % LEVEL2_SLICE_SIZE;
+        // Don't wait for the slice to fill up.
+        if advice == Advice::Sequential {
+            actual_read_sz = cmp::max(
+                actual_read_sz,
+                self.roundup_slice1(
+                    offset,
+                    self.level1_readahead
+                        .saturating_mul(level1_slice_size)
+                        .saturating_mul(self.page_size),
+                ),
+            );
+        }
+        let slice1num = offset / self.page_size / level1_slice_size;
+        let slice2num = offset / self.page_size / level2_slice_size;
+        let slice1page = (offset / self.page_size) % level1_slice_size;
+        let slice2page = (offset / self.page_size) % level2_slice_size;
         // Would we fill a small slice?
         {
             let slice1entry = self.level1slices.entry(slice1num);
-            let s1e = slice1entry.or_insert_with(|| Slice::new(LEVEL1_SLICE_SIZE));
+            let s1e = slice1entry.or_insert_with(|| Slice::new(level1_slice_size));
             if s1e.would_fill(slice1page) {
-                actual_read_sz = extend_readahead1(offset, *PAGESIZE_USIZE);
+                actual_read_sz = self.extend_readahead1(offset, self.page_size);
             }
         }
 
         // Would we fill a big slice?
         {
             let slice2entry = self.level2slices.entry(slice2num);
-            let s2e = slice2entry.or_insert_with(|| Slice::new(LEVEL2_SLICE_SIZE));
+            let s2e = slice2entry.or_insert_with(|| Slice::new(level2_slice_size));
             if s2e.would_fill(slice2page) {
                 actual_read_sz = cmp::max(
                     actual_read_sz,
-                    LEVEL2_READAHEAD * LEVEL2_SLICE_SIZE * *PAGESIZE_USIZE,
+                    self.level2_readahead
+                        .saturating_mul(level2_slice_size)
+                        .saturating_mul(self.page_size),
                 );
-                actual_read_sz = roundup_slice1(offset, actual_read_sz);
+                actual_read_sz = self.roundup_slice1(offset, actual_read_sz);
+            }
+        }
+
+        // A fault in the stream's window that the slices don't answer is on a page that was
+        // evicted before the reader got to it, most likely to make room for what other streams
+        // read. The reader is still going the same way, so it reads as much again rather than
+        // waiting for the slices to fill up from scratch.
+        let page = offset / self.page_size;
+        if actual_read_sz == asked {
+            match self.windows.get(&stream) {
+                Some(&(start, end)) if start <= page && page < end => {
+                    let loaded = self
+                        .level1slices
+                        .get(&slice1num)
+                        .map_or(false, |s1e| s1e.loaded_pages.contains(&slice1page));
+                    if !loaded {
+                        actual_read_sz = cmp::max(actual_read_sz, (end - start) * self.page_size);
+                    }
+                }
+                _ => {
+                    self.windows.remove(&stream);
+                }
             }
         }
+        if actual_read_sz > asked {
+            let end = (offset + actual_read_sz + self.page_size - 1) / self.page_size;
+            self.windows.insert(stream, (page, end));
+        }
         actual_read_sz
     }
+
+    fn stream_ended(&mut self, stream: u32) {
+        self.windows.remove(&stream);
+    }
+
+    fn loaded(&mut self, page: usize) {
+        self.add_to_slices(page);
+    }
+
+    fn evicted(&mut self, page: usize) {
+        self.remove_from_slices(page);
+    }
 }
 
 struct Slice {
@@ -178,64 +952,399 @@ impl Slice {
     }
 }
 
-// Given an offset and a read size, extend the read size until level1 read-ahead is met.
-//
-// Twist: extend the read-ahead so that if we read the next page, we immediately trigger a second
-// read-ahead. We do this by extending the read-ahead so that we almost completely read the next
-// slice in full as well.
-//
-// If level2 slice lines up well we may extend to that instead.
-fn extend_readahead1(offset: usize, minsz: usize) -> usize {
-    // this is the value if we just straight up extend with level1 readahead size
-    let actual_read_sz = LEVEL1_READAHEAD * LEVEL1_SLICE_SIZE * *PAGESIZE_USIZE;
-    // but what if we extended to next level2 boundary? (so next read will trigger level2
-    // read-ahead)
-    let minsz_page = (offset + minsz - 1) / *PAGESIZE_USIZE;
-    let minsz_level2_page = minsz_page % LEVEL2_SLICE_SIZE;
-    if minsz_level2_page == LEVEL2_SLICE_SIZE - 2 {
-        return minsz;
-    } else if minsz_level2_page < LEVEL2_SLICE_SIZE - 2 {
-        let missing_pages = (LEVEL2_SLICE_SIZE - 2) - minsz_level2_page;
-        let new_sz = minsz + missing_pages * *PAGESIZE_USIZE;
-        // Round to level2 boundary if the amount of reading would be less than level1 readahead
-        if new_sz <= LEVEL1_READAHEAD * LEVEL1_SLICE_SIZE * *PAGESIZE_USIZE {
-            return new_sz;
-        }
-    }
-    roundup_slice1(offset, actual_read_sz)
-}
-
-fn roundup_slice1(offset: usize, sz: usize) -> usize {
-    let final_page = (offset + sz - 1) / *PAGESIZE_USIZE;
-    let level1_page = final_page % LEVEL1_SLICE_SIZE;
-    if level1_page == LEVEL1_SLICE_SIZE - 2 {
-        sz
-    } else if level1_page == LEVEL1_SLICE_SIZE - 1 {
-        sz + (LEVEL1_SLICE_SIZE - 1) * *PAGESIZE_USIZE
-    } else {
-        let missing_pages = (LEVEL1_SLICE_SIZE - 2) - level1_page;
-        sz + missing_pages * *PAGESIZE_USIZE
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn two_level(page_size: usize, config: &HeuristicsConfig) -> TwoLevelReadahead {
+        let mut readahead = TwoLevelReadahead::new();
+        readahead.configure(page_size, config);
+        readahead
+    }
+
     #[test]
     fn roundup_slice1_tests() {
+        let heuristics = two_level(4096, &HeuristicsConfig::new());
+        let slice = heuristics.level1_slice_size;
         // 1 page at 0th offset should get extended to just below 1 slice size.
-        assert_eq!(roundup_slice1(0, 4096), 4096 * (LEVEL1_SLICE_SIZE - 1));
+        assert_eq!(heuristics.roundup_slice1(0, 4096), 4096 * (slice - 1));
         // 1 page at 1th offset should get extended to just below 1 slice size, minus the one page
         //   we have with offset.
-        assert_eq!(roundup_slice1(4096, 4096), 4096 * (LEVEL1_SLICE_SIZE - 2));
+        assert_eq!(heuristics.roundup_slice1(4096, 4096), 4096 * (slice - 2));
         // Non-page aligned read
-        assert_eq!(roundup_slice1(1111, 4096), 4096 * (LEVEL1_SLICE_SIZE - 2));
+        assert_eq!(heuristics.roundup_slice1(1111, 4096), 4096 * (slice - 2));
 
         // After one slice read
         assert_eq!(
-            roundup_slice1(LEVEL1_SLICE_SIZE * 4096, 4096),
-            4096 * (LEVEL1_SLICE_SIZE - 1)
+            heuristics.roundup_slice1(slice * 4096, 4096),
+            4096 * (slice - 1)
+        );
+    }
+
+    #[test]
+    fn advised_patterns_tests() {
+        let mut heuristics = PageHeuristics::new(4096);
+        let slice = LEVEL1_SLICE_SIZE / 4096;
+        let mut evictions = BTreeSet::new();
+        heuristics.advise(0, 4096 * 1000, Advice::Random, &mut evictions);
+        heuristics.advise(4096 * 100, 4096 * 100, Advice::Sequential, &mut evictions);
+        assert_eq!(heuristics.pattern(99), Advice::Random);
+        assert_eq!(heuristics.pattern(100), Advice::Sequential);
+        assert_eq!(heuristics.pattern(200), Advice::Random);
+        assert_eq!(heuristics.pattern(1000), Advice::Normal);
+
+        // No read-ahead for random access, even when a slice fills up.
+        heuristics.mark_pages_as_read(0, slice - 1);
+        assert_eq!(
+            heuristics.readahead_heuristic(1, (slice - 1) * 4096, 4096),
+            4096
+        );
+        // Sequential access reads ahead right away.
+        assert!(heuristics.readahead_heuristic(1, 4096 * 150, 4096) > 4096 * slice);
+
+        heuristics.advise(0, 4096 * 1000, Advice::Normal, &mut evictions);
+        assert_eq!(heuristics.pattern(150), Advice::Normal);
+        assert!(evictions.is_empty());
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let mut heuristics = PageHeuristics::new(4096);
+        let max_loaded = heuristics.max_loaded_pages;
+        heuristics.pin_pages(0, 10);
+        heuristics.mark_pages_as_read(0, max_loaded + 1);
+        let evictions = heuristics.evict_pages_if_needed2();
+        assert_eq!(evictions.len(), heuristics.evict_below_pages + 1 - 10);
+        assert!(evictions.iter().all(|page| *page >= 10));
+
+        // Unpinned pages go to the back of the queue.
+        heuristics.unpin_pages(0, 10);
+        heuristics.mark_pages_as_read(max_loaded + 1, max_loaded + 1000);
+        let evictions = heuristics.evict_pages_if_needed2();
+        assert!(!evictions.contains(&0));
+    }
+
+    #[test]
+    fn kept_pages_stay_loaded() {
+        let mut heuristics = PageHeuristics::new(4096);
+        let max_loaded = heuristics.max_loaded_pages;
+        heuristics.mark_pages_as_read(0, max_loaded + 1);
+        let evictions = heuristics.evict_pages_if_needed2();
+        assert!(evictions.contains(&0));
+
+        // Page 0 was modified and could not go. It counts as loaded again and goes to the back of
+        // the line.
+        heuristics.kept(0, 1);
+        assert!(heuristics.loaded.contains(&0));
+        heuristics.mark_pages_as_read(max_loaded + 1, max_loaded + 1000);
+        let evictions = heuristics.evict_pages_if_needed2();
+        assert!(!evictions.is_empty());
+        assert!(!evictions.contains(&0));
+    }
+
+    #[test]
+    fn budget_evictions_are_forgotten() {
+        let mut heuristics = PageHeuristics::new(4096);
+        heuristics.pin_pages(0, 5);
+        heuristics.mark_pages_as_read(0, 100);
+
+        // Pinned pages stay whatever the budget says.
+        heuristics.budget_evicted(&(0..10).collect());
+        assert!((0..5).all(|page| heuristics.loaded.contains(&page)));
+        assert!((5..10).all(|page| !heuristics.loaded.contains(&page)));
+        assert_eq!(heuristics.eviction.len(), 95);
+    }
+
+    #[test]
+    fn accessed_pages_survive_scans() {
+        let mut config = HeuristicsConfig::new();
+        config.eviction_policy = EvictionPolicyFactory::clock();
+        let mut heuristics = PageHeuristics::with_config(4096, &config);
+        let max_loaded = heuristics.max_loaded_pages;
+        heuristics.mark_pages_as_read(0, 100);
+        heuristics.accessed(10, 20);
+
+        // A scan of twice as many pages as are kept loaded, with the hot pages used now and then.
+        let mut evicted = BTreeSet::new();
+        for start in (100..2 * max_loaded).step_by(1000) {
+            heuristics.mark_pages_as_read(start, start + 1000);
+            heuristics.accessed(10, 20);
+            heuristics.evict_pages_if_needed(&mut evicted);
+        }
+        assert!(evicted.contains(&0));
+        assert!((10..20).all(|page| !evicted.contains(&page)));
+    }
+
+    // Faults on `pagenum` in `stream` like a handler would and returns how many pages it reads
+    // and the pages it reads in the background.
+    fn stream_fault(
+        heuristics: &mut PageHeuristics,
+        stream: u32,
+        pagenum: usize,
+    ) -> (usize, Vec<(usize, usize)>) {
+        let sz = heuristics.readahead_heuristic(stream, pagenum * 4096, 4096);
+        heuristics.mark_pages_as_read(pagenum, pagenum + sz / 4096);
+        let background = heuristics.stride_readahead(stream, 4096 * 100000);
+        let background = background
+            .into_iter()
+            .map(|(offset, len)| (offset / 4096, (offset + len) / 4096))
+            .collect();
+        (sz / 4096, background)
+    }
+
+    fn fault(heuristics: &mut PageHeuristics, pagenum: usize) -> (usize, Vec<(usize, usize)>) {
+        stream_fault(heuristics, 1, pagenum)
+    }
+
+    #[test]
+    fn backward_and_strided_faults() {
+        // Reading backwards. The third fault starts reading ahead below.
+        let mut heuristics = PageHeuristics::new(4096);
+        assert_eq!(fault(&mut heuristics, 999), (1, vec![]));
+        assert_eq!(fault(&mut heuristics, 998), (1, vec![]));
+        assert_eq!(fault(&mut heuristics, 997), (1, vec![(993, 997)]));
+        // Everything down to the next fault has been read; then it's twice as much.
+        assert_eq!(fault(&mut heuristics, 992), (1, vec![(984, 992)]));
+        let mut faults = 4;
+        for pagenum in (0..984).rev() {
+            if !heuristics.loaded.contains(&pagenum) {
+                fault(&mut heuristics, pagenum);
+                faults += 1;
+            }
+        }
+        assert!(faults < 30);
+
+        // Every tenth page, and then records of two pages every 100 pages.
+        let mut heuristics = PageHeuristics::new(4096);
+        fault(&mut heuristics, 0);
+        fault(&mut heuristics, 10);
+        assert_eq!(
+            fault(&mut heuristics, 20),
+            (1, vec![(30, 31), (40, 41), (50, 51), (60, 61)])
+        );
+        assert_eq!(fault(&mut heuristics, 70).1.len(), 8);
+        for record in 10..12 {
+            fault(&mut heuristics, record * 100);
+            fault(&mut heuristics, record * 100 + 1);
+        }
+        assert_eq!(
+            fault(&mut heuristics, 1200).1,
+            vec![
+                (1201, 1202),
+                (1300, 1302),
+                (1400, 1402),
+                (1500, 1502),
+                (1600, 1602)
+            ]
+        );
+
+        // Sequential reading with read-ahead faults the same distance apart but is left to the
+        // read-ahead policy.
+        let mut heuristics = PageHeuristics::new(4096);
+        for pagenum in 0..100000 {
+            if !heuristics.loaded.contains(&pagenum) {
+                assert_eq!(fault(&mut heuristics, pagenum).1, vec![]);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_streams() {
+        // Two threads reading backwards, faulting in turns.
+        let mut heuristics = PageHeuristics::new(4096);
+        for pagenum in &[999, 998] {
+            assert_eq!(stream_fault(&mut heuristics, 1, *pagenum).1, vec![]);
+            assert_eq!(stream_fault(&mut heuristics, 2, pagenum + 5000).1, vec![]);
+        }
+        assert_eq!(stream_fault(&mut heuristics, 1, 997).1, vec![(993, 997)]);
+        assert_eq!(stream_fault(&mut heuristics, 2, 5997).1, vec![(5993, 5997)]);
+
+        // Two threads reading sequentially on the default config, faulting in turns, fault as
+        // often as one thread reading alone.
+        let read = |streams: usize| {
+            let mut heuristics = PageHeuristics::new(4096);
+            let mut next = [0, LEVEL2_SLICE_SIZE / 4096 * 6];
+            let mut faults = [0, 0];
+            while next[0] < 30000 {
+                for stream in 0..streams {
+                    next[stream] += stream_fault(&mut heuristics, stream as u32, next[stream]).0;
+                    faults[stream] += 1;
+                }
+            }
+            (heuristics, next, faults)
+        };
+        let (_, _, alone) = read(1);
+        let (mut heuristics, next, faults) = read(2);
+        assert_eq!(faults, [alone[0], alone[0]]);
+
+        // When what one of them read ahead is evicted before it gets there, it reads ahead again
+        // right away.
+        let (sz, _) = stream_fault(&mut heuristics, 0, next[0]);
+        assert!(sz > 1);
+        heuristics.budget_evicted(&(next[0] + 1..next[0] + sz).collect());
+        stream_fault(&mut heuristics, 1, next[1]);
+        assert!(stream_fault(&mut heuristics, 0, next[0] + 1).0 > 1);
+
+        // Two threads reading sequentially with on-demand read-ahead each get a growing window.
+        let mut config = HeuristicsConfig::new();
+        config.readahead_policy = ReadaheadPolicyFactory::ondemand();
+        let mut heuristics = PageHeuristics::with_config(4096, &config);
+        let mut next = [0, 50000];
+        let mut sizes = [vec![], vec![]];
+        for _ in 0..4 {
+            for stream in 0..2 {
+                let (sz, _) = stream_fault(&mut heuristics, stream as u32, next[stream]);
+                next[stream] += sz;
+                sizes[stream].push(sz);
+            }
+        }
+        for sizes in &sizes {
+            assert!(sizes[3] > sizes[2] && sizes[2] > sizes[1]);
+        }
+
+        // Only so many threads are followed.
+        for stream in 0..MAX_STREAMS as u32 * 2 {
+            stream_fault(&mut heuristics, stream, 100000 + stream as usize * 100);
+        }
+        assert_eq!(heuristics.streams.len(), MAX_STREAMS);
+        assert!(heuristics
+            .streams
+            .contains_key(&(MAX_STREAMS as u32 * 2 - 1)));
+        assert!(!heuristics.streams.contains_key(&0));
+    }
+
+    #[test]
+    fn split_readahead_tests() {
+        let heuristics = PageHeuristics::new(4096);
+        let sync = heuristics.sync_read_size;
+        let background = heuristics.background_read_size;
+        // Small reads are read in full right away.
+        assert_eq!(heuristics.split_readahead(4096, 4096), (4096, vec![]));
+        assert_eq!(
+            heuristics.split_readahead(0, sync * 4096),
+            (sync * 4096, vec![])
+        );
+
+        // The rest goes to the background, in pieces of at most BACKGROUND_READ_SIZE.
+        let sz = (sync + background + 10) * 4096;
+        assert_eq!(
+            heuristics.split_readahead(4096, sz),
+            (
+                sync * 4096,
+                vec![
+                    (4096 + sync * 4096, background * 4096),
+                    (4096 + (sync + background) * 4096, 10 * 4096),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn config_tests() {
+        let mut config = HeuristicsConfig::new();
+        config.min_request_size = 4096 * 8;
+        config.max_request_size = 4096 * 100;
+        let mut heuristics = PageHeuristics::with_config(4096, &config);
+        let slice = LEVEL1_SLICE_SIZE / 4096;
+        // Every fault reads at least min_request_size...
+        assert_eq!(heuristics.readahead_heuristic(1, 0, 4096), 4096 * 8);
+        // ...and read-ahead stops at max_request_size.
+        heuristics.mark_pages_as_read(0, slice - 1);
+        assert_eq!(
+            heuristics.readahead_heuristic(1, (slice - 1) * 4096, 4096),
+            4096 * 100
         );
+
+        // Smaller slices on a live mapping.
+        config.level1_slice_size = 4096 * 16;
+        heuristics.set_config(&config);
+        assert_eq!(heuristics.max_request_size, 100);
+        assert!(heuristics.readahead_heuristic(1, 4096 * 63, 4096) > 4096 * 8);
+
+        // What's loaded is sorted into them.
+        let mut readahead = two_level(4096, &HeuristicsConfig::new());
+        for page in 0..slice - 1 {
+            readahead.loaded(page);
+        }
+        readahead.configure(4096, &config);
+        assert_eq!(readahead.level1_slice_size, 16);
+        assert_eq!(readahead.level1slices[&0].loaded_pages.len(), 16);
+        assert_eq!(readahead.level1slices[&3].loaded_pages.len(), 15);
+
+        // Knobs that contradict each other are caught rather than bent into shape.
+        let mut config = HeuristicsConfig::new();
+        assert!(config.consistent(4096));
+        assert!(config.consistent(2 * 1024 * 1024));
+        config.max_loaded_size = LEVEL2_SLICE_SIZE;
+        assert!(!config.consistent(4096));
+        config.readahead_policy = ReadaheadPolicyFactory::none();
+        assert!(config.consistent(4096));
+        config.evict_below_max_loaded_size = config.max_loaded_size + 1;
+        assert!(!config.consistent(4096));
+        let mut config = HeuristicsConfig::new();
+        config.max_request_size = 4096 * 100;
+        config.min_request_size = 4096 * 101;
+        assert!(!config.consistent(4096));
+    }
+
+    #[test]
+    fn policies_can_be_changed_on_live_mappings() {
+        let mut config = HeuristicsConfig::new();
+        let mut heuristics = PageHeuristics::with_config(4096, &config);
+        let max_loaded = heuristics.max_loaded_pages;
+        heuristics.mark_pages_as_read(0, max_loaded);
+        heuristics.pin_pages(0, 10);
+
+        // The new policy starts with what's loaded, pinned pages left out.
+        config.eviction_policy = EvictionPolicyFactory::lru();
+        config.readahead_policy = ReadaheadPolicyFactory::none();
+        heuristics.set_config(&config);
+        assert_eq!(heuristics.eviction.len(), max_loaded - 10);
+        assert_eq!(heuristics.readahead_heuristic(1, 4096 * 5, 4096), 4096);
+
+        // Reloading a page makes it the most recently used one.
+        heuristics.mark_pages_as_read(10, 11);
+        heuristics.mark_pages_as_read(max_loaded, max_loaded + 11);
+        let evictions = heuristics.evict_pages_if_needed2();
+        assert_eq!(evictions.len(), heuristics.evict_below_pages + 1);
+        assert!(!evictions.contains(&10));
+        assert!(evictions.contains(&11));
+        assert!(evictions.iter().all(|page| *page >= 10));
+
+        // A clone of the config keeps the policies as they are. (A new eviction policy would
+        // leave out the page pinned here.)
+        heuristics.pin_pages(600, 601);
+        heuristics.set_config(&config.clone());
+        assert!(heuristics.eviction.contains(600));
+    }
+
+    #[test]
+    fn huge_page_units() {
+        // The knobs are the same number of bytes with any page size...
+        let config = HeuristicsConfig::new();
+        let huge = PageHeuristics::new(2 * 1024 * 1024);
+        let small = PageHeuristics::new(4096);
+        let huge_readahead = two_level(2 * 1024 * 1024, &config);
+        let small_readahead = two_level(4096, &config);
+        assert_eq!(
+            huge_readahead.level2_slice_size * 2 * 1024 * 1024,
+            small_readahead.level2_slice_size * 4096
+        );
+        assert_eq!(
+            huge.max_loaded_pages * 2 * 1024 * 1024,
+            small.max_loaded_pages * 4096
+        );
+        // ...except where that would be less than a page or slices would be too small to round
+        // read-ahead to.
+        assert_eq!(huge.sync_read_size, 1);
+        assert_eq!(huge_readahead.level1_slice_size, MIN_SLICE_PAGES);
+
+        // A fault that fills a slice reads ahead in whole huge pages.
+        let mut huge = huge;
+        let page = 2 * 1024 * 1024;
+        huge.mark_pages_as_read(0, MIN_SLICE_PAGES - 1);
+        let sz = huge.readahead_heuristic(1, (MIN_SLICE_PAGES - 1) * page, page);
+        assert!(sz > page);
+        assert_eq!(sz % page, 0);
     }
 }
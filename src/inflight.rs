@@ -0,0 +1,122 @@
+// This module keeps track of byte ranges that are being fetched right now, so that faults close to
+// each other don't fetch the same bytes twice.
+//
+// It knows nothing about where the bytes come from; any MMapHandler can use it.
+//
+/*
+ * A handler claims the range it wants to fetch before it starts. The claim is cut short where
+ * somebody else's claim starts. If the start of the range is already claimed, there is nothing to
+ * do but wait until that claim is released; by then the pages are there (or the fetch failed and
+ * the fault has to be tried again).
+ *
+ * Claims are released when they are dropped. Handlers keep them in their page stream so that they
+ * are released only after the last page has been handed over.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Clone)]
+pub struct InFlight {
+    // start -> (end, claim)
+    ranges: Arc<Mutex<BTreeMap<usize, (usize, Arc<Released>)>>>,
+}
+
+struct Released {
+    released: Mutex<bool>,
+    cond: Condvar,
+}
+
+pub struct InFlightClaim {
+    ranges: Arc<Mutex<BTreeMap<usize, (usize, Arc<Released>)>>>,
+    released: Arc<Released>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight {
+            ranges: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // Claims [start, end), or the part of it up to the first range that is already claimed. If
+    // `start` itself is already claimed, waits for that claim to be released and returns None.
+    pub fn claim(&self, start: usize, end: usize) -> Option<InFlightClaim> {
+        let waiting_for = {
+            let mut ranges = self.ranges.lock().unwrap();
+            match ranges.range(..=start).next_back() {
+                Some((_, (claimed_end, released))) if *claimed_end > start => released.clone(),
+                _ => {
+                    let end = match ranges.range(start..end).next() {
+                        Some((claimed_start, _)) => *claimed_start,
+                        None => end,
+                    };
+                    let released = Arc::new(Released {
+                        released: Mutex::new(false),
+                        cond: Condvar::new(),
+                    });
+                    ranges.insert(start, (end, released.clone()));
+                    return Some(InFlightClaim {
+                        ranges: self.ranges.clone(),
+                        released,
+                        start,
+                        end,
+                    });
+                }
+            }
+        };
+
+        let mut released = waiting_for.released.lock().unwrap();
+        while !*released {
+            released = waiting_for.cond.wait(released).unwrap();
+        }
+        None
+    }
+}
+
+impl Drop for InFlightClaim {
+    fn drop(&mut self) {
+        self.ranges.lock().unwrap().remove(&self.start);
+        *self.released.released.lock().unwrap() = true;
+        self.released.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn claims_are_cut_short() {
+        let inflight = InFlight::new();
+        let later = inflight.claim(100, 200).unwrap();
+        let earlier = inflight.claim(50, 150).unwrap();
+        assert_eq!((earlier.start, earlier.end), (50, 100));
+        // Not overlapping at all.
+        let after = inflight.claim(200, 300).unwrap();
+        assert_eq!((after.start, after.end), (200, 300));
+        drop(later);
+        let again = inflight.claim(100, 250).unwrap();
+        assert_eq!((again.start, again.end), (100, 200));
+    }
+
+    #[test]
+    fn claimed_start_waits_for_release() {
+        let inflight = InFlight::new();
+        let claim = inflight.claim(0, 100).unwrap();
+        let waiter = {
+            let inflight = inflight.clone();
+            thread::spawn(move || inflight.claim(50, 150).is_none())
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(claim);
+        assert!(waiter.join().unwrap());
+        // Released claims are gone.
+        let claim = inflight.claim(50, 150).unwrap();
+        assert_eq!((claim.start, claim.end), (50, 150));
+    }
+}
@@ -3,16 +3,43 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate regex;
 
+mod budget;
 mod capi;
+mod diskcache;
 mod heuristics;
+mod inflight;
 mod mmaputil;
+mod pagecache;
+mod policy;
+mod reactor;
+#[cfg(feature = "async")]
+mod reader;
+mod registry;
 mod userfaultfd;
 mod userfaultfd_dummy;
 mod userfaultfd_s3;
+mod view;
 
-pub use crate::userfaultfd::mmap_with_userfault;
+pub use crate::budget::{memory_budget, memory_used, set_memory_budget};
+pub use crate::diskcache::{set_disk_cache, DiskCache};
+pub use crate::heuristics::HeuristicsConfig;
+pub use crate::mmaputil::MMapPages;
+pub use crate::pagecache::PageCache;
+pub use crate::policy::{
+    EvictionPolicy, EvictionPolicyFactory, ReadaheadPolicy, ReadaheadPolicyFactory,
+};
+pub use crate::userfaultfd::{
+    mmap_with_userfault, mmap_with_userfault_options, Advice, ErrorPolicy, FaultRequest,
+    FaultResolution, MMap, MMapHandler, MMapMode, MMapOptions, PageRun, Resident,
+};
+#[cfg(feature = "async")]
+pub use crate::reader::MMapReader;
+pub use crate::registry::mmap_shared;
 pub use crate::userfaultfd_dummy::MMapDummy;
 pub use crate::userfaultfd_s3::MMapS3;
+pub use crate::view::{Endian, Pod, ViewError};
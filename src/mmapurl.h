@@ -12,6 +12,11 @@ extern "C" {
 #define MMAP_S3_NOT_FOUND        4    // bucket or key not found
 #define MMAP_S3_PERMISSION_ERROR 5    // we are not allowed to read from S3
 #define MMAP_S3_INVALID_S3URL    7    // the S3 url is invalid
+#define MMAP_S3_OBJECT_MODIFIED  9    // object was replaced in S3 while we had
+                                      // it mapped; modifications were not written
+#define MMAP_S3_NOT_MAPPED       10   // pointer was not returned by mmap_s3()
+#define MMAP_S3_NOT_CACHED       11   // offline and the object is not in the
+                                      // disk cache
 
 // These errors are defined but they should never happen; only if our
 // library is buggy or S3 is not conforming to its protocol in some way.
@@ -20,6 +25,56 @@ extern "C" {
 #define MMAP_S3_NO_BODY_RETURNED 6    // S3 GET request didn't include body
 #define MMAP_S3_UNKNOWN          7    // Some error happened we have not categorized.
 
+// What to do if downloading a page fails after the mapping has been
+// established. Set with mmap_s3_options_set_error_policy().
+#define MMAP_S3_POLICY_ABORT     0    // call abort() (the default)
+#define MMAP_S3_POLICY_RETRY     1    // retry a number of times, then abort()
+#define MMAP_S3_POLICY_ZEROFILL  2    // fill the page with zeroes and carry on
+#define MMAP_S3_POLICY_SIGBUS    3    // raise SIGBUS in the faulting thread
+                                      // (needs Linux 6.6+)
+
+// Mapping modes. Set with mmap_s3_options_set_mode().
+#define MMAP_S3_MODE_READONLY    0    // read-only mapping (the default)
+#define MMAP_S3_MODE_WRITEBACK   1    // writable; msync_s3() uploads changes
+#define MMAP_S3_MODE_PRIVATE     2    // writable; changes stay in this process
+                                      // and are never uploaded
+
+// Access hints for madvise_s3(), like the MADV_* flags of madvise().
+#define MMAP_S3_ADVICE_NORMAL     0   // forget earlier SEQUENTIAL and RANDOM advice
+#define MMAP_S3_ADVICE_SEQUENTIAL 1   // read ahead a lot, right from the start
+#define MMAP_S3_ADVICE_RANDOM     2   // don't read ahead at all
+#define MMAP_S3_ADVICE_WILLNEED   3   // start downloading in the background
+#define MMAP_S3_ADVICE_DONTNEED   4   // evict; modified pages are kept
+
+// Read-ahead and eviction knobs. Set with mmap_s3_options_set_heuristic() or,
+// on a live mapping, mmap_s3_set_heuristic(). Sizes are in bytes.
+#define MMAP_S3_HEURISTIC_LEVEL1_SLICE_SIZE           0 // small slices (256kb)
+#define MMAP_S3_HEURISTIC_LEVEL2_SLICE_SIZE           1 // big slices (32mb)
+#define MMAP_S3_HEURISTIC_LEVEL1_READAHEAD            2 // in small slices (16)
+#define MMAP_S3_HEURISTIC_LEVEL2_READAHEAD            3 // in big slices (2)
+#define MMAP_S3_HEURISTIC_MAX_LOADED_SIZE             4 // kept loaded (128mb)
+#define MMAP_S3_HEURISTIC_EVICT_BELOW_MAX_LOADED_SIZE 5 // how far below to evict (~2mb)
+#define MMAP_S3_HEURISTIC_SYNC_READ_SIZE              6 // waited for by a fault (256kb)
+#define MMAP_S3_HEURISTIC_BACKGROUND_READ_SIZE        7 // one background read (8mb)
+#define MMAP_S3_HEURISTIC_MIN_REQUEST_SIZE            8 // a fault reads at least (a page)
+#define MMAP_S3_HEURISTIC_MAX_REQUEST_SIZE            9 // a fault reads at most (no limit)
+#define MMAP_S3_HEURISTIC_READAHEAD_POLICY           10 // one of MMAP_S3_READAHEAD_*
+#define MMAP_S3_HEURISTIC_EVICTION_POLICY            11 // one of MMAP_S3_EVICTION_*
+
+// Read-ahead policies.
+#define MMAP_S3_READAHEAD_TWO_LEVEL 0 // read ahead when a slice fills up (default)
+#define MMAP_S3_READAHEAD_ONDEMAND  1 // a window that doubles as reading goes on
+#define MMAP_S3_READAHEAD_NONE      2 // read only what a fault needs
+
+// Eviction policies.
+#define MMAP_S3_EVICTION_FIFO  0 // in the order pages were loaded (default)
+#define MMAP_S3_EVICTION_LRU   1 // least recently used first
+#define MMAP_S3_EVICTION_CLOCK 2 // second chance for pages used since last time
+#define MMAP_S3_EVICTION_ARC   3 // adaptive replacement cache
+
+// Opaque set of options for mmap_s3_opts().
+typedef struct mmap_s3_options mmap_s3_options;
+
 // Memory maps an S3 object. Returns a pointer to it or MAP_FAILED. Reading
 // from the pointer will trigger downloads from S3 on-demand.
 //
@@ -31,21 +86,204 @@ extern "C" {
 // pointer will be valid.
 //
 // If any errors happen while you are touching the returned pages, the
-// program will call abort().
+// program will call abort(). Use mmap_s3_opts() to choose something else.
+//
+// The returned pointer is read-only unless you use mmap_s3_opts() to ask for
+// a writable mode.
+//
+// The mapping is inherited by forked children, which keep getting pages for
+// as long as this process keeps the mapping (this needs CAP_SYS_PTRACE;
+// otherwise children don't get the mapping at all).
 //
-// The returned pointer is read-only.
+// Opening an object that is already mapped read-only in this process (same
+// URL and same ETag, i.e. the object has not been replaced since) returns
+// the same pointer again instead of mapping it a second time. Asking for it
+// with options that differ from the ones it was mapped with (heuristics as
+// they are now, after any mmap_s3_set_heuristic()) fails with
+// MMAP_S3_ERRNO and errno EBUSY. Heuristics belong to the mapping, so
+// changing them changes them for everybody who has it. Writable mappings are
+// never shared.
 //
-// Unmap the region with munmap_s3().
+// Unmap the region with munmap_s3(), with the pointer returned here, once
+// for every time it was returned.
 const void* mmap_s3(const char* s3url, size_t* sz, int* err);
 
+// Same as mmap_s3() but takes a set of options. 'opts' may be NULL, in
+// which case this behaves exactly like mmap_s3(). The options are copied;
+// you can free them as soon as this returns.
+const void* mmap_s3_opts(const char* s3url, const mmap_s3_options* opts, size_t* sz, int* err);
+
+// Allocates a set of options with default values. Free with
+// mmap_s3_options_free(). The mmap_s3_options_set_*() functions below return
+// 0 on success, and all of them return -1 with errno set to EINVAL if 'opts'
+// is NULL.
+mmap_s3_options* mmap_s3_options_new(void);
+void mmap_s3_options_free(mmap_s3_options* opts);
+
+// Sets what happens when a page cannot be downloaded, one of
+// MMAP_S3_POLICY_*. 'retries' is only used with MMAP_S3_POLICY_RETRY.
+//
+// Returns -1 if the policy is not recognized, 0 otherwise.
+int mmap_s3_options_set_error_policy(mmap_s3_options* opts, int policy, unsigned int retries);
+
+// Sets the mapping mode, one of MMAP_S3_MODE_*. Writable modes need Linux
+// 5.7+ (userfaultfd write-protection).
+//
+// Returns -1 if the mode is not recognized, 0 otherwise.
+int mmap_s3_options_set_mode(mmap_s3_options* opts, int mode);
+
+// Sets how many bytes pin_s3() may keep in memory for the mapping. The
+// default is 64 megabytes.
+int mmap_s3_options_set_pin_limit(mmap_s3_options* opts, size_t limit);
+
+// Sets how many loaded pages are checked every second or so for whether they
+// are still being used, so that pages in use are kept over pages that were
+// read once. The default is 0, no checks. Sampled pages are unmapped and
+// noticed when they are touched; a copy of each is kept until then (except
+// with a page cache), so this costs up to 'pages' pages of memory. Only
+// eviction policies other than MMAP_S3_EVICTION_FIFO, the default, make use
+// of what is found.
+int mmap_s3_options_set_access_sample_size(mmap_s3_options* opts, size_t pages);
+
+// Makes the mapping out of huge pages (hugetlbfs, the system's default huge
+// page size) if 'huge_pages' is non-zero. Huge pages have to be set aside
+// beforehand (vm.nr_hugepages). mmap_s3_opts() fails with EINVAL if the
+// kernel has no huge pages at all.
+int mmap_s3_options_set_huge_pages(mmap_s3_options* opts, int huge_pages);
+
+// Sets one read-ahead or eviction knob, one of MMAP_S3_HEURISTIC_*. Sizes
+// are rounded down to whole pages, but never below one page.
+//
+// The knobs have to make sense together: the two-level read-ahead must be
+// able to keep twice LEVEL2_READAHEAD slices loaded, EVICT_BELOW_MAX_LOADED_SIZE
+// can't be above MAX_LOADED_SIZE and MIN_REQUEST_SIZE can't be above
+// MAX_REQUEST_SIZE. That is only checked when mapping, where mmap_s3_opts()
+// fails with MMAP_S3_ERRNO and errno EINVAL, so knobs can be set in any order.
+//
+// Returns -1 if the knob (or the policy) is not recognized, 0 otherwise.
+int mmap_s3_options_set_heuristic(mmap_s3_options* opts, int knob, size_t value);
+
+// Makes a new, empty page cache (a memfd) and returns its file descriptor, or
+// -1 with errno set. Mappings made with the same page cache share their
+// pages: a page downloaded by one is there for all the others, also in other
+// processes. Close the descriptor when you no longer need it; the cache lives
+// on as long as some mapping or descriptor uses it.
+int mmap_s3_page_cache_create(void);
+
+// Makes the mapping use the page cache 'fd' (from mmap_s3_page_cache_create()
+// or mmap_s3_page_cache_receive()). The options keep their own descriptor so
+// 'fd' can be closed afterwards. A negative 'fd' unsets the page cache.
+//
+// Returns 0, or -1 with errno set if 'fd' is not a usable cache. Only
+// MMAP_S3_MODE_READONLY mappings without huge pages can use a page cache;
+// mmap_s3_opts() fails with EINVAL otherwise. Needs Linux 5.13+.
+int mmap_s3_options_set_page_cache(mmap_s3_options* opts, int fd);
+
+// Sends a page cache to another process over the unix socket 'socket'.
+// Returns 0, or -1 with errno set.
+int mmap_s3_page_cache_send(int socket, int fd);
+
+// Receives a page cache sent with mmap_s3_page_cache_send(). Returns its file
+// descriptor, or -1 with errno set.
+int mmap_s3_page_cache_receive(int socket);
+
+// Makes S3 mappings made from now on go through a cache on local disk, in
+// the directory 'dir' (made if it's not there). Downloaded data is kept there
+// between runs, keyed by bucket, key and ETag, and the least recently used
+// parts are deleted to keep it under about 'max_size' bytes. Several
+// processes can use the same directory at once. A NULL 'dir' turns the disk
+// cache off again; mappings that already exist keep what they have.
+//
+// If 'offline' is non-zero, S3 is never contacted. Only objects that have been
+// opened online before can be opened (mmap_s3() fails with
+// MMAP_S3_NOT_CACHED otherwise), and touching a part of them that is not in
+// the cache is an error handled by the mapping's error policy.
+//
+// Returns 0, or -1 with errno set if the directory cannot be used.
+int mmap_s3_disk_cache(const char* dir, size_t max_size, int offline);
+
+// Uploads pages modified through a MMAP_S3_MODE_WRITEBACK mapping back to
+// S3. Unchanged parts of the object are copied on the S3 side and are not
+// uploaded. Does nothing for read-only mappings.
+//
+// Returns one of the MMAP_S3_* codes, MMAP_S3_OBJECT_MODIFIED if the object
+// in S3 is no longer the one that was mapped. Pages that failed to upload
+// stay modified and will be tried again on the next call. munmap_s3() also
+// writes back, but has no way to report failures.
+int msync_s3(const void* ptr);
+
+// Tells mmapurl how 'len' bytes starting 'offset' bytes into a region mapped
+// with mmap_s3() are going to be accessed. 'advice' is one of
+// MMAP_S3_ADVICE_*. The range is widened to whole pages. This does not wait
+// for downloads; MMAP_S3_ADVICE_WILLNEED only starts them.
+//
+// Returns MMAP_S3_OK, MMAP_S3_NOT_MAPPED if the pointer is unrecognized or
+// MMAP_S3_ERRNO with errno set to EINVAL if the advice is not recognized.
+int madvise_s3(const void* ptr, size_t offset, size_t len, int advice);
+
+// Changes one read-ahead or eviction knob of a region mapped with mmap_s3(),
+// like mmap_s3_options_set_heuristic(). What is loaded stays loaded. A shared
+// mapping (see mmap_s3()) changes for everybody using it.
+//
+// Returns MMAP_S3_OK, MMAP_S3_NOT_MAPPED if the pointer is unrecognized or
+// MMAP_S3_ERRNO with errno set to EINVAL if the knob is not recognized or the
+// new value doesn't make sense with the others. Nothing changes if this fails.
+int mmap_s3_set_heuristic(const void* ptr, int knob, size_t value);
+
+// Starts downloading a range in the background. Same as madvise_s3() with
+// MMAP_S3_ADVICE_WILLNEED.
+int prefetch_s3(const void* ptr, size_t offset, size_t len);
+
+// Drops a range from memory right away; it's downloaded again when it's
+// touched. Pages that have been modified or pinned stay. Same as madvise_s3()
+// with MMAP_S3_ADVICE_DONTNEED.
+int evict_s3(const void* ptr, size_t offset, size_t len);
+
+// Keeps 'len' bytes starting 'offset' bytes into a region mapped with
+// mmap_s3() in memory until unpin_s3() is called for them, and starts
+// downloading whatever of it isn't there yet. The range is widened to whole
+// pages.
+//
+// Returns MMAP_S3_OK, MMAP_S3_NOT_MAPPED if the pointer is unrecognized or
+// MMAP_S3_ERRNO with errno set to ENOMEM if that would take the mapping over
+// its pin limit (see mmap_s3_options_set_pin_limit()). Nothing is pinned if
+// this fails.
+int pin_s3(const void* ptr, size_t offset, size_t len);
+
+// Lets a pinned range be evicted again. Returns MMAP_S3_OK or
+// MMAP_S3_NOT_MAPPED.
+int unpin_s3(const void* ptr, size_t offset, size_t len);
+
+// Sets how many bytes all mappings in the process together may keep in
+// memory. When they go over it, the pages used least recently go, whichever
+// mappings they belong to. Modified and pinned pages are never evicted and
+// still count. 0 means no limit, which is the default; each mapping is still
+// kept under about 128 megabytes of its own.
+void mmap_s3_set_memory_budget(size_t limit);
+
+// Returns how many bytes all mappings in the process together have in
+// memory.
+size_t mmap_s3_memory_used(void);
+
+// Fills 'size' with how many bytes of a region mapped with mmap_s3() are in
+// memory. Returns MMAP_S3_OK or MMAP_S3_NOT_MAPPED.
+int mmap_s3_resident_size(const void* ptr, size_t* size);
+
 // Unmaps a region previously mapped with mmap_s3().
 //
 // Returns -1 if the pointer is unrecognized and then does nothing.
-// Otherwise returns 0 and the memory is released.
+// Otherwise returns 0. The memory is released when the last of the
+// mmap_s3() calls that returned this pointer has been matched with a
+// munmap_s3() call.
+//
+// This returns promptly; it does not wait for downloads that are in progress
+// for the region. Those finish in the background and their data is thrown
+// away. For MMAP_S3_MODE_WRITEBACK mappings modified pages are written back
+// first, which takes as long as the upload takes.
 //
-// Note: this call may take some time to complete because it has to stop
-// threads that are potentially quite busy. Usually it's just hundreds of
-// milliseconds.
+// Like with munmap(), the region must not be touched during or after this
+// call. A thread that is still waiting for a page of the region when it is
+// unmapped gets SIGSEGV once the download it is waiting for is abandoned.
 int munmap_s3(const void* ptr);
 
 // Takes an error code and turns it into a string that can be displayed.
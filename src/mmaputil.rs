@@ -1,23 +1,66 @@
 use libc::{c_int, c_void, size_t};
+use std::cmp;
+use std::fs;
 use std::slice;
 
 lazy_static! {
     pub static ref PAGESIZE_U64: u64 = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
     pub static ref PAGESIZE_USIZE: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    // Default huge page size, what MAP_HUGETLB gets us. None if the kernel has no hugetlbfs.
+    pub static ref HUGE_PAGESIZE_USIZE: Option<usize> = default_huge_pagesize();
 }
 
-pub fn round_up_to_pagesize(nbytes: size_t) -> size_t {
-    if nbytes % *PAGESIZE_USIZE == 0 {
-        return nbytes;
+fn default_huge_pagesize() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    for line in meminfo.lines() {
+        if line.starts_with("Hugepagesize:") {
+            // Hugepagesize:       2048 kB
+            let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+            return Some(kb * 1024);
+        }
     }
-    nbytes + (*PAGESIZE_USIZE - (nbytes % *PAGESIZE_USIZE))
+    None
 }
 
-pub fn round_down_to_pagesize(nbytes: size_t) -> size_t {
-    if nbytes % *PAGESIZE_USIZE == 0 {
+// Rounds to whole pages of `page_size` bytes.
+pub fn round_up_to(nbytes: size_t, page_size: size_t) -> size_t {
+    if nbytes % page_size == 0 {
         return nbytes;
     }
-    nbytes - (nbytes % *PAGESIZE_USIZE)
+    nbytes + (page_size - (nbytes % page_size))
+}
+
+pub fn round_down_to(nbytes: size_t, page_size: size_t) -> size_t {
+    nbytes - (nbytes % page_size)
+}
+
+// How large the pieces of a streamed read-ahead are. The faulting page goes first, on its own, so
+// that the faulting thread can get going as soon as possible. After that pieces double in size up
+// to STREAM_CHUNK_MAX_SIZE bytes (or one page, for pages larger than that); copying in a large
+// read-ahead one page at a time costs more than waiting for it.
+const STREAM_CHUNK_MAX_SIZE: usize = 2 * 1024 * 1024;
+
+pub struct StreamChunks {
+    page_size: usize,
+    next_pages: usize,
+    max_pages: usize,
+}
+
+impl StreamChunks {
+    pub fn new(page_size: usize) -> Self {
+        StreamChunks {
+            page_size,
+            next_pages: 1,
+            max_pages: cmp::max(STREAM_CHUNK_MAX_SIZE / page_size, 1),
+        }
+    }
+
+    // Size in bytes of the next piece, at most `remaining` bytes.
+    pub fn next_size(&mut self, remaining: usize) -> usize {
+        let sz = cmp::min(self.next_pages * self.page_size, remaining);
+        self.next_pages = cmp::min(self.next_pages * 2, self.max_pages);
+        sz
+    }
 }
 
 // This is a small struct+trait combination to make it easy to quickly ask for a bit of mmapped
@@ -30,7 +73,13 @@ pub struct MMapPages {
 
 impl MMapPages {
     pub fn new(nbytes: u64) -> Self {
-        let nbytes = round_up_to_pagesize(nbytes as usize);
+        MMapPages::with_page_size(nbytes, *PAGESIZE_USIZE)
+    }
+
+    // Rounds up to whole pages of `page_size` bytes, the pages of the mapping the pages are for.
+    // The memory itself is made of normal pages either way; it's only copied from.
+    pub fn with_page_size(nbytes: u64, page_size: usize) -> Self {
+        let nbytes = round_up_to(round_up_to(nbytes as usize, page_size), *PAGESIZE_USIZE);
         let vehicle_page = unsafe {
             libc::mmap(
                 std::ptr::null::<*const c_void>() as *mut c_void,
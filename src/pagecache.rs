@@ -0,0 +1,301 @@
+/* This module implements a page cache that mappings (in this process or others) can share.
+ *
+ * Normally pages are private anonymous memory: every mapping downloads and keeps its own copy.
+ * A mapping made with MMapOptions::page_cache instead maps a memfd (shmem) and the pages live in
+ * that. The mapping is registered with userfaultfd for both missing and minor faults:
+ *
+ *   - A missing fault means the page is not in the cache. The handler is asked for it as usual and
+ *     what it hands back is written into the memfd, then mapped in with UFFDIO_CONTINUE.
+ *   - A minor fault means the page is in the cache already, put there by another mapping or by
+ *     this one before the page was evicted. It's mapped in with UFFDIO_CONTINUE right away; the
+ *     handler never hears of it.
+ *
+ * Evicting pages from a mapping only unmaps them; they stay in the cache. The cache only gives
+ * memory back when it's trimmed with PageCache::evict() or when the last file descriptor for the
+ * memfd goes away.
+ *
+ * A forked child inherits the memfd. Unrelated processes can be handed it over a unix socket with
+ * send() and receive() (SCM_RIGHTS), so one process can warm the cache for all others.
+ *
+ * The cache holds the bytes of one object; it's up to the user not to map anything else with it.
+ */
+
+use libc::{c_int, c_void};
+use std::mem;
+use std::ptr;
+
+static NR_MEMFD_CREATE: libc::c_long = 319;
+static MFD_CLOEXEC: libc::c_uint = 0x0001;
+
+#[derive(Debug)]
+pub struct PageCache {
+    fd: c_int,
+}
+
+impl PageCache {
+    // Makes a new, empty cache.
+    pub fn new() -> Result<Self, c_int> {
+        let name = b"mmapurl\0";
+        let fd = unsafe { libc::syscall(NR_MEMFD_CREATE, name.as_ptr(), MFD_CLOEXEC) as c_int };
+        if fd == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            return Err(err);
+        }
+        Ok(PageCache { fd })
+    }
+
+    // Takes over the memfd of a cache made elsewhere, e.g. in a parent process that passed it on
+    // through exec(). The cache closes it when it's dropped.
+    pub fn from_fd(fd: c_int) -> Result<Self, c_int> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            return Err(err);
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(libc::EINVAL);
+        }
+        Ok(PageCache { fd })
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+
+    // How many bytes the cache covers. This grows as larger mappings are made with it.
+    pub fn len(&self) -> usize {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(self.fd, &mut stat) } == -1 {
+            return 0;
+        }
+        stat.st_size as usize
+    }
+
+    // Drops [offset, offset+len) from the cache, giving the memory back. Every mapping using the
+    // cache, in every process, loses those pages and gets them from its handler again.
+    pub fn evict(&self, offset: usize, len: usize) {
+        unsafe {
+            libc::fallocate(
+                self.fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            );
+        }
+    }
+
+    // Sends the cache over a unix socket. The receiving end gets it with PageCache::receive().
+    pub fn send(&self, socket: c_int) -> Result<(), c_int> {
+        send_fd(socket, self.fd)
+    }
+
+    // Receives a cache someone sent with PageCache::send().
+    pub fn receive(socket: c_int) -> Result<Self, c_int> {
+        let fd = receive_fd(socket)?;
+        match PageCache::from_fd(fd) {
+            Ok(cache) => Ok(cache),
+            Err(err) => {
+                unsafe {
+                    libc::close(fd);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Makes the cache at least `len` bytes long so it can back a mapping that long.
+    pub(crate) fn ensure_len(&self, len: usize) -> Result<(), c_int> {
+        if self.len() >= len {
+            return Ok(());
+        }
+        if unsafe { libc::ftruncate(self.fd, len as libc::off_t) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Puts bytes in the cache at `offset`. Anything past the end of the cache is left out. Fails
+    // with the errno of pwrite(), for example ENOSPC when there is no memory left for the cache.
+    pub(crate) fn write(&self, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        let len = self.len() as u64;
+        if offset >= len {
+            return Ok(());
+        }
+        let data = &data[..std::cmp::min(data.len() as u64, len - offset) as usize];
+        let mut written = 0;
+        while written < data.len() {
+            let ret = unsafe {
+                libc::pwrite(
+                    self.fd,
+                    data[written..].as_ptr() as *const c_void,
+                    data.len() - written,
+                    (offset + written as u64) as libc::off_t,
+                )
+            };
+            if ret == -1 {
+                let err: c_int = unsafe { *libc::__errno_location() };
+                if err == libc::EINTR {
+                    continue;
+                }
+                return Err(err);
+            }
+            written += ret as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PageCache {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Sends a file descriptor over a unix socket, along with one byte of data (some data has to go
+// with it).
+pub fn send_fd(socket: c_int, fd: c_int) -> Result<(), c_int> {
+    let mut byte: u8 = 0;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) } as usize;
+    let mut control: Vec<u8> = vec![0; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, fd);
+    }
+    loop {
+        if unsafe { libc::sendmsg(socket, &msg, 0) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EINTR {
+                continue;
+            }
+            return Err(err);
+        }
+        return Ok(());
+    }
+}
+
+// Receives a file descriptor sent with send_fd(). It's close-on-exec.
+pub fn receive_fd(socket: c_int) -> Result<c_int, c_int> {
+    let mut byte: u8 = 0;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) } as usize;
+    let mut control: Vec<u8> = vec![0; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = space as _;
+    loop {
+        let ret = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EINTR {
+                continue;
+            }
+            return Err(err);
+        }
+        // The other end went away without sending anything.
+        if ret == 0 {
+            return Err(libc::ECONNRESET);
+        }
+        break;
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(libc::EBADMSG);
+        }
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userfaultfd::{mmap_with_userfault_options, MMap, MMapMode, MMapOptions};
+    use crate::userfaultfd_dummy::MMapDummy;
+    use std::sync::Arc;
+
+    fn with_cache(cache: &Arc<PageCache>) -> MMapOptions {
+        let mut options = MMapOptions::new();
+        options.page_cache = Some(cache.clone());
+        options
+    }
+
+    fn expected(offset: usize) -> u8 {
+        ((offset * 13) & 0xFF) as u8
+    }
+
+    #[test]
+    fn shared_page_cache() {
+        let cache = Arc::new(PageCache::new().unwrap());
+        let first: MMap<MMapDummy> =
+            mmap_with_userfault_options(4096 * 64, with_cache(&cache)).unwrap();
+        assert_eq!(cache.len(), 4096 * 64);
+        assert_eq!(first.as_slice::<u8>()[4096 * 5 + 1], expected(4096 * 5 + 1));
+
+        // Scribble over a page in the cache. From now on it comes from the cache, not the handler,
+        // also after it has been evicted from the mapping.
+        cache.write(4096 * 5, &[0xAA; 4096]).unwrap();
+        first.evict(4096 * 5..4096 * 6);
+        assert_eq!(first.as_slice::<u8>()[4096 * 5 + 1], 0xAA);
+
+        let second: MMap<MMapDummy> =
+            mmap_with_userfault_options(4096 * 64, with_cache(&cache)).unwrap();
+        assert_eq!(second.as_slice::<u8>()[4096 * 5 + 1], 0xAA);
+        assert_eq!(
+            second.as_slice::<u8>()[4096 * 9 + 1],
+            expected(4096 * 9 + 1)
+        );
+
+        // Hand the cache over a socket, like to another process.
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+        cache.send(fds[0]).unwrap();
+        let received = Arc::new(PageCache::receive(fds[1]).unwrap());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        let third: MMap<MMapDummy> =
+            mmap_with_userfault_options(4096 * 64, with_cache(&received)).unwrap();
+        assert_eq!(third.as_slice::<u8>()[4096 * 5 + 1], 0xAA);
+
+        // Trimming the cache takes the page away from every mapping. It comes back from the
+        // handler.
+        cache.evict(4096 * 5, 4096);
+        assert_eq!(third.as_slice::<u8>()[4096 * 5 + 1], expected(4096 * 5 + 1));
+        assert_eq!(first.as_slice::<u8>()[4096 * 5 + 2], expected(4096 * 5 + 2));
+
+        // The cache is shared, so no writing.
+        let mut options = with_cache(&cache);
+        options.mode = MMapMode::Private;
+        match mmap_with_userfault_options::<MMapDummy>(4096, options) {
+            Err(Ok(err)) => assert_eq!(err, libc::EINVAL),
+            _ => panic!("writable mapping with a page cache"),
+        }
+    }
+}
@@ -0,0 +1,689 @@
+/* This module implements the read-ahead and eviction policies PageHeuristics can be built from.
+ *
+ * PageHeuristics (see heuristics.rs) keeps track of what is loaded, pinned and advised and asks two
+ * policies for the actual decisions:
+ *
+ *   - a ReadaheadPolicy decides how much to read when a page faults, and
+ *   - an EvictionPolicy keeps the loaded pages in some order and picks which one goes next when
+ *     the mapping has too many loaded.
+ *
+ * Which ones a mapping uses is part of its HeuristicsConfig, as factories that make a fresh policy
+ * for each mapping. Anybody can implement the traits and wrap their policy in a factory with
+ * ReadaheadPolicyFactory::new() or EvictionPolicyFactory::new().
+ *
+ * Read-ahead policies that come with the library:
+ *
+ *   - two_level: two levels of slices that read ahead when a slice is about to fill up. This is
+ *     the default; see heuristics.rs.
+ *   - ondemand: a window that starts at the faulting page and doubles every time the reader gets
+ *     to its end, like Linux does for files. Each faulting thread has its own window.
+ *   - none: only read what was asked for.
+ *
+ * Eviction policies that come with the library:
+ *
+ *   - fifo: pages go in the order they were loaded. This is the default.
+ *   - lru: least recently used pages go first.
+ *   - clock: pages are given a second chance if they have been used since the hand last passed.
+ *   - arc: adaptive replacement cache. Pages loaded once and pages loaded again after being evicted
+ *     are kept in separate lists, and the split between them adapts to which of them keeps coming
+ *     back.
+ *
+ * A policy only finds out a page was used when it's told with EvictionPolicy::access(), which
+ * happens for pages the mapping samples and finds in use (see sample_accesses() in
+ * userfaultfd.rs), and only if the mapping has sampling turned on. Other than that, use is when the
+ * page was loaded.
+ */
+
+use crate::heuristics::{HeuristicsConfig, TwoLevelReadahead};
+use crate::userfaultfd::Advice;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+// Decides how much to read for a fault. Pages are numbered from the start of the mapping.
+pub trait ReadaheadPolicy: Send + Sync {
+    // Called before the policy is used, and again whenever the mapping gets a new config.
+    // `page_size` is the size of the pages of the mapping in bytes.
+    fn configure(&mut self, page_size: usize, config: &HeuristicsConfig);
+
+    // Returns how many bytes to read from `offset` on, given that at least `len` bytes are needed.
+    // Can be more than there is; the handler cuts it to size. `advice` is Advice::Sequential if the
+    // range has been advised so, Advice::Normal otherwise. (Advice::Random never gets here.)
+    //
+    // `stream` is the thread that faulted. Several threads can read one mapping at once; a policy
+    // that follows where the reader is going should do so for each stream on its own.
+    fn readahead(&mut self, stream: u32, offset: usize, len: usize, advice: Advice) -> usize;
+
+    // PageHeuristics no longer follows a stream. Whatever was kept for it can go.
+    fn stream_ended(&mut self, _stream: u32) {}
+
+    // A page has been loaded, or is about to be.
+    fn loaded(&mut self, _page: usize) {}
+
+    // A page has been evicted or forgotten.
+    fn evicted(&mut self, _page: usize) {}
+}
+
+// Keeps track of loaded pages and picks which ones to evict. Pinned pages are tracked like any
+// other; when one is picked, PageHeuristics keeps it loaded and inserts it again once it's
+// unpinned.
+pub trait EvictionPolicy: Send + Sync {
+    // How many pages the mapping keeps loaded. Called before the policy is used and whenever it
+    // changes.
+    fn set_capacity(&mut self, _pages: usize) {}
+
+    // A page has been loaded. This is also called for pages the policy is already tracking.
+    fn insert(&mut self, page: usize);
+
+    // A loaded page has been used.
+    fn access(&mut self, page: usize);
+
+    // Stop tracking a page; it has been dropped without going through pick_victim().
+    fn remove(&mut self, page: usize);
+
+    fn contains(&self, page: usize) -> bool;
+
+    // How many pages are tracked.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Picks the page to evict next and stops tracking it. None if nothing is tracked.
+    fn pick_victim(&mut self) -> Option<usize>;
+}
+
+type MakeReadahead = dyn Fn() -> Box<dyn ReadaheadPolicy> + Send + Sync;
+type MakeEviction = dyn Fn() -> Box<dyn EvictionPolicy> + Send + Sync;
+
+lazy_static! {
+    // The built-in factories are made once so that configs using them compare as the same policy
+    // (see same()).
+    static ref TWO_LEVEL: ReadaheadPolicyFactory =
+        ReadaheadPolicyFactory::new(|| Box::new(TwoLevelReadahead::new()));
+    static ref ONDEMAND: ReadaheadPolicyFactory =
+        ReadaheadPolicyFactory::new(|| Box::new(OnDemandReadahead::new()));
+    static ref NO_READAHEAD: ReadaheadPolicyFactory =
+        ReadaheadPolicyFactory::new(|| Box::new(NoReadahead));
+    static ref FIFO: EvictionPolicyFactory =
+        EvictionPolicyFactory::new(|| Box::new(FifoEviction::new()));
+    static ref LRU: EvictionPolicyFactory =
+        EvictionPolicyFactory::new(|| Box::new(LruEviction::new()));
+    static ref CLOCK: EvictionPolicyFactory =
+        EvictionPolicyFactory::new(|| Box::new(ClockEviction::new()));
+    static ref ARC: EvictionPolicyFactory =
+        EvictionPolicyFactory::new(|| Box::new(ArcEviction::new()));
+}
+
+// Makes the read-ahead policy of each mapping.
+#[derive(Clone)]
+pub struct ReadaheadPolicyFactory(Arc<MakeReadahead>);
+
+impl ReadaheadPolicyFactory {
+    pub fn new<F>(make: F) -> Self
+    where
+        F: Fn() -> Box<dyn ReadaheadPolicy> + Send + Sync + 'static,
+    {
+        ReadaheadPolicyFactory(Arc::new(make))
+    }
+
+    pub fn two_level() -> Self {
+        TWO_LEVEL.clone()
+    }
+
+    pub fn ondemand() -> Self {
+        ONDEMAND.clone()
+    }
+
+    pub fn none() -> Self {
+        NO_READAHEAD.clone()
+    }
+
+    pub(crate) fn make(&self) -> Box<dyn ReadaheadPolicy> {
+        (self.0)()
+    }
+
+    // Whether both make the same policy, i.e. one is a clone of the other.
+    pub(crate) fn same(&self, other: &Self) -> bool {
+        &*self.0 as *const MakeReadahead as *const u8
+            == &*other.0 as *const MakeReadahead as *const u8
+    }
+}
+
+impl fmt::Debug for ReadaheadPolicyFactory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadaheadPolicyFactory")
+    }
+}
+
+// Makes the eviction policy of each mapping.
+#[derive(Clone)]
+pub struct EvictionPolicyFactory(Arc<MakeEviction>);
+
+impl EvictionPolicyFactory {
+    pub fn new<F>(make: F) -> Self
+    where
+        F: Fn() -> Box<dyn EvictionPolicy> + Send + Sync + 'static,
+    {
+        EvictionPolicyFactory(Arc::new(make))
+    }
+
+    pub fn fifo() -> Self {
+        FIFO.clone()
+    }
+
+    pub fn lru() -> Self {
+        LRU.clone()
+    }
+
+    pub fn clock() -> Self {
+        CLOCK.clone()
+    }
+
+    pub fn arc() -> Self {
+        ARC.clone()
+    }
+
+    pub(crate) fn make(&self) -> Box<dyn EvictionPolicy> {
+        (self.0)()
+    }
+
+    pub(crate) fn same(&self, other: &Self) -> bool {
+        &*self.0 as *const MakeEviction as *const u8
+            == &*other.0 as *const MakeEviction as *const u8
+    }
+}
+
+impl fmt::Debug for EvictionPolicyFactory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EvictionPolicyFactory")
+    }
+}
+
+struct NoReadahead;
+
+impl ReadaheadPolicy for NoReadahead {
+    fn configure(&mut self, _page_size: usize, _config: &HeuristicsConfig) {}
+
+    fn readahead(&mut self, _stream: u32, _offset: usize, len: usize, _advice: Advice) -> usize {
+        len
+    }
+}
+
+// Linux style on-demand read-ahead. The window is where the last read-ahead went, in pages. A
+// fault right at its end means the reader is going through it sequentially, so the next window is
+// twice as big. A fault right after the last page read starts a new window. Anything else is a
+// random read and reads only what was asked for. Each stream has a window of its own.
+struct OnDemandReadahead {
+    page_size: usize,
+    // Smallest and largest window, in pages.
+    min_window: usize,
+    max_window: usize,
+    streams: BTreeMap<u32, OnDemandStream>,
+}
+
+#[derive(Default)]
+struct OnDemandStream {
+    // (start, size)
+    window: Option<(usize, usize)>,
+    last_page: Option<usize>,
+}
+
+impl OnDemandReadahead {
+    fn new() -> Self {
+        OnDemandReadahead {
+            page_size: 0,
+            min_window: 1,
+            max_window: 1,
+            streams: BTreeMap::new(),
+        }
+    }
+}
+
+impl ReadaheadPolicy for OnDemandReadahead {
+    // Windows start at a small slice and grow up to what two-level read-ahead reads at most.
+    fn configure(&mut self, page_size: usize, config: &HeuristicsConfig) {
+        self.page_size = page_size;
+        self.min_window = cmp::max(config.level1_slice_size / page_size, 1);
+        let level2_readahead = config
+            .level2_readahead
+            .saturating_mul(config.level2_slice_size);
+        self.max_window = cmp::max(level2_readahead / page_size, self.min_window);
+    }
+
+    fn readahead(&mut self, stream: u32, offset: usize, len: usize, advice: Advice) -> usize {
+        let page = offset / self.page_size;
+        let wanted = (offset % self.page_size + len + self.page_size - 1) / self.page_size;
+        let stream = self.streams.entry(stream).or_default();
+        let window = match (stream.window, stream.last_page) {
+            _ if advice == Advice::Sequential => Some(self.max_window),
+            (Some((start, size)), _) if page == start + size => {
+                Some(cmp::min(size * 2, self.max_window))
+            }
+            (_, Some(last_page)) if page == last_page + 1 => Some(cmp::min(
+                cmp::max(wanted * 4, self.min_window),
+                self.max_window,
+            )),
+            _ => None,
+        };
+        stream.last_page = Some(page + wanted - 1);
+        match window {
+            Some(window) => {
+                let window = cmp::max(window, wanted);
+                stream.window = Some((page, window));
+                cmp::max(window * self.page_size, len)
+            }
+            None => {
+                stream.window = None;
+                len
+            }
+        }
+    }
+
+    fn stream_ended(&mut self, stream: u32) {
+        self.streams.remove(&stream);
+    }
+}
+
+// Pages in some order, oldest first. Moving a page to the back is O(log n).
+struct Recency {
+    // stamp -> page
+    order: BTreeMap<u64, usize>,
+    // page -> stamp
+    stamps: BTreeMap<usize, u64>,
+    next_stamp: u64,
+}
+
+impl Recency {
+    fn new() -> Self {
+        Recency {
+            order: BTreeMap::new(),
+            stamps: BTreeMap::new(),
+            next_stamp: 0,
+        }
+    }
+
+    // Puts a page at the back, whether it was in already or not.
+    fn touch(&mut self, page: usize) {
+        self.remove(page);
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.order.insert(stamp, page);
+        self.stamps.insert(page, stamp);
+    }
+
+    fn remove(&mut self, page: usize) -> bool {
+        match self.stamps.remove(&page) {
+            Some(stamp) => {
+                self.order.remove(&stamp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<usize> {
+        let stamp = *self.order.keys().next()?;
+        let page = self.order.remove(&stamp)?;
+        self.stamps.remove(&page);
+        Some(page)
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        self.stamps.contains_key(&page)
+    }
+
+    fn len(&self) -> usize {
+        self.stamps.len()
+    }
+}
+
+// Pages go in the order they were first loaded. A page that is removed and loaded again goes to
+// the back.
+struct FifoEviction {
+    pages: Recency,
+}
+
+impl FifoEviction {
+    fn new() -> Self {
+        FifoEviction {
+            pages: Recency::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for FifoEviction {
+    fn insert(&mut self, page: usize) {
+        if !self.pages.contains(page) {
+            self.pages.touch(page);
+        }
+    }
+
+    fn access(&mut self, _page: usize) {}
+
+    fn remove(&mut self, page: usize) {
+        self.pages.remove(page);
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        self.pages.contains(page)
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn pick_victim(&mut self) -> Option<usize> {
+        self.pages.pop_oldest()
+    }
+}
+
+struct LruEviction {
+    pages: Recency,
+}
+
+impl LruEviction {
+    fn new() -> Self {
+        LruEviction {
+            pages: Recency::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for LruEviction {
+    fn insert(&mut self, page: usize) {
+        self.pages.touch(page);
+    }
+
+    fn access(&mut self, page: usize) {
+        if self.pages.contains(page) {
+            self.pages.touch(page);
+        }
+    }
+
+    fn remove(&mut self, page: usize) {
+        self.pages.remove(page);
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        self.pages.contains(page)
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn pick_victim(&mut self) -> Option<usize> {
+        self.pages.pop_oldest()
+    }
+}
+
+// The hand is the front of the ring. A page the hand passes over goes to the back.
+struct ClockEviction {
+    ring: Recency,
+    // Pages used since the hand last passed them.
+    referenced: BTreeSet<usize>,
+}
+
+impl ClockEviction {
+    fn new() -> Self {
+        ClockEviction {
+            ring: Recency::new(),
+            referenced: BTreeSet::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for ClockEviction {
+    fn insert(&mut self, page: usize) {
+        if !self.ring.contains(page) {
+            self.ring.touch(page);
+        }
+    }
+
+    fn access(&mut self, page: usize) {
+        if self.ring.contains(page) {
+            self.referenced.insert(page);
+        }
+    }
+
+    fn remove(&mut self, page: usize) {
+        self.ring.remove(page);
+        self.referenced.remove(&page);
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        self.ring.contains(page)
+    }
+
+    fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    // Every page gets cleared on the first pass so this ends within two rounds.
+    fn pick_victim(&mut self) -> Option<usize> {
+        while let Some(page) = self.ring.pop_oldest() {
+            if !self.referenced.remove(&page) {
+                return Some(page);
+            }
+            self.ring.touch(page);
+        }
+        None
+    }
+}
+
+// Adaptive replacement cache (Megiddo and Modha). t1 has pages used once since they were loaded,
+// t2 pages used more than that or loaded again soon after being evicted. b1 and b2 remember pages
+// recently evicted from t1 and t2. `target` is how big t1 should be; a page coming back from b1
+// grows it and one coming back from b2 shrinks it.
+struct ArcEviction {
+    capacity: usize,
+    target: usize,
+    t1: Recency,
+    t2: Recency,
+    b1: Recency,
+    b2: Recency,
+}
+
+impl ArcEviction {
+    fn new() -> Self {
+        ArcEviction {
+            capacity: 1,
+            target: 0,
+            t1: Recency::new(),
+            t2: Recency::new(),
+            b1: Recency::new(),
+            b2: Recency::new(),
+        }
+    }
+
+    // Ghost lists hold at most `capacity` pages together with what they stand for.
+    fn trim_ghosts(&mut self) {
+        while self.t1.len() + self.b1.len() > self.capacity && self.b1.pop_oldest().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+            && self.b2.pop_oldest().is_some()
+        {}
+    }
+}
+
+impl EvictionPolicy for ArcEviction {
+    fn set_capacity(&mut self, pages: usize) {
+        self.capacity = cmp::max(pages, 1);
+        self.target = cmp::min(self.target, self.capacity);
+        self.trim_ghosts();
+    }
+
+    fn insert(&mut self, page: usize) {
+        if self.t1.contains(page) || self.t2.contains(page) {
+            return;
+        }
+        if self.b1.remove(page) {
+            let delta = cmp::max(self.b2.len() / cmp::max(self.b1.len(), 1), 1);
+            self.target = cmp::min(self.target + delta, self.capacity);
+            self.t2.touch(page);
+        } else if self.b2.remove(page) {
+            let delta = cmp::max(self.b1.len() / cmp::max(self.b2.len(), 1), 1);
+            self.target = self.target.saturating_sub(delta);
+            self.t2.touch(page);
+        } else {
+            self.t1.touch(page);
+        }
+        self.trim_ghosts();
+    }
+
+    fn access(&mut self, page: usize) {
+        if self.t1.remove(page) || self.t2.contains(page) {
+            self.t2.touch(page);
+        }
+    }
+
+    fn remove(&mut self, page: usize) {
+        if !self.t1.remove(page) {
+            self.t2.remove(page);
+        }
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        self.t1.contains(page) || self.t2.contains(page)
+    }
+
+    fn len(&self) -> usize {
+        self.t1.len() + self.t2.len()
+    }
+
+    fn pick_victim(&mut self) -> Option<usize> {
+        let from_t1 = self.t1.len() > 0 && (self.t1.len() > self.target || self.t2.len() == 0);
+        let page = if from_t1 {
+            let page = self.t1.pop_oldest()?;
+            self.b1.touch(page);
+            page
+        } else {
+            let page = self.t2.pop_oldest()?;
+            self.b2.touch(page);
+            page
+        };
+        self.trim_ghosts();
+        Some(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn victims(policy: &mut dyn EvictionPolicy, count: usize) -> Vec<usize> {
+        (0..count).filter_map(|_| policy.pick_victim()).collect()
+    }
+
+    #[test]
+    fn eviction_policies() {
+        // Load 0..4, use 0 and 1, evict two.
+        let run = |policy: &mut dyn EvictionPolicy| {
+            policy.set_capacity(4);
+            for page in 0..4 {
+                policy.insert(page);
+            }
+            policy.access(0);
+            policy.access(1);
+            victims(policy, 2)
+        };
+        assert_eq!(run(&mut *EvictionPolicyFactory::fifo().make()), vec![0, 1]);
+        assert_eq!(run(&mut *EvictionPolicyFactory::lru().make()), vec![2, 3]);
+        assert_eq!(run(&mut *EvictionPolicyFactory::clock().make()), vec![2, 3]);
+        assert_eq!(run(&mut *EvictionPolicyFactory::arc().make()), vec![2, 3]);
+
+        // Removed pages are not picked and not counted.
+        for factory in &[
+            EvictionPolicyFactory::fifo(),
+            EvictionPolicyFactory::lru(),
+            EvictionPolicyFactory::clock(),
+            EvictionPolicyFactory::arc(),
+        ] {
+            let mut policy = factory.make();
+            policy.set_capacity(10);
+            for page in 0..5 {
+                policy.insert(page);
+            }
+            policy.insert(3);
+            policy.remove(0);
+            assert_eq!(policy.len(), 4);
+            assert!(!policy.contains(0));
+            let mut picked = victims(&mut *policy, 10);
+            picked.sort();
+            assert_eq!(picked, vec![1, 2, 3, 4]);
+            assert_eq!(policy.len(), 0);
+        }
+
+        // A page that is removed and loaded again is as new.
+        for factory in &[
+            EvictionPolicyFactory::fifo(),
+            EvictionPolicyFactory::clock(),
+        ] {
+            let mut policy = factory.make();
+            policy.set_capacity(10);
+            for page in 0..3 {
+                policy.insert(page);
+            }
+            policy.remove(0);
+            policy.insert(0);
+            assert_eq!(victims(&mut *policy, 10), vec![1, 2, 0]);
+        }
+    }
+
+    #[test]
+    fn arc_keeps_pages_that_come_back() {
+        let mut policy = ArcEviction::new();
+        policy.set_capacity(4);
+        for page in 0..4 {
+            policy.insert(page);
+        }
+        // 0 and 1 go to b1, then come back and land in t2.
+        assert_eq!(victims(&mut policy, 2), vec![0, 1]);
+        policy.insert(0);
+        policy.insert(1);
+        assert!(policy.target > 0);
+        // A scan of new pages pushes out t1 and leaves 0 and 1 alone.
+        for page in 10..20 {
+            policy.insert(page);
+            while policy.len() > 4 {
+                let victim = policy.pick_victim().unwrap();
+                assert!(victim != 0 && victim != 1);
+            }
+        }
+        assert!(policy.contains(0) && policy.contains(1));
+    }
+
+    #[test]
+    fn ondemand_window_grows() {
+        let page = 4096;
+        let config = HeuristicsConfig::new();
+        let mut policy = OnDemandReadahead::new();
+        policy.configure(page, &config);
+
+        // Random reads read what was asked.
+        assert_eq!(policy.readahead(1, page * 100, page, Advice::Normal), page);
+        assert_eq!(policy.readahead(1, page * 50, page, Advice::Normal), page);
+        // The next page starts a window...
+        let first = policy.readahead(1, page * 51, page, Advice::Normal);
+        assert_eq!(first, policy.min_window * page);
+        // ...that doubles when the reader gets to its end...
+        let second = policy.readahead(1, page * 51 + first, page, Advice::Normal);
+        assert_eq!(second, first * 2);
+        // ...up to the largest window.
+        let mut offset = page * 51 + first + second;
+        let mut size = second;
+        for _ in 0..20 {
+            size = policy.readahead(1, offset, page, Advice::Normal);
+            offset += size;
+        }
+        assert_eq!(size, policy.max_window * page);
+
+        // Sequential advice goes straight to the largest window.
+        assert_eq!(
+            policy.readahead(1, page * 3, page, Advice::Sequential),
+            policy.max_window * page
+        );
+    }
+}
@@ -0,0 +1,294 @@
+/* This module implements the process-wide userfaultfd reactor.
+ *
+ * Every mapping still has its own userfaultfd but they are all watched by a single thread through
+ * one epoll set. Page faults read from them are handed to one shared, bounded pool of worker
+ * threads. Other events (fork, mremap, munmap, madvise) are handed to their target right away on
+ * the reactor thread; the process that caused them is blocked until we have read them.
+ *
+ * Mappings are removed by the reactor thread itself, between two rounds of epoll_wait(). That way
+ * the reactor never reads from a userfaultfd that has already been closed (and whose number may
+ * have been reused). An eventfd sits in the epoll set next to the userfaultfds so that a removal
+ * wakes the reactor up immediately.
+ *
+ * Removing a target does not wait for its jobs. Jobs that are queued or running keep the target
+ * (and with it the userfaultfd) alive until they are done; it's up to the target to notice it has
+ * been cancelled and not touch memory that is no longer there.
+ *
+ * Threads do not survive fork(). A forked child that maps something gets a reactor of its own.
+ */
+
+use crate::userfaultfd::{uffd_msg, UFFD_EVENT_PAGEFAULT, USERFAULT_MSG_SZ};
+use libc::{c_int, c_void};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+
+// All mappings share these. Most of the time workers are waiting on the network so this is a fair
+// bit more than the number of cores.
+const MAX_CONCURRENT_WORKERS: usize = 64;
+
+// How many epoll events and userfaultfd messages we take in one go.
+const MAX_EVENTS: usize = 64;
+const MAX_MESSAGES: usize = 64;
+
+// epoll data for the eventfd. Userfaultfds use their file descriptor number.
+const WAKE_TOKEN: u64 = u64::max_value();
+
+lazy_static! {
+    static ref REACTOR: Mutex<Option<Arc<Reactor>>> = Mutex::new(None);
+}
+
+// Something that owns a userfaultfd.
+pub trait FaultTarget: Send + Sync {
+    fn ufd(&self) -> c_int;
+    // Called on a worker thread for every page fault read from the userfaultfd.
+    fn handle_pagefault(&self, msg: uffd_msg);
+    // Called on the reactor thread for every other event. This must not block; other processes
+    // and threads may be waiting for the reactor to read their events.
+    fn handle_event(&self, msg: uffd_msg);
+}
+
+struct Reactor {
+    // Process the reactor thread runs in.
+    pid: libc::pid_t,
+    epfd: c_int,
+    // eventfd used to wake up the reactor thread.
+    wakefd: c_int,
+    // Keyed by userfaultfd. A target owns its userfaultfd so the number can't be reused while it's in
+    // here.
+    targets: RwLock<BTreeMap<c_int, Arc<dyn FaultTarget>>>,
+    // Userfaultfds of targets waiting to be removed by the reactor thread.
+    removals: Mutex<Vec<c_int>>,
+    removals_done: Condvar,
+    pool: ThreadPool,
+}
+
+// Starts watching a target's userfaultfd. Returns errno if that can't be done. This may be called
+// from FaultTarget::handle_event().
+pub fn register(target: Arc<dyn FaultTarget>) -> Result<(), c_int> {
+    reactor().register(target)
+}
+
+// Stops watching a target. When this returns the reactor no longer reads from the target's
+// userfaultfd and won't start new jobs for it. Jobs that were already handed out may still be
+// queued or running.
+pub fn unregister(ufd: c_int) {
+    reactor().unregister(ufd)
+}
+
+// Runs a job on the shared worker pool.
+pub fn spawn<F: FnOnce() + Send + 'static>(job: F) {
+    reactor().pool.spawn(job)
+}
+
+fn reactor() -> Arc<Reactor> {
+    let mut reactor = REACTOR.lock().unwrap();
+    let pid = unsafe { libc::getpid() };
+    match *reactor {
+        Some(ref reactor) if reactor.pid == pid => reactor.clone(),
+        _ => {
+            // Either there is no reactor yet or we are a forked child and the reactor thread stayed
+            // behind in the parent. The old one can't be cleaned up; whatever it was holding is in
+            // an unknown state.
+            let new_reactor = Reactor::start();
+            if let Some(old_reactor) = reactor.replace(new_reactor.clone()) {
+                mem::forget(old_reactor);
+            }
+            new_reactor
+        }
+    }
+}
+
+impl Reactor {
+    fn start() -> Arc<Self> {
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epfd == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            panic!("Cannot create epoll instance for userfaultfd reactor. {}", err);
+        }
+        let wakefd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wakefd == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            panic!("Cannot create eventfd for userfaultfd reactor. {}", err);
+        }
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: WAKE_TOKEN,
+        };
+        if unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, wakefd, &mut event) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            panic!("Cannot add eventfd to userfaultfd reactor. {}", err);
+        }
+
+        let reactor = Arc::new(Reactor {
+            pid: unsafe { libc::getpid() },
+            epfd,
+            wakefd,
+            targets: RwLock::new(BTreeMap::new()),
+            removals: Mutex::new(Vec::new()),
+            removals_done: Condvar::new(),
+            pool: ThreadPoolBuilder::new()
+                .num_threads(MAX_CONCURRENT_WORKERS)
+                .build()
+                .unwrap(),
+        });
+        let reactor_thread = reactor.clone();
+        thread::spawn(move || reactor_thread.run());
+        reactor
+    }
+
+    fn register(&self, target: Arc<dyn FaultTarget>) -> Result<(), c_int> {
+        let ufd = target.ufd();
+        {
+            let mut targets = self.targets.write().unwrap();
+            targets.insert(ufd, target);
+        }
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: ufd as u64,
+        };
+        if unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, ufd, &mut event) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            let mut targets = self.targets.write().unwrap();
+            targets.remove(&ufd);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn unregister(&self, ufd: c_int) {
+        let mut removals = self.removals.lock().unwrap();
+        removals.push(ufd);
+        self.wake();
+        while removals.contains(&ufd) {
+            removals = self.removals_done.wait(removals).unwrap();
+        }
+    }
+
+    fn wake(&self) {
+        let one: u64 = 1;
+        let ret = unsafe { libc::write(self.wakefd, &one as *const u64 as *const c_void, 8) };
+        // EAGAIN means the counter is about to overflow, which means there's a wakeup pending
+        // anyway.
+        if ret == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err != libc::EAGAIN {
+                panic!("Unexpected error from write() to reactor eventfd. {}", err);
+            }
+        }
+    }
+
+    fn run(&self) {
+        let mut events: Vec<libc::epoll_event> =
+            vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+
+        loop {
+            let nevents = unsafe {
+                libc::epoll_wait(
+                    self.epfd,
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as c_int,
+                    -1,
+                )
+            };
+            if nevents == -1 {
+                let err: c_int = unsafe { *libc::__errno_location() };
+                if err == libc::EINTR || err == libc::EAGAIN {
+                    continue;
+                }
+                // We are in a bad state and no easy way to recover so just panic.
+                panic!(
+                    "Unexpectedly received unrecoverable error from epoll_wait() syscall: {}",
+                    err
+                );
+            }
+
+            for event in events[..nevents as usize].iter() {
+                let token = event.u64;
+                if token == WAKE_TOKEN {
+                    let mut counter: u64 = 0;
+                    unsafe {
+                        libc::read(self.wakefd, &mut counter as *mut u64 as *mut c_void, 8);
+                    }
+                    continue;
+                }
+                self.read_messages(token as c_int);
+            }
+
+            self.process_removals();
+        }
+    }
+
+    // Reads everything there is to read from a userfaultfd and dispatches it.
+    fn read_messages(&self, ufd: c_int) {
+        let mut msgs = [uffd_msg::new(); MAX_MESSAGES];
+        loop {
+            let nread = unsafe {
+                libc::read(
+                    ufd,
+                    msgs.as_mut_ptr() as *mut c_void,
+                    USERFAULT_MSG_SZ as usize * MAX_MESSAGES,
+                )
+            };
+            if nread == -1 {
+                let err: c_int = unsafe { *libc::__errno_location() };
+                if err == libc::EINTR {
+                    continue;
+                }
+                // Drained.
+                if err == libc::EAGAIN {
+                    return;
+                }
+                panic!("Unexpected result from read() syscall {}", err);
+            }
+            if nread == 0 {
+                panic!("Unexpected EOF from userfaultfd.");
+            }
+            if nread % USERFAULT_MSG_SZ != 0 {
+                panic!(
+                    "Unexpected read size from read() syscall, expected a multiple of {} bytes, got {} bytes.",
+                    USERFAULT_MSG_SZ, nread
+                );
+            }
+            for msg in msgs[..(nread / USERFAULT_MSG_SZ) as usize].iter() {
+                self.dispatch(ufd, *msg);
+            }
+        }
+    }
+
+    fn dispatch(&self, ufd: c_int, msg: uffd_msg) {
+        // Don't hold the lock while handling; handle_event() may register new targets.
+        let target = match self.targets.read().unwrap().get(&ufd) {
+            Some(target) => target.clone(),
+            None => return,
+        };
+
+        if msg.event == UFFD_EVENT_PAGEFAULT {
+            self.pool.spawn(move || target.handle_pagefault(msg));
+        } else {
+            target.handle_event(msg);
+        }
+    }
+
+    fn process_removals(&self) {
+        let mut removals = self.removals.lock().unwrap();
+        if removals.is_empty() {
+            return;
+        }
+        {
+            let mut targets = self.targets.write().unwrap();
+            for ufd in removals.drain(..) {
+                // Keep the target (and its userfaultfd) around until it's out of the epoll set.
+                if let Some(_target) = targets.remove(&ufd) {
+                    unsafe {
+                        libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, ufd, std::ptr::null_mut());
+                    }
+                }
+            }
+        }
+        self.removals_done.notify_all();
+    }
+}
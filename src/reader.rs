@@ -0,0 +1,131 @@
+// This module implements AsyncRead and AsyncSeek over a mapping (with the "async" feature).
+//
+// Reads wait for MMap::ensure_resident() before copying anything out of the mapping, so they
+// don't take blocking page faults. A page could still be evicted between the two; then that read
+// faults like any other. With handlers that can't populate, every read faults.
+
+use crate::userfaultfd::{MMap, MMapHandler, Resident};
+use futures_io::{AsyncRead, AsyncSeek};
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct MMapReader<'a, M: MMapHandler> {
+    mmap: &'a MMap<M>,
+    pos: usize,
+    // The range the last read is waiting for.
+    pending: Option<(usize, usize, Resident<M::Failure>)>,
+}
+
+impl<'a, M: MMapHandler> MMapReader<'a, M> {
+    pub fn new(mmap: &'a MMap<M>) -> Self {
+        MMapReader {
+            mmap,
+            pos: 0,
+            pending: None,
+        }
+    }
+}
+
+impl<'a, M: MMapHandler + Send + Sync> AsyncRead for MMapReader<'a, M>
+where
+    M::Failure: Send,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = this.mmap.len();
+        if this.pos >= len || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let start = this.pos;
+        let end = cmp::min(start + buf.len(), len);
+
+        let mmap = this.mmap;
+        if !M::supports_populate() {
+            buf[..end - start].copy_from_slice(&mmap.as_slice::<u8>()[start..end]);
+            this.pos = end;
+            return Poll::Ready(Ok(end - start));
+        }
+        let stale = match this.pending {
+            Some((pending_start, pending_end, _)) => pending_start != start || pending_end != end,
+            None => true,
+        };
+        if stale {
+            this.pending = Some((start, end, mmap.ensure_resident(start..end)));
+        }
+        let pending = &mut this.pending.as_mut().unwrap().2;
+        match Pin::new(pending).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => {
+                this.pending = None;
+                if let Err(failure) = result {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("{:?}", failure),
+                    )));
+                }
+            }
+        }
+
+        buf[..end - start].copy_from_slice(&mmap.as_slice::<u8>()[start..end]);
+        this.pos = end;
+        Poll::Ready(Ok(end - start))
+    }
+}
+
+impl<'a, M: MMapHandler> AsyncSeek for MMapReader<'a, M> {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => this.mmap.len() as i64 + offset,
+            SeekFrom::Current(offset) => this.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )));
+        }
+        this.pos = new_pos as usize;
+        Poll::Ready(Ok(this.pos as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userfaultfd::mmap_with_userfault;
+    use crate::userfaultfd_dummy::MMapDummy;
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[test]
+    fn read_and_seek() {
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(4096 * 100 + 10).unwrap();
+        let mut reader = MMapReader::new(&mmapped);
+        block_on(async {
+            let mut buf = vec![0u8; 4096 * 3];
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 4096 * 3);
+            for (i, byte) in buf.iter().enumerate() {
+                assert_eq!(*byte, ((i * 13) & 0xFF) as u8);
+            }
+
+            assert_eq!(reader.seek(SeekFrom::End(-20)).await.unwrap(), 4096 * 100 - 10);
+            let mut rest = vec![];
+            assert_eq!(reader.read_to_end(&mut rest).await.unwrap(), 20);
+            assert_eq!(rest[0], (((4096 * 100 - 10) * 13) & 0xFF) as u8);
+        });
+    }
+}
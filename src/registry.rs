@@ -0,0 +1,162 @@
+/* This module implements sharing one mapping between everybody in the process that opens the same
+ * thing.
+ *
+ * mmap_shared() makes the handler first (for S3 that's the HEAD request) and asks it for its
+ * identity, which for S3 is the URL plus the ETag. If a mapping with that identity is still alive,
+ * the new handler is thrown away and the caller gets another reference to that mapping. Otherwise
+ * a new mapping is made and remembered. The registry only holds weak references; the mapping goes
+ * away when the last caller lets go of it.
+ *
+ * Only a mapping with the same options is handed out: asking for one with other options (error
+ * policy, pin limit, huge pages, page cache, access sampling or heuristics) while it's alive fails
+ * with EBUSY. The heuristics go with the mapping, not with whoever opened it, so
+ * MMap::set_heuristics() by one user changes them for all of them (and from then on the others
+ * have to ask for the new ones). Only read-only mappings are shared; writable ones and handlers
+ * without an identity get a mapping of their own like with mmap_with_userfault_options().
+ */
+
+use crate::userfaultfd::{
+    mmap_with_handler, options_page_size, MMap, MMapHandler, MMapMode, MMapOptions,
+};
+use libc::c_int;
+use std::any::{Any, TypeId};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+
+lazy_static! {
+    // Values are Weak<MMap<M>> for the M in the key.
+    static ref SHARED_MMAPS: Mutex<BTreeMap<(TypeId, String), Box<dyn SharedEntry>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+// What the registry needs to know of an entry without knowing its handler type.
+trait SharedEntry: Send {
+    fn as_any(&self) -> &dyn Any;
+    fn alive(&self) -> bool;
+}
+
+impl<M: MMapHandler + Send + Sync> SharedEntry for Weak<MMap<M>> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn alive(&self) -> bool {
+        self.upgrade().is_some()
+    }
+}
+
+pub fn mmap_shared<M: MMapHandler + Send + Sync>(
+    arg: M::Argument,
+    options: MMapOptions,
+) -> Result<Arc<MMap<M>>, Result<c_int, M::Failure>> {
+    let page_size = match options_page_size(&options) {
+        Ok(page_size) => page_size,
+        Err(err) => return Err(Ok(err)),
+    };
+    let (mmap_state, nbytes) = match M::new(arg, page_size) {
+        Err(fail) => return Err(Err(fail)),
+        Ok((mmap_state, nbytes)) => (mmap_state, nbytes),
+    };
+
+    let identity = match mmap_state.identity() {
+        Some(identity) if options.mode == MMapMode::ReadOnly => identity,
+        _ => return mmap_with_handler(mmap_state, nbytes, options).map(Arc::new),
+    };
+    let key = (TypeId::of::<M>(), identity);
+    if let Some(mmapped) = lookup::<M>(&key) {
+        return same_options(mmapped, &options);
+    }
+
+    // Mapping takes a while so don't hold the lock for it. Someone else may beat us to it; then we
+    // use theirs and drop ours.
+    let mmapped = Arc::new(mmap_with_handler(mmap_state, nbytes, options.clone())?);
+    let mut shared_mmaps = SHARED_MMAPS.lock().unwrap();
+    if let Some(theirs) = upgrade::<M>(shared_mmaps.get(&key)) {
+        return same_options(theirs, &options);
+    }
+    let dead: Vec<(TypeId, String)> = shared_mmaps
+        .iter()
+        .filter(|(_, entry)| !entry.alive())
+        .map(|(key, _)| key.clone())
+        .collect();
+    for dead_key in dead {
+        shared_mmaps.remove(&dead_key);
+    }
+    shared_mmaps.insert(key, Box::new(Arc::downgrade(&mmapped)));
+    Ok(mmapped)
+}
+
+fn same_options<M: MMapHandler + Send + Sync>(
+    mmapped: Arc<MMap<M>>,
+    options: &MMapOptions,
+) -> Result<Arc<MMap<M>>, Result<c_int, M::Failure>> {
+    if mmapped.same_options(options) {
+        Ok(mmapped)
+    } else {
+        Err(Ok(libc::EBUSY))
+    }
+}
+
+fn lookup<M: MMapHandler + Send + Sync>(key: &(TypeId, String)) -> Option<Arc<MMap<M>>> {
+    let shared_mmaps = SHARED_MMAPS.lock().unwrap();
+    upgrade::<M>(shared_mmaps.get(key))
+}
+
+fn upgrade<M: MMapHandler + Send + Sync>(
+    entry: Option<&Box<dyn SharedEntry>>,
+) -> Option<Arc<MMap<M>>> {
+    entry?.as_any().downcast_ref::<Weak<MMap<M>>>()?.upgrade()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userfaultfd::ErrorPolicy;
+    use crate::userfaultfd_dummy::MMapDummy;
+
+    #[test]
+    fn same_identity_same_mapping() {
+        // A size no other test uses, the dummy's identity is its size.
+        let size = 4096 * 77 + 3;
+        let first: Arc<MMap<MMapDummy>> = mmap_shared(size, MMapOptions::new()).unwrap();
+        let second: Arc<MMap<MMapDummy>> = mmap_shared(size, MMapOptions::new()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            second.as_slice::<u8>()[4096 * 3 + 1],
+            (((4096 * 3 + 1) * 13) & 0xFF) as u8
+        );
+
+        let other: Arc<MMap<MMapDummy>> = mmap_shared(size + 1, MMapOptions::new()).unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        // Not with other options, though.
+        let mut options = MMapOptions::new();
+        options.error_policy = ErrorPolicy::ZeroFill;
+        assert_eq!(
+            mmap_shared::<MMapDummy>(size, options).err().unwrap().ok(),
+            Some(libc::EBUSY)
+        );
+        let mut config = first.heuristics();
+        config.max_loaded_size *= 2;
+        first.set_heuristics(config.clone()).unwrap();
+        assert!(mmap_shared::<MMapDummy>(size, MMapOptions::new()).is_err());
+        let mut options = MMapOptions::new();
+        options.heuristics = config;
+        let third: Arc<MMap<MMapDummy>> = mmap_shared(size, options).unwrap();
+        assert!(Arc::ptr_eq(&first, &third));
+        drop(third);
+
+        // Writable mappings are never shared.
+        let mut options = MMapOptions::new();
+        options.mode = MMapMode::Private;
+        let private: Arc<MMap<MMapDummy>> = mmap_shared(size, options).unwrap();
+        assert!(!Arc::ptr_eq(&first, &private));
+
+        // The mapping lives as long as somebody holds it.
+        let key = (TypeId::of::<MMapDummy>(), format!("dummy {}", size));
+        drop(first);
+        assert!(lookup::<MMapDummy>(&key).is_some());
+        drop(second);
+        assert!(lookup::<MMapDummy>(&key).is_none());
+    }
+}
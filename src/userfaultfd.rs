@@ -1,12 +1,23 @@
-use crate::mmaputil::{round_down_to_pagesize, round_up_to_pagesize, MMapPages, PAGESIZE_USIZE};
+use crate::budget::{self, BudgetTarget};
+use crate::heuristics::HeuristicsConfig;
+use crate::mmaputil::{
+    round_down_to, round_up_to, MMapPages, HUGE_PAGESIZE_USIZE, PAGESIZE_U64, PAGESIZE_USIZE,
+};
+use crate::pagecache::PageCache;
+use crate::reactor::{self, FaultTarget};
 use libc::{c_int, c_long, c_void, size_t};
-use rayon::ThreadPoolBuilder;
-use std::collections::BTreeSet;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
+use std::future::Future;
 use std::mem;
+use std::ops::Range;
+use std::pin::Pin;
 use std::slice;
-use std::sync::{Arc, RwLock};
-use std::thread::{spawn, JoinHandle};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 static NR_USERFAULTFD: c_long = 323;
 static O_CLOEXEC: c_int = 0o2000000;
@@ -15,41 +26,431 @@ static O_NONBLOCK: c_int = 0o0004000;
 // ioctl IDs
 static UFFDIO_API: c_int = -1072125377;
 static UFFDIO_REGISTER: c_int = -1071601152;
+static UFFDIO_UNREGISTER: c_int = -2146391551;
+static UFFDIO_WAKE: c_int = -2146391550;
 static UFFDIO_COPY: c_int = -1071076861;
+static UFFDIO_ZEROPAGE: c_int = -1071601148;
+static UFFDIO_POISON: c_int = -1071601144;
+static UFFDIO_WRITEPROTECT: c_int = -1072125434;
+static UFFDIO_CONTINUE: c_int = -1071601145;
 
 static UFFD_API: u64 = 0xAA;
 static UFFDIO_REGISTER_MODE_MISSING: u64 = 0x1;
+static UFFDIO_REGISTER_MODE_WP: u64 = 0x2;
+static UFFDIO_REGISTER_MODE_MINOR: u64 = 0x4;
+static UFFDIO_COPY_MODE_WP: u64 = 1 << 1;
+static UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
 
-static UFFD_EVENT_PAGEFAULT: u8 = 18;
+static UFFD_FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+static UFFD_FEATURE_EVENT_FORK: u64 = 1 << 1;
+static UFFD_FEATURE_EVENT_REMAP: u64 = 1 << 2;
+static UFFD_FEATURE_EVENT_REMOVE: u64 = 1 << 3;
+static UFFD_FEATURE_EVENT_UNMAP: u64 = 1 << 6;
+static UFFD_FEATURE_THREAD_ID: u64 = 1 << 8;
+static UFFD_FEATURE_MINOR_SHMEM: u64 = 1 << 10;
+static UFFD_FEATURE_EXACT_ADDRESS: u64 = 1 << 11;
+static UFFD_FEATURE_POISON: u64 = 1 << 14;
 
-const USERFAULT_MSG_SZ: isize = 32;
+static UFFD_PAGEFAULT_FLAG_WRITE: u64 = 1 << 0;
+static UFFD_PAGEFAULT_FLAG_WP: u64 = 1 << 1;
+static UFFD_PAGEFAULT_FLAG_MINOR: u64 = 1 << 2;
 
-const MAX_CONCURRENT_WORKERS: usize = 16;
+pub static UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+static UFFD_EVENT_FORK: u8 = 0x13;
+static UFFD_EVENT_REMAP: u8 = 0x14;
+static UFFD_EVENT_REMOVE: u8 = 0x15;
+static UFFD_EVENT_UNMAP: u8 = 0x16;
+
+pub const USERFAULT_MSG_SZ: isize = 32;
+
+// How long to wait before retrying a failed userfault, multiplied by the attempt number.
+const RETRY_BACKOFF_MS: u64 = 100;
+
+// Default for MMapOptions::pin_limit.
+const DEFAULT_PIN_LIMIT: usize = 64 * 1024 * 1024;
+
+// Default for MMapOptions::access_sample_size (off), and how often pages are sampled.
+const DEFAULT_ACCESS_SAMPLE_SIZE: usize = 0;
+const ACCESS_SAMPLE_INTERVAL_MS: u64 = 1000;
+
+// What to do when a handler fails to produce the pages for a fault. By the time this happens the
+// mapping has been handed out and there is nobody to return an error to; the faulting thread is
+// just sitting there waiting for its page.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    Abort,        // Take down the whole process. This is the default.
+    Retry(u32),   // Ask the handler again up to this many times, then abort.
+    ZeroFill,     // Fill the faulting page with zeroes (UFFDIO_ZEROPAGE) and carry on.
+    SigBus,       // Deliver SIGBUS to the faulting thread (UFFDIO_POISON, Linux 6.6+).
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MMapMode {
+    ReadOnly,  // PROT_READ only. This is the default.
+    WriteBack, // Readable and writable; MMap::flush() writes modified pages back to the handler.
+    Private,   // Readable and writable; modified pages stay in our memory and are never written
+               // anywhere. Unmodified pages are still loaded and evicted as usual.
+}
+
+// Hints about how a range of a mapping is going to be used, see MMap::advise().
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Advice {
+    Normal,     // Forget earlier Sequential and Random advice.
+    Sequential, // Read ahead a lot, without waiting to see sequential access first.
+    Random,     // Don't read ahead at all.
+    WillNeed,   // Start bringing the range in, in the background.
+    DontNeed,   // Evict the range. Modified pages stay.
+}
+
+#[derive(Debug, Clone)]
+pub struct MMapOptions {
+    pub error_policy: ErrorPolicy,
+    pub mode: MMapMode,
+    // How many bytes MMap::pin() may keep from being evicted.
+    pub pin_limit: usize,
+    // Make the mapping out of huge pages (MAP_HUGETLB, of the system's default huge page size)
+    // instead of normal ones. Pages are then copied in, tracked and evicted a huge page at a time.
+    // The huge pages have to be set aside beforehand (vm.nr_hugepages), enough for whatever the
+    // handler keeps loaded.
+    pub huge_pages: bool,
+    // Keep the pages in a cache that other mappings of the same object, also in other processes,
+    // can use too (see pagecache.rs). Only for MMapMode::ReadOnly mappings without huge pages.
+    pub page_cache: Option<Arc<PageCache>>,
+    // Read-ahead and eviction knobs handed to the handler (see heuristics.rs). They can be changed
+    // later with MMap::set_heuristics(). Mapping fails with EINVAL if they don't hold together.
+    pub heuristics: HeuristicsConfig,
+    // How many loaded pages to check every second or so for whether they are still being used.
+    // What is found is reported with MMapHandler::accessed(). 0, the default, turns this off. See
+    // sample_accesses().
+    pub access_sample_size: usize,
+}
+
+impl MMapOptions {
+    pub fn new() -> Self {
+        MMapOptions {
+            error_policy: ErrorPolicy::Abort,
+            mode: MMapMode::ReadOnly,
+            pin_limit: DEFAULT_PIN_LIMIT,
+            huge_pages: false,
+            page_cache: None,
+            heuristics: HeuristicsConfig::new(),
+            access_sample_size: DEFAULT_ACCESS_SAMPLE_SIZE,
+        }
+    }
+}
+
+impl Default for MMapOptions {
+    fn default() -> Self {
+        MMapOptions::new()
+    }
+}
 
 #[derive(Debug)]
-pub struct MMap<M> {
-    thread_handle: Option<JoinHandle<()>>,
+pub struct MMap<M: MMapHandler> {
     ptr_u64: u64,
-    die: Arc<RwLock<bool>>,
     sz: size_t,
     sz_unrounded: size_t,
-    ufd: c_int,
+    // Process that made the mapping. A forked child gets a copy of the MMap but the pages of its
+    // copy of the mapping are still served from here.
+    pid: libc::pid_t,
+    shared: Arc<MMapShared>,
+    flush_lock: Mutex<()>,
+    // What was last handed to the handler with MMapHandler::set_heuristics().
+    heuristics: Mutex<HeuristicsConfig>,
     mmap_state: M,
 }
 
+// The parts of a mapping the fault handling threads need to see. There is one of these for the
+// mapping itself and one for each copy of it in a forked child, each with its own userfaultfd.
+// Fault jobs can outlive the MMap so this owns the userfaultfd; it's closed when the last job lets
+// go.
+#[derive(Debug)]
+struct MMapShared {
+    ufd: c_int,
+    family: Arc<MMapFamily>,
+    sz: u64,
+    // Size of the pages the mapping is made of, see MMapOptions::huge_pages. Everything the
+    // handler sees and the page numbers in here are in these.
+    page_size: u64,
+    options: MMapOptions,
+    // Copies in forked children. We can put pages in through the userfaultfd but can't evict
+    // anything from another process.
+    forked: bool,
+    // Where the mapping is in memory. Only locked briefly and never by anything that waits for the
+    // reactor, because the reactor thread locks it to handle mremap() and munmap().
+    segments: Mutex<Segments>,
+    // Pages that have been written to (since last flush, for MMapMode::WriteBack). Only used with
+    // writable mappings. These must never be evicted; their contents only exist in our memory.
+    dirty: RwLock<BTreeSet<usize>>,
+    // Pages that MMap::pin() keeps from being evicted.
+    pinned: RwLock<BTreeSet<usize>>,
+    // (start, end) offsets of pages that are being streamed in right now.
+    streams: Mutex<Vec<(u64, u64)>>,
+    // What the mapping is known as to the memory budget (see budget.rs). Copies in forked children
+    // have the same id but are not counted.
+    budget_id: u64,
+    // Pages sample_accesses() has taken out of the mapping and that haven't been touched since,
+    // with their contents unless the page cache has them. Evictions lock this first.
+    sampled: Mutex<BTreeMap<usize, Option<Vec<u8>>>>,
+    last_sample: Mutex<Instant>,
+}
+
+// What a mapping shares with its copies in forked children.
+#[derive(Debug)]
+struct MMapFamily {
+    // Set to false when the MMap is dropped. Fault jobs hold a read lock while they install or evict
+    // pages, so once the MMap holds the write lock none of them touches the memory again.
+    alive: RwLock<bool>,
+    // userfaultfds of the forked copies. Children are served until the MMap is dropped.
+    forks: Mutex<Vec<c_int>>,
+}
+
+// Where the pieces of a mapping are. Normally this is one piece at the address mmap() gave us, but
+// mremap() and munmap() from elsewhere in the process can move pieces around, split them up and
+// remove them.
+#[derive(Debug, Clone)]
+struct Segments {
+    // start address -> (length, offset into the mapping)
+    pieces: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Segments {
+    fn new(start: u64, len: u64) -> Self {
+        let mut pieces = BTreeMap::new();
+        pieces.insert(start, (len, 0));
+        Segments { pieces }
+    }
+
+    // Offset into the mapping of an address.
+    fn offset_of(&self, address: u64) -> Option<u64> {
+        let (start, (len, offset)) = self.pieces.range(..=address).next_back()?;
+        if address < start + len {
+            Some(offset + (address - start))
+        } else {
+            None
+        }
+    }
+
+    // Where the range [offset, offset+len) of the mapping is, as (address, offset, length) pieces.
+    // Parts that are not mapped anywhere are left out.
+    fn addresses_of(&self, offset: u64, len: u64) -> Vec<(u64, u64, u64)> {
+        let mut result = vec![];
+        for (start, (piece_len, piece_offset)) in self.pieces.iter() {
+            let from = cmp::max(offset, *piece_offset);
+            let to = cmp::min(offset + len, piece_offset + piece_len);
+            if from < to {
+                result.push((start + (from - piece_offset), from, to - from));
+            }
+        }
+        result
+    }
+
+    // Address of the start of the mapping, if the mapping of `sz` bytes is still in one piece.
+    fn base(&self, sz: u64) -> Option<u64> {
+        if self.pieces.len() != 1 {
+            return None;
+        }
+        let (start, (len, offset)) = self.pieces.iter().next()?;
+        if *len == sz && *offset == 0 {
+            Some(*start)
+        } else {
+            None
+        }
+    }
+
+    // Removes [start, end) and returns what was there as (address, length, offset).
+    fn take(&mut self, start: u64, end: u64) -> Vec<(u64, u64, u64)> {
+        let overlapping: Vec<(u64, u64, u64)> = self
+            .pieces
+            .range(..end)
+            .filter(|(piece_start, (len, _))| *piece_start + len > start)
+            .map(|(piece_start, (len, offset))| (*piece_start, *len, *offset))
+            .collect();
+        let mut taken = vec![];
+        for (piece_start, len, offset) in overlapping {
+            let piece_end = piece_start + len;
+            self.pieces.remove(&piece_start);
+            if piece_start < start {
+                self.pieces.insert(piece_start, (start - piece_start, offset));
+            }
+            if piece_end > end {
+                self.pieces
+                    .insert(end, (piece_end - end, offset + (end - piece_start)));
+            }
+            let from = cmp::max(start, piece_start);
+            let to = cmp::min(end, piece_end);
+            taken.push((from, to - from, offset + (from - piece_start)));
+        }
+        taken
+    }
+
+    fn unmap(&mut self, start: u64, end: u64) {
+        self.take(start, end);
+    }
+
+    fn remap(&mut self, from: u64, to: u64, len: u64) {
+        for (start, piece_len, offset) in self.take(from, from + len) {
+            self.pieces.insert(start - from + to, (piece_len, offset));
+        }
+    }
+}
+
+// What a handler is asked to resolve.
+#[derive(Debug, Clone)]
+pub struct FaultRequest {
+    // Offset into the mapping of the faulting page.
+    pub offset: u64,
+    // The address that was touched. With mremap() this need not be anywhere near where the mapping
+    // started out. Before Linux 5.18 this is the start of the page.
+    pub address: u64,
+    // Whether the fault was caused by a write.
+    pub write: bool,
+    // Thread that faulted, as a kernel thread id (what gettid() returns in that thread).
+    pub thread_id: u32,
+}
+
+// Pages and the offset into the mapping they go to.
+pub struct PageRun {
+    pub offset: u64,
+    pub pages: MMapPages,
+}
+
+// What a handler hands back for a userfault.
+pub struct FaultResolution<I> {
+    // Runs of pages to copy in. This can be a stream: each run is copied in as soon as the iterator
+    // hands it over, so the faulting thread gets going as soon as the run with the faulting page
+    // is there; that one should come first. Runs don't have to be contiguous or in order.
+    // Dropping the iterator means we are not interested in the rest, e.g. because the mapping is
+    // gone. No run (and no zero range) covering the faulting page means somebody else has brought
+    // the faulting page in.
+    pub pages: I,
+    // (offset, length) ranges, page aligned, that the handler knows to be all zeroes. These are
+    // filled with UFFDIO_ZEROPAGE without allocating or copying anything (except for huge pages,
+    // which have no zero page).
+    pub zero_ranges: Vec<(u64, u64)>,
+    // Pages the handler wants evicted.
+    pub evictions: BTreeSet<usize>,
+    // How many bytes from the faulting page on `pages` is going to cover, if known. Faults on those
+    // pages while they are streamed in wait for the stream instead of asking the handler again.
+    pub pages_len: u64,
+    // (offset, length) ranges to bring in in the background. Each is handed to
+    // MMapHandler::populate() on the worker pool, so the faulting thread only waits for `pages`.
+    pub readahead: Vec<(u64, u64)>,
+}
+
+impl MMapShared {
+    // Returns a guard to hold while touching the mapped memory, or None if the mapping is gone.
+    fn lock_alive(&self) -> Option<RwLockReadGuard<'_, bool>> {
+        let alive = self.family.alive.read().unwrap();
+        if *alive {
+            Some(alive)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for MMapShared {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.ufd);
+        }
+    }
+}
+
+impl<I> FaultResolution<I> {
+    pub fn new(pages: I, evictions: BTreeSet<usize>) -> Self {
+        FaultResolution {
+            pages,
+            zero_ranges: vec![],
+            evictions,
+            pages_len: 0,
+            readahead: vec![],
+        }
+    }
+}
+
 pub trait MMapHandler
 where
     Self: Sized + Clone + 'static,
 {
     type Argument;
     type Failure: Debug;
-    type PageIterator: IntoIterator<Item = MMapPages>;
+    // A failure before the faulting page is handed over fails the whole userfault (see
+    // ErrorPolicy). After that it only cuts the read-ahead short.
+    type PageIterator: IntoIterator<Item = Result<PageRun, Self::Failure>>;
 
-    fn new(arg: Self::Argument) -> Result<(Self, usize), Self::Failure>;
+    // Returns the handler and the size of the mapping in bytes. `page_size` is the size of the
+    // pages the mapping is made of; offsets and lengths the handler is given are multiples of it
+    // and so must be the page runs it hands back (except at the end of the mapping).
+    fn new(arg: Self::Argument, page_size: usize) -> Result<(Self, usize), Self::Failure>;
+    // Called on a worker thread, possibly on several at the same time.
     fn handle_userfault(
-        self,
-        offset: u64,
-    ) -> Result<(Self::PageIterator, BTreeSet<usize>), Self::Failure>;
+        &self,
+        request: &FaultRequest,
+    ) -> Result<FaultResolution<Self::PageIterator>, Self::Failure>;
+
+    // Handlers that can fetch pages other than for a fault override supports_populate(); the rest
+    // return an error from populate(). Read-ahead asked of a handler that doesn't support it is
+    // dropped and the pages are faulted in when they are touched.
+    fn supports_populate() -> bool {
+        false
+    }
+
+    // Fetches the pages for a read-ahead range from FaultResolution::readahead. Like with faults,
+    // pages that are left out are assumed to be on their way from somewhere else.
+    fn populate(&self, offset: u64, len: u64) -> Result<Self::PageIterator, Self::Failure>;
+
+    // Takes a hint about [offset, offset+len), page aligned. The returned read-ahead, zero ranges
+    // and evictions are carried out like they are for faults. Pages already there are not read
+    // ahead again, and Advice::DontNeed evicts the range whatever the handler says.
+    fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> FaultResolution<()> {
+        FaultResolution::new((), BTreeSet::new())
+    }
+
+    // Pages in [offset, offset+len), page aligned, have been pinned or unpinned. Pinned pages are
+    // never evicted, whatever the handler asks for, so they shouldn't count towards whatever limits
+    // the handler evicts pages to stay under.
+    fn pin(&self, _offset: u64, _len: u64) {}
+    fn unpin(&self, _offset: u64, _len: u64) {}
+
+    // Takes read-ahead and eviction knobs. Called with MMapOptions::heuristics before the mapping
+    // is handed out, and again whenever MMap::set_heuristics() is called. Handlers that don't read
+    // ahead or evict can ignore it.
+    fn set_heuristics(&self, _config: &HeuristicsConfig) {}
+
+    // Pages in [offset, offset+len), page aligned, have been used since they were loaded. Only a
+    // sample of the loaded pages is watched (see MMapOptions::access_sample_size), so this is a
+    // hint about which pages are worth keeping, not a record of every access.
+    fn accessed(&self, _offset: u64, _len: u64) {}
+
+    // Pages in [offset, offset+len), page aligned, that the handler asked to evict were kept
+    // because they are modified or pinned. They are still loaded and will be asked about again.
+    fn kept(&self, _offset: u64, _len: u64) {}
+
+    // Pages that are about to be evicted to keep the process under its memory budget (see
+    // budget.rs), picked from all mappings by how recently they were used. Handlers that keep
+    // track of what they have loaded should forget them like pages they evict on their own. Those
+    // that can't be evicted are handed back with kept().
+    fn budget_evicted(&self, _pages: &BTreeSet<usize>) {}
+
+    // Handlers that can write data back override supports_write_back(); the rest return an error
+    // from write_back(). mmap_with_userfault_options() refuses to make a MMapMode::WriteBack
+    // mapping if supports_write_back() returns false, so for them it is never called.
+    fn supports_write_back() -> bool {
+        false
+    }
+
+    // `contents` is the entire mapping and `dirty` lists the (start, end) byte ranges that have
+    // been modified, sorted and non-overlapping.
+    fn write_back(&self, contents: &[u8], dirty: &[(usize, usize)]) -> Result<(), Self::Failure>;
+
+    // Names what the handler serves, for mmap_shared(). Two handlers with the same identity must
+    // hand out the same bytes; their mappings are then one and the same. None (the default) means
+    // the mapping is never shared.
+    fn identity(&self) -> Option<String> {
+        None
+    }
 }
 
 #[repr(C)]
@@ -67,13 +468,46 @@ struct uffdio_register {
     ioctls: u64,
 }
 
+#[repr(C)]
+struct uffdio_range {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct uffdio_zeropage {
+    range: uffdio_range,
+    mode: u64,
+    zeropage: i64,
+}
+
+#[repr(C)]
+struct uffdio_poison {
+    range: uffdio_range,
+    mode: u64,
+    updated: i64,
+}
+
+#[repr(C)]
+struct uffdio_writeprotect {
+    range: uffdio_range,
+    mode: u64,
+}
+
+#[repr(C)]
+struct uffdio_continue {
+    range: uffdio_range,
+    mode: u64,
+    mapped: i64,
+}
+
 #[repr(C)]
 struct uffdio_copy {
     dst: u64,
     src: u64,
     len: u64,
     mode: u64,
-    copy: u64,
+    copy: i64,
 }
 
 impl uffdio_copy {
@@ -92,18 +526,18 @@ impl uffdio_copy {
 #[repr(packed)]
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
-struct uffd_msg {
-    event: u8,
+pub struct uffd_msg {
+    pub event: u8,
     reserved1: u8,
     reserved2: u16,
     reserved3: u32,
-    flags: u64,
-    address: u64,
+    pub flags: u64,
+    pub address: u64,
     padding: u64,
 }
 
 impl uffd_msg {
-    fn new() -> Self {
+    pub fn new() -> Self {
         uffd_msg {
             event: 0,
             reserved1: 0,
@@ -137,59 +571,226 @@ impl uffdio_api {
     }
 }
 
-impl<M> Drop for MMap<M> {
+impl<M: MMapHandler> Drop for MMap<M> {
     fn drop(&mut self) {
-        {
-            let mut die_val = self.die.write().unwrap();
-            *die_val = true;
-        };
-        let handle = self.thread_handle.take();
-        match handle {
-            Some(handle) => {
-                handle.join().unwrap();
+        if unsafe { libc::getpid() } != self.pid {
+            // We are a forked child; the reactor and everything else lives in the parent. Just get
+            // rid of our copy. Anything else would mean taking locks that may have been held by
+            // some thread of the parent at the time of the fork.
+            unsafe {
+                libc::munmap(self.ptr_u64 as *mut c_void, self.sz);
             }
-            None => (),
+            return;
         }
-        unsafe {
-            libc::close(self.ufd);
-            libc::munmap(self.ptr_u64 as *mut c_void, self.sz);
+
+        // Last chance to get modifications out. There is nowhere to report errors to.
+        if self.shared.options.mode == MMapMode::WriteBack {
+            let in_one_piece = self.shared.segments.lock().unwrap().base(self.sz as u64).is_some();
+            if !in_one_piece {
+                error!("mapping has been split up, cannot write back modified pages while unmapping.");
+            } else if let Err(err) = self.flush() {
+                error!(
+                    "failed to write back modified pages while unmapping: {:?}",
+                    err
+                );
+            }
+        }
+
+        // Jobs that are already queued or running are not waited for; they see the mapping is
+        // gone and drop whatever they were doing. The only thing we wait for is a job that is in
+        // the middle of installing or evicting pages. This has to happen before we stop listening
+        // to the userfaultfd: madvise() in an evicting job waits for the reactor to read the
+        // UFFD_EVENT_REMOVE it causes.
+        *self.shared.family.alive.write().unwrap() = false;
+        budget::unregister(self.shared.budget_id);
+        // No new fault jobs after this, for us or for copies of us in forked children. Copies
+        // can fork more copies until they are unregistered.
+        reactor::unregister(self.shared.ufd);
+        loop {
+            let forks = mem::replace(&mut *self.shared.family.forks.lock().unwrap(), vec![]);
+            if forks.is_empty() {
+                break;
+            }
+            for ufd in forks {
+                reactor::unregister(ufd);
+            }
+        }
+
+        let segments = self.shared.segments.lock().unwrap().clone();
+        for (start, (len, _)) in segments.pieces.iter() {
+            // Threads still waiting for a page are woken up when the range is unregistered from
+            // the userfaultfd. Taking away access first makes them get SIGSEGV rather than a page
+            // of zeroes. Unregistering also keeps munmap() from sending an event nobody reads.
+            unsafe {
+                libc::mprotect(*start as *mut c_void, *len as size_t, libc::PROT_NONE);
+            }
+            let range = uffdio_range {
+                start: *start,
+                len: *len,
+            };
+            unsafe {
+                libc::ioctl(self.shared.ufd, UFFDIO_UNREGISTER as u64, &range);
+                libc::munmap(*start as *mut c_void, *len as size_t);
+            }
         }
     }
 }
 
-pub fn mmap_with_userfault<M: MMapHandler + Send>(
-    arg: M::Argument,
-) -> Result<MMap<M>, Result<c_int, M::Failure>> {
-    let (mmap_state, nbytes) = match M::new(arg) {
-        Err(fail) => return Err(Err(fail)),
-        Ok((mmap_state, nbytes)) => (mmap_state, nbytes),
+lazy_static! {
+    // Features the kernel knows about. Asking for no features gets us the whole list.
+    static ref AVAILABLE_FEATURES: u64 = {
+        match open_userfaultfd(0) {
+            Ok((ufd, features)) => {
+                unsafe {
+                    libc::close(ufd);
+                }
+                features
+            }
+            Err(_) => 0,
+        }
     };
+}
 
-    let nbytes_unrounded = nbytes;
-    let nbytes = if nbytes == 0 { 1 } else { nbytes };
-    let nbytes = round_up_to_pagesize(nbytes);
-
+// Opens a userfaultfd with the given features. Returns it along with the features the kernel has
+// to offer, or errno if that can't be done.
+fn open_userfaultfd(features: u64) -> Result<(c_int, u64), c_int> {
     let ufd: c_int = unsafe { libc::syscall(NR_USERFAULTFD, O_CLOEXEC | O_NONBLOCK) as c_int };
     if ufd == -1 {
         let err: c_int = unsafe { *libc::__errno_location() };
-        return Err(Ok(err));
+        return Err(err);
     }
-    let uapi = uffdio_api::new();
-    if unsafe { libc::ioctl(ufd, UFFDIO_API as u64, &uapi) } == -1 {
+    let mut uapi = uffdio_api::new();
+    uapi.features = features;
+    if unsafe { libc::ioctl(ufd, UFFDIO_API as u64, &mut uapi) } == -1 {
         let err: c_int = unsafe { *libc::__errno_location() };
         unsafe {
             libc::close(ufd);
         };
-        return Err(Ok(err));
+        return Err(err);
+    }
+    Ok((ufd, uapi.features))
+}
+
+pub fn mmap_with_userfault<M: MMapHandler + Send + Sync>(
+    arg: M::Argument,
+) -> Result<MMap<M>, Result<c_int, M::Failure>> {
+    mmap_with_userfault_options(arg, MMapOptions::new())
+}
+
+pub fn mmap_with_userfault_options<M: MMapHandler + Send + Sync>(
+    arg: M::Argument,
+    options: MMapOptions,
+) -> Result<MMap<M>, Result<c_int, M::Failure>> {
+    let page_size = match options_page_size(&options) {
+        Ok(page_size) => page_size,
+        Err(err) => return Err(Ok(err)),
+    };
+
+    let (mmap_state, nbytes) = match M::new(arg, page_size) {
+        Err(fail) => return Err(Err(fail)),
+        Ok((mmap_state, nbytes)) => (mmap_state, nbytes),
+    };
+    mmap_with_handler(mmap_state, nbytes, options)
+}
+
+// The page size mappings made with `options` are made of, or errno.
+pub(crate) fn options_page_size(options: &MMapOptions) -> Result<usize, c_int> {
+    if options.huge_pages {
+        match *HUGE_PAGESIZE_USIZE {
+            Some(page_size) => Ok(page_size),
+            None => Err(libc::EINVAL),
+        }
+    } else {
+        Ok(*PAGESIZE_USIZE)
+    }
+}
+
+// Maps `nbytes` served by a handler that has already been made (with the page size
+// options_page_size() gives for `options`).
+pub(crate) fn mmap_with_handler<M: MMapHandler + Send + Sync>(
+    mmap_state: M,
+    nbytes: usize,
+    options: MMapOptions,
+) -> Result<MMap<M>, Result<c_int, M::Failure>> {
+    let page_size = match options_page_size(&options) {
+        Ok(page_size) => page_size,
+        Err(err) => return Err(Ok(err)),
+    };
+
+    let writable = options.mode != MMapMode::ReadOnly;
+    if options.mode == MMapMode::WriteBack && !M::supports_write_back() {
+        return Err(Ok(libc::EROFS));
+    }
+    // Writes would end up in everybody's copy, and hugetlbfs can't be written to other than
+    // through a mapping.
+    if options.page_cache.is_some() && (writable || options.huge_pages) {
+        return Err(Ok(libc::EINVAL));
+    }
+    if !options.heuristics.consistent(page_size) {
+        return Err(Ok(libc::EINVAL));
+    }
+
+    let nbytes_unrounded = nbytes;
+    let nbytes = if nbytes == 0 { 1 } else { nbytes };
+    let nbytes = round_up_to(nbytes, page_size);
+
+    if let Some(ref cache) = options.page_cache {
+        if let Err(err) = cache.ensure_len(nbytes) {
+            return Err(Ok(err));
+        }
+    }
+
+    // The events let us follow the mapping into forked children and notice when other code in the
+    // process moves or unmaps parts of it. Thread ids are passed on to the handler.
+    let mut features = UFFD_FEATURE_EVENT_FORK
+        | UFFD_FEATURE_EVENT_REMAP
+        | UFFD_FEATURE_EVENT_REMOVE
+        | UFFD_FEATURE_EVENT_UNMAP
+        | UFFD_FEATURE_THREAD_ID;
+    if options.error_policy == ErrorPolicy::SigBus {
+        features |= UFFD_FEATURE_POISON;
+    }
+    if writable {
+        features |= UFFD_FEATURE_PAGEFAULT_FLAG_WP;
     }
+    if options.page_cache.is_some() {
+        features |= UFFD_FEATURE_MINOR_SHMEM;
+    }
+    // Before Linux 5.18 we only get to know which page was touched.
+    features |= *AVAILABLE_FEATURES & UFFD_FEATURE_EXACT_ADDRESS;
+    // Following forks needs CAP_SYS_PTRACE. Without it, forked children don't get the mapping at
+    // all (see MADV_DONTFORK below).
+    let (ufd, follows_forks) = match open_userfaultfd(features) {
+        Ok((ufd, _)) => (ufd, true),
+        Err(err) if err == libc::EPERM => {
+            match open_userfaultfd(features & !UFFD_FEATURE_EVENT_FORK) {
+                Ok((ufd, _)) => (ufd, false),
+                Err(err) => return Err(Ok(err)),
+            }
+        }
+        Err(err) => return Err(Ok(err)),
+    };
 
     let ptr = unsafe {
         libc::mmap(
             std::ptr::null::<*const c_void>() as *mut c_void,
             nbytes,
-            libc::PROT_READ,
-            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
-            -1,
+            if writable {
+                libc::PROT_READ | libc::PROT_WRITE
+            } else {
+                libc::PROT_READ
+            },
+            if options.page_cache.is_some() {
+                libc::MAP_SHARED
+            } else if options.huge_pages {
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_HUGETLB
+            } else {
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE
+            },
+            match options.page_cache {
+                Some(ref cache) => cache.fd(),
+                None => -1,
+            },
             0,
         )
     };
@@ -205,6 +806,15 @@ pub fn mmap_with_userfault<M: MMapHandler + Send>(
     register.start = ptr as u64;
     register.len = nbytes as u64;
     register.mode = UFFDIO_REGISTER_MODE_MISSING;
+    // Writable mappings install every page write-protected so that we find out which pages get
+    // modified.
+    if writable {
+        register.mode |= UFFDIO_REGISTER_MODE_WP;
+    }
+    // Pages that are in the cache but not mapped by us yet fault too.
+    if options.page_cache.is_some() {
+        register.mode |= UFFDIO_REGISTER_MODE_MINOR;
+    }
 
     if unsafe { libc::ioctl(ufd, UFFDIO_REGISTER as u64, &register) } == -1 {
         let err: c_int = unsafe { *libc::__errno_location() };
@@ -215,202 +825,1142 @@ pub fn mmap_with_userfault<M: MMapHandler + Send>(
         return Err(Ok(err));
     }
 
-    let die = Arc::new(RwLock::new(false));
-    let die_thread = die.clone();
-    // The Wrapper is a dance to send a pointer to a thread.
-    // Rust resists sending pointers to threads without some rituals.
-    let mmap_state_cloned = mmap_state.clone();
+    // A child would get a copy of the mapping that nobody serves; every missing page would read
+    // as zeroes. Better it doesn't get one at all.
+    if !follows_forks && unsafe { libc::madvise(ptr, nbytes, libc::MADV_DONTFORK) } == -1 {
+        let err: c_int = unsafe { *libc::__errno_location() };
+        unsafe {
+            libc::munmap(ptr as *mut c_void, nbytes);
+            libc::close(ufd);
+        }
+        return Err(Ok(err));
+    }
+
     let ptr_u64: u64 = ptr as u64;
-    let thread_handle = spawn(move || {
-        run_userfault_handler(ufd, die_thread, mmap_state_cloned, ptr_u64);
+    let shared = Arc::new(MMapShared {
+        ufd,
+        family: Arc::new(MMapFamily {
+            alive: RwLock::new(true),
+            forks: Mutex::new(vec![]),
+        }),
+        sz: nbytes as u64,
+        page_size: page_size as u64,
+        options,
+        forked: false,
+        segments: Mutex::new(Segments::new(ptr_u64, nbytes as u64)),
+        dirty: RwLock::new(BTreeSet::new()),
+        pinned: RwLock::new(BTreeSet::new()),
+        streams: Mutex::new(vec![]),
+        budget_id: budget::new_id(),
+        sampled: Mutex::new(BTreeMap::new()),
+        last_sample: Mutex::new(Instant::now()),
+    });
+    let target = Arc::new(MMapFaultTarget {
+        shared: shared.clone(),
+        mmap_state: mmap_state.clone(),
     });
+    if let Err(err) = reactor::register(target.clone()) {
+        // The userfaultfd is closed when `shared` goes away.
+        unsafe {
+            libc::munmap(ptr as *mut c_void, nbytes);
+        }
+        return Err(Ok(err));
+    }
+    // The reactor holds on to the target until the mapping is dropped.
+    let budget_target: Arc<dyn BudgetTarget> = target;
+    budget::register(shared.budget_id, page_size, Arc::downgrade(&budget_target));
+    let heuristics = shared.options.heuristics.clone();
+    mmap_state.set_heuristics(&heuristics);
+
     Ok(MMap {
-        thread_handle: Some(thread_handle),
         ptr_u64,
-        die: die,
         sz: nbytes,
         sz_unrounded: nbytes_unrounded,
-        ufd,
+        pid: unsafe { libc::getpid() },
+        shared,
+        flush_lock: Mutex::new(()),
+        heuristics: Mutex::new(heuristics),
         mmap_state,
     })
 }
 
-fn run_userfault_handler<M: MMapHandler + Send>(
-    ufd: c_int,
-    die: Arc<RwLock<bool>>,
+// This is what the reactor sees of a mapping.
+struct MMapFaultTarget<M> {
+    shared: Arc<MMapShared>,
     mmap_state: M,
-    ptr_u64: u64,
-) {
-    let tpool = ThreadPoolBuilder::new()
-        .num_threads(MAX_CONCURRENT_WORKERS)
-        .build()
-        .unwrap();
-
-    tpool.scope(move |scope| run_userfault_handler_scoped(ufd, die, scope, mmap_state, ptr_u64));
 }
 
-fn run_userfault_handler_scoped<M: MMapHandler + Send>(
-    ufd: c_int,
-    die: Arc<RwLock<bool>>,
-    scope: &rayon::Scope,
-    mmap_state: M,
-    ptr_u64: u64,
-) {
-    let mut userfault_msg = uffd_msg::new();
+impl<M: MMapHandler + Send + Sync> FaultTarget for MMapFaultTarget<M> {
+    fn ufd(&self) -> c_int {
+        self.shared.ufd
+    }
 
-    let mut pfd = libc::pollfd {
-        fd: ufd,
-        events: libc::POLLIN,
-        revents: 0,
-    };
+    fn handle_pagefault(&self, msg: uffd_msg) {
+        pagefault_handle(msg, &self.mmap_state, &self.shared);
+    }
 
-    loop {
-        // Check if we are supposed to die.
-        {
-            let die_val = die.read().unwrap();
-            if *die_val == true {
-                return;
-            }
+    fn handle_event(&self, msg: uffd_msg) {
+        let event = msg.event;
+        if event == UFFD_EVENT_FORK {
+            // The child's userfaultfd number is in the low 32 bits.
+            self.fork(msg.flags as u32 as c_int);
+        } else if event == UFFD_EVENT_REMAP {
+            // from, to, length
+            let mut segments = self.shared.segments.lock().unwrap();
+            segments.remap(msg.flags, msg.address, msg.padding);
+        } else if event == UFFD_EVENT_UNMAP {
+            // start, end
+            let mut segments = self.shared.segments.lock().unwrap();
+            segments.unmap(msg.flags, msg.address);
+        } else if event == UFFD_EVENT_REMOVE {
+            // Pages dropped with madvise(), including our own evictions. They are missing again
+            // and come back from the handler the next time they are touched; nothing to do.
+        } else {
+            panic!("Unexpected userfaultfd event type {}.", event);
         }
+    }
+}
 
-        // Poll for events. We specify timeout of 100ms and try again if timeout was reached. This
-        // makes sure we check for the die flag in at most 100ms if it gets set by MMap Drop trait
-        // and is waiting for our thread to go down.
-        pfd.fd = ufd;
-        pfd.events = libc::POLLIN;
-        pfd.revents = 0;
-        let pollresult = unsafe { libc::poll(&mut pfd, 1, 100) };
-        if pollresult == -1 {
-            let err: c_int = unsafe { *libc::__errno_location() };
-            if err == libc::EINTR {
-                continue;
-            }
-            // According to linux man apge, EAGAIN sometimes can happen on other POSIXes and
-            // portable applications probably should treat it like EINTR.
-            // It happens if Kernel is out resources.
-            if err == libc::EAGAIN {
-                continue;
-            }
-            // In any other condition...well we are in a bad state and no easy way to recover so
-            // just panic.
-            panic!(format!(
-                "Unexpectedly received unrecoverable error from poll() syscall: {}",
-                err
-            ));
-        }
-        // timeout
-        if pollresult == 0 {
-            continue;
-        }
-        // pollresult should only be 1.
-        // Check for strange results
-        if pollresult != 1 {
-            panic!(format!("Unexpectedly received return value {} from poll() syscall. I only expect 1 for successful poll() call.", pollresult));
+impl<M: MMapHandler + Send + Sync> BudgetTarget for MMapFaultTarget<M> {
+    fn evict_for_budget(&self, pages: BTreeSet<usize>) {
+        self.mmap_state.budget_evicted(&pages);
+        if let Some(_alive) = self.shared.lock_alive() {
+            evict_handler_pages(&self.shared, &self.mmap_state, pages);
         }
+    }
+}
 
-        // Check that this is a type of poll event we care about.
-        if ((pfd.revents | libc::POLLIN) == 0) && ((pfd.revents | libc::POLLERR) == 0) {
-            continue;
+impl<M: MMapHandler + Send + Sync> MMapFaultTarget<M> {
+    // Starts serving a forked child's copy of the mapping. Runs on the reactor thread, so this
+    // can't wait for anything a fault job might be holding.
+    fn fork(&self, child_ufd: c_int) {
+        let shared = Arc::new(MMapShared {
+            ufd: child_ufd,
+            family: self.shared.family.clone(),
+            sz: self.shared.sz,
+            page_size: self.shared.page_size,
+            options: self.shared.options.clone(),
+            forked: true,
+            segments: Mutex::new(self.shared.segments.lock().unwrap().clone()),
+            dirty: RwLock::new(BTreeSet::new()),
+            pinned: RwLock::new(BTreeSet::new()),
+            streams: Mutex::new(vec![]),
+            budget_id: self.shared.budget_id,
+            sampled: Mutex::new(BTreeMap::new()),
+            last_sample: Mutex::new(Instant::now()),
+        });
+        let target = Arc::new(MMapFaultTarget {
+            shared,
+            mmap_state: self.mmap_state.clone(),
+        });
+        match reactor::register(target) {
+            Ok(()) => self.shared.family.forks.lock().unwrap().push(child_ufd),
+            Err(err) => error!(
+                "cannot serve mapping in forked child, it will read as zeroes. {}",
+                err
+            ),
         }
+    }
+}
 
-        loop {
-            let userfault_msg_ptr: *mut uffd_msg = &mut userfault_msg;
-            let nread = unsafe {
-                libc::read(
-                    ufd,
-                    userfault_msg_ptr as *mut c_void,
-                    USERFAULT_MSG_SZ as usize,
-                )
+fn pagefault_handle<M: MMapHandler + Send + Sync>(
+    msg: uffd_msg,
+    mmap_state: &M,
+    shared: &Arc<MMapShared>,
+) {
+    let ufd = shared.ufd;
+    let error_policy = shared.options.error_policy;
+    let writable = shared.options.mode != MMapMode::ReadOnly;
+    let page_size = shared.page_size;
+    let offset_ptr = round_down_to(msg.address as usize, page_size as usize) as u64;
+    let offset = shared.segments.lock().unwrap().offset_of(offset_ptr);
+
+    // Write to a page that is already there. Remember it as dirty and let the write through.
+    if msg.flags & UFFD_PAGEFAULT_FLAG_WP != 0 {
+        {
+            let _alive = match shared.lock_alive() {
+                Some(alive) => alive,
+                None => return,
             };
-            if nread == -1 {
-                let err: c_int = unsafe { *libc::__errno_location() };
-                if err == libc::EINTR {
-                    continue;
-                }
-                panic!(format!("Unexpected result from read() syscall {}", err));
-            }
-            if nread == 0 {
-                panic!(format!("Unexpected EOF from userfaultfd."));
-            }
-            if nread != USERFAULT_MSG_SZ {
-                panic!(format!(
-                    "Unexpected read size from read() syscall, expected {} bytes, got {} bytes.",
-                    USERFAULT_MSG_SZ, nread
-                ));
+            let mut dirty = shared.dirty.write().unwrap();
+            if let Some(offset) = offset {
+                dirty.insert((offset / page_size) as usize);
             }
-            break;
+            write_protect(ufd, offset_ptr, page_size, false);
         }
-
-        // Check that the userfault message is what we expect
-        if userfault_msg.event != UFFD_EVENT_PAGEFAULT {
-            panic!(format!(
-                "Unexpected userfaultfd event type, we only expect UFFD_EVENT_PAGEFAULT"
-            ));
+        // Writes in forked children are to their own copies of the pages.
+        if let (false, Some(offset)) = (shared.forked, offset) {
+            accessed(shared, mmap_state, offset, page_size);
         }
+        return;
+    }
 
-        // If we are at this point, we have successfully received a request to fill in some page.
-        // We send the request to our thread pool to deal with.
-        let mmap_state_cloned = mmap_state.clone();
-        scope.spawn(move |_scope| pagefault_handle(ufd, userfault_msg, mmap_state_cloned, ptr_u64));
+    // The page is in the page cache already, put there by another mapping or taken out of the
+    // mapping by sample_accesses(). Just map it.
+    if msg.flags & UFFD_PAGEFAULT_FLAG_MINOR != 0 {
+        let mut sampled = false;
+        if let Some(_alive) = shared.lock_alive() {
+            continue_pages(ufd, offset_ptr, page_size, page_size);
+            if let Some(offset) = offset {
+                record_loaded(shared, offset, page_size);
+                let page = (offset / page_size) as usize;
+                sampled = shared.sampled.lock().unwrap().remove(&page).is_some();
+            }
+        }
+        if let (true, Some(offset)) = (sampled, offset) {
+            accessed(shared, mmap_state, offset, page_size);
+        }
+        return;
     }
-}
 
-fn pagefault_handle<M: MMapHandler + Send>(ufd: c_int, msg: uffd_msg, mmap_state: M, ptr_u64: u64) {
-    let offset_ptr = round_down_to_pagesize(msg.address as usize) as u64;
-    let offset = offset_ptr - ptr_u64;
-    let (pages, evictions) = mmap_state.handle_userfault(offset).unwrap();
+    let offset = match offset {
+        Some(offset) => offset,
+        None => {
+            // Registered with us but not part of the mapping; mremap() grew it. There is nothing
+            // to put there but zeroes. It's not in the page cache either, so this can't fail.
+            if let Some(_alive) = shared.lock_alive() {
+                let _ = zero_fill(shared, offset_ptr, page_size);
+            }
+            return;
+        }
+    };
 
-    let mut uffdio_copy = uffdio_copy::new();
-    for page in pages {
-        loop {
-            uffdio_copy.src = page.vehicle_page as u64;
-            uffdio_copy.dst = offset_ptr;
-            uffdio_copy.len = page.mmapped_size;
-            uffdio_copy.mode = 0;
-            uffdio_copy.copy = 0;
-            if unsafe { libc::ioctl(ufd, UFFDIO_COPY as u64, &uffdio_copy) } == -1 {
+    // The page was taken out by sample_accesses() and is being used after all. Put it back, write
+    // protected again if the mapping is writable so that writes to it are still noticed.
+    {
+        let _alive = match shared.lock_alive() {
+            Some(alive) => alive,
+            None => return,
+        };
+        let mut sampled = shared.sampled.lock().unwrap();
+        if let Some(Some(contents)) = sampled.remove(&((offset / page_size) as usize)) {
+            let mut page = MMapPages::with_page_size(page_size, page_size as usize);
+            page.as_mut_slice().copy_from_slice(&contents);
+            copy_pages(
+                ufd,
+                page.vehicle_page as u64,
+                offset_ptr,
+                page_size,
+                writable,
+                page_size,
+            );
+            drop(sampled);
+            drop(_alive);
+            accessed(shared, mmap_state, offset, page_size);
+            return;
+        }
+    }
+
+    // The page is on its way. Copying it in wakes us up.
+    {
+        let streams = shared.streams.lock().unwrap();
+        if streams
+            .iter()
+            .any(|(start, end)| offset >= *start && offset < *end)
+        {
+            return;
+        }
+    }
+
+    let request = FaultRequest {
+        offset,
+        address: msg.address,
+        write: msg.flags & UFFD_PAGEFAULT_FLAG_WRITE != 0,
+        // The thread id is in the low 32 bits of what follows the address.
+        thread_id: msg.padding as u32,
+    };
+
+    let mut attempt: u32 = 0;
+    loop {
+        // The mapping may have been dropped while this job was queued or backing off.
+        if shared.lock_alive().is_none() {
+            return;
+        }
+        let failure = match mmap_state.handle_userfault(&request) {
+            Ok(mut resolution) => {
+                // Get the read-ahead going first so that it's already under way when the faulting
+                // thread gets there.
+                let readahead = mem::replace(&mut resolution.readahead, vec![]);
+                schedule_readahead(shared, mmap_state, readahead);
+                match deliver(shared, mmap_state, resolution, offset, writable) {
+                    Ok(()) => {
+                        sample_accesses(shared);
+                        return;
+                    }
+                    Err(failure) => failure,
+                }
+            }
+            Err(failure) => InstallFailure::Handler(failure),
+        };
+        match error_policy {
+            ErrorPolicy::Retry(retries) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "userfault at offset {} failed ({:?}), retrying ({}/{}).",
+                    offset, failure, attempt, retries
+                );
+                sleep(Duration::from_millis(RETRY_BACKOFF_MS * attempt as u64));
+            }
+            ErrorPolicy::ZeroFill => {
+                warn!(
+                    "userfault at offset {} failed ({:?}), filling page with zeroes.",
+                    offset, failure
+                );
+                if let Some(_alive) = shared.lock_alive() {
+                    if let Err(errno) = zero_fill(shared, offset_ptr, page_size) {
+                        warn!(
+                            "cannot fill page at offset {} with zeroes either (errno {}), raising SIGBUS.",
+                            offset, errno
+                        );
+                        resolve_with_poison(ufd, offset_ptr, page_size);
+                    }
+                }
+                return;
+            }
+            ErrorPolicy::SigBus => {
+                warn!(
+                    "userfault at offset {} failed ({:?}), raising SIGBUS.",
+                    offset, failure
+                );
+                if let Some(_alive) = shared.lock_alive() {
+                    resolve_with_poison(ufd, offset_ptr, page_size);
+                }
+                return;
+            }
+            _ => panic!(
+                "Unrecoverable failure while handling userfault at offset {}: {:?}",
+                offset, failure
+            ),
+        }
+    }
+}
+
+// Copies in pages as the handler produces them. Fails if the handler fails before the faulting
+// page is in. Zero ranges and evictions are done either way.
+fn deliver<M: MMapHandler>(
+    shared: &Arc<MMapShared>,
+    mmap_state: &M,
+    resolution: FaultResolution<M::PageIterator>,
+    offset: u64,
+    writable: bool,
+) -> Result<(), InstallFailure<M::Failure>> {
+    let mut stream = Stream::new(shared, offset, offset + resolution.pages_len, true);
+    let mut result = Ok(());
+    if let Some(failure) = install_stream::<M>(shared, resolution.pages, &mut stream, writable) {
+        if !stream.start_installed {
+            result = Err(failure);
+        } else {
+            // Whatever didn't make it is faulted in again when it's needed.
+            debug!(
+                "read-ahead at offset {} stopped after {} bytes ({:?}).",
+                offset, stream.installed, failure
+            );
+        }
+    }
+    let start_installed = stream.start_installed;
+    drop(stream);
+
+    let _alive = match shared.lock_alive() {
+        Some(alive) => alive,
+        None => return Ok(()),
+    };
+    for (zero_offset, zero_len) in resolution.zero_ranges {
+        let pieces = shared
+            .segments
+            .lock()
+            .unwrap()
+            .addresses_of(zero_offset, zero_len);
+        for (address, _, len) in pieces {
+            if let Err(errno) = zero_fill(shared, address, len) {
+                // Only the faulting page has somebody waiting; the rest faults again when it's
+                // needed.
+                let faulting = offset >= zero_offset && offset < zero_offset + zero_len;
+                if faulting && result.is_ok() && !start_installed {
+                    result = Err(InstallFailure::PageCache(errno));
+                }
+            }
+        }
+    }
+
+    // No faulting page means the handler waited for somebody else to bring it in. Wake up the
+    // faulting thread; if the page still isn't there it faults again.
+    if result.is_ok() && !start_installed {
+        let pieces = shared
+            .segments
+            .lock()
+            .unwrap()
+            .addresses_of(offset, shared.page_size);
+        for (address, _, len) in pieces {
+            wake(shared.ufd, address, len);
+        }
+    }
+
+    if !shared.forked {
+        evict_handler_pages(shared, mmap_state, resolution.evictions);
+    }
+    result
+}
+
+// Hands read-ahead ranges to MMapHandler::populate() on the worker pool. They count as being
+// streamed in from here on, so faults on them wait for them instead of fetching the same pages.
+fn schedule_readahead<M: MMapHandler + Send + Sync>(
+    shared: &Arc<MMapShared>,
+    mmap_state: &M,
+    readahead: Vec<(u64, u64)>,
+) {
+    if !M::supports_populate() {
+        return;
+    }
+    for (offset, len) in readahead {
+        let stream = Stream::new(shared, offset, offset + len, false);
+        let shared = shared.clone();
+        let mmap_state = mmap_state.clone();
+        reactor::spawn(move || populate_handle(&shared, mmap_state, stream));
+    }
+}
+
+// Brings in read-ahead pages in the background. Nobody is waiting for these (yet), so if anything
+// goes wrong they are left to be faulted in.
+fn populate_handle<M: MMapHandler>(shared: &Arc<MMapShared>, mmap_state: M, mut stream: Stream) {
+    if let Err(failure) = populate_stream(shared, &mmap_state, &mut stream) {
+        debug!(
+            "read-ahead at offset {} stopped after {} bytes ({:?}).",
+            stream.start, stream.installed, failure
+        );
+    }
+}
+
+// Asks the handler for the pages of a stream and copies them in.
+fn populate_stream<M: MMapHandler>(
+    shared: &Arc<MMapShared>,
+    mmap_state: &M,
+    stream: &mut Stream,
+) -> Result<(), InstallFailure<M::Failure>> {
+    if shared.lock_alive().is_none() {
+        return Ok(());
+    }
+    let writable = shared.options.mode != MMapMode::ReadOnly;
+    let pages = mmap_state
+        .populate(stream.start, stream.end - stream.start)
+        .map_err(InstallFailure::Handler)?;
+    match install_stream::<M>(shared, pages, stream, writable) {
+        Some(failure) => Err(failure),
+        None => Ok(()),
+    }
+}
+
+// Why pages could not be put into a mapping.
+#[derive(Debug)]
+enum InstallFailure<F> {
+    Handler(F),
+    // Writing to the page cache failed with this errno.
+    PageCache(c_int),
+}
+
+// Copies in page runs as the handler hands them over. Stops at the first failure and returns it,
+// or when the mapping goes away.
+fn install_stream<M: MMapHandler>(
+    shared: &MMapShared,
+    pages: M::PageIterator,
+    stream: &mut Stream,
+    writable: bool,
+) -> Option<InstallFailure<M::Failure>> {
+    for run in pages {
+        let run = match run {
+            Ok(run) => run,
+            Err(failure) => return Some(InstallFailure::Handler(failure)),
+        };
+        // Handlers can take a long time. If the mapping went away in the meantime the rest is
+        // thrown away.
+        {
+            let _alive = shared.lock_alive()?;
+            if let Err(errno) = install_pages(shared, &run.pages, run.offset, writable) {
+                return Some(InstallFailure::PageCache(errno));
+            }
+            stream.record(run.offset, run.pages.mmapped_size);
+        }
+        // This may evict pages of any mapping, this one included, so it can't be done while
+        // holding on to the mapping.
+        budget::enforce();
+    }
+    None
+}
+
+// Keeps a range in MMapShared::streams while pages are being streamed in.
+struct Stream {
+    shared: Arc<MMapShared>,
+    start: u64,
+    end: u64,
+    // How much of [start, end) has been installed, and whether the first page is in. Runs are
+    // expected not to overlap.
+    installed: u64,
+    start_installed: bool,
+    // Whether there is a faulting thread waiting for the first page, which it's up to the fault
+    // handling to take care of.
+    fault: bool,
+}
+
+impl Stream {
+    fn new(shared: &Arc<MMapShared>, start: u64, end: u64, fault: bool) -> Self {
+        if start < end {
+            shared.streams.lock().unwrap().push((start, end));
+        }
+        Stream {
+            shared: shared.clone(),
+            start,
+            end,
+            installed: 0,
+            start_installed: false,
+            fault,
+        }
+    }
+
+    // Records that [offset, offset+len) has been copied in.
+    fn record(&mut self, offset: u64, len: u64) {
+        let from = cmp::max(offset, self.start);
+        let to = cmp::min(offset + len, self.end);
+        if from < to {
+            self.installed += to - from;
+        }
+        if offset <= self.start && self.start < offset + len {
+            self.start_installed = true;
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.start >= self.end {
+            return;
+        }
+        {
+            let mut streams = self.shared.streams.lock().unwrap();
+            if let Some(idx) = streams
+                .iter()
+                .position(|range| *range == (self.start, self.end))
+            {
+                streams.remove(idx);
+            }
+        }
+        if self.installed >= self.end - self.start {
+            return;
+        }
+        // The stream stopped early or left gaps. Whoever was waiting for the rest has to fault
+        // again; waking up pages that are already there does no harm. The faulting page itself is
+        // left alone; the error policy takes care of that.
+        let from = if self.fault {
+            self.start + self.shared.page_size
+        } else {
+            self.start
+        };
+        if from < self.end {
+            let pieces = self
+                .shared
+                .segments
+                .lock()
+                .unwrap()
+                .addresses_of(from, self.end - from);
+            for (address, _, len) in pieces {
+                wake(self.shared.ufd, address, len);
+            }
+        }
+    }
+}
+
+// Copies pages holding the contents of the mapping from `offset` on to wherever that part of the
+// mapping is. With a page cache they are written to the cache and mapped from there; that can fail
+// with an errno, and then nothing is put in.
+fn install_pages(
+    shared: &MMapShared,
+    page: &MMapPages,
+    offset: u64,
+    writable: bool,
+) -> Result<(), c_int> {
+    forget_samples(shared, offset, page.mmapped_size);
+    if let Some(ref cache) = shared.options.page_cache {
+        let contents = unsafe {
+            slice::from_raw_parts(page.vehicle_page as *const u8, page.mmapped_size as usize)
+        };
+        cache.write(offset, contents)?;
+        let pieces = shared
+            .segments
+            .lock()
+            .unwrap()
+            .addresses_of(offset, page.mmapped_size);
+        for (address, _, len) in pieces {
+            continue_pages(shared.ufd, address, len, shared.page_size);
+        }
+        record_loaded(shared, offset, page.mmapped_size);
+        return Ok(());
+    }
+    let pieces = shared
+        .segments
+        .lock()
+        .unwrap()
+        .addresses_of(offset, page.mmapped_size);
+    for (address, piece_offset, len) in pieces {
+        let src = page.vehicle_page as u64 + (piece_offset - offset);
+        copy_pages(shared.ufd, src, address, len, writable, shared.page_size);
+    }
+    record_loaded(shared, offset, page.mmapped_size);
+    Ok(())
+}
+
+// Tells the memory budget about pages that have been put into [offset, offset+len) of a mapping.
+fn record_loaded(shared: &MMapShared, offset: u64, len: u64) {
+    if shared.forked {
+        return;
+    }
+    let page_size = shared.page_size;
+    let start_page = offset / page_size;
+    let end_page = (offset + len + page_size - 1) / page_size;
+    budget::loaded(shared.budget_id, start_page as usize, end_page as usize);
+}
+
+// Evicts pages the handler asked to evict and tells it about the ones that had to stay, so that it
+// doesn't lose track of them. They go to the back of the memory budget's line too.
+fn evict_handler_pages<M: MMapHandler>(
+    shared: &MMapShared,
+    mmap_state: &M,
+    evictions: BTreeSet<usize>,
+) {
+    let kept = evict_pages(shared, evictions);
+    let page_size = shared.page_size;
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for page in kept {
+        match runs.last_mut() {
+            Some(last) if last.1 == page => last.1 += 1,
+            _ => runs.push((page, page + 1)),
+        }
+    }
+    for (start, end) in runs {
+        if !shared.forked {
+            budget::touched(shared.budget_id, start, end);
+        }
+        mmap_state.kept(start as u64 * page_size, (end - start) as u64 * page_size);
+    }
+}
+
+// Returns the pages that could not be evicted.
+fn evict_pages(shared: &MMapShared, evictions: BTreeSet<usize>) -> BTreeSet<usize> {
+    // Sampled pages that are evicted were not used. Holding the lock keeps sample_accesses() from
+    // copying pages while they go.
+    let mut sampled = shared.sampled.lock().unwrap();
+    // Dirty pages only exist in our memory so they cannot be evicted. Holding the lock makes sure
+    // nothing gets dirtied while we are evicting. Pinned pages stay too.
+    let dirty = shared.dirty.read().unwrap();
+    let pinned = shared.pinned.read().unwrap();
+    let (kept, evictions): (BTreeSet<usize>, BTreeSet<usize>) = evictions
+        .into_iter()
+        .partition(|page| dirty.contains(page) || pinned.contains(page));
+    for page in evictions.iter() {
+        sampled.remove(page);
+    }
+
+    // Each madvise() is a round trip through the reactor (UFFD_EVENT_REMOVE) so do contiguous
+    // pages together.
+    for (start, end) in pages_to_ranges(&evictions, shared.sz as usize, shared.page_size as usize) {
+        let pieces = shared
+            .segments
+            .lock()
+            .unwrap()
+            .addresses_of(start as u64, (end - start) as u64);
+        for (address, _, len) in pieces {
+            let ret =
+                unsafe { libc::madvise(address as *mut c_void, len as size_t, libc::MADV_DONTNEED) };
+            if ret == -1 {
                 let err: c_int = unsafe { *libc::__errno_location() };
-                if err == libc::EAGAIN {
+                // Unmapped under us.
+                if err == libc::ENOMEM {
                     continue;
                 }
-                // EEXIST isn't even documented as possible return from UFFDIO_COPY.
-                //
-                // I think you get this when the pages are already loaded. Seems like tests pass (all
-                // data is there properly) even when you get this so I'm hoping really hard it's fine
-                // to ignore EEXIST.
-                if err == libc::EEXIST {
-                    break;
-                }
                 panic!(format!(
-                    "Unexpected error from ioctl() syscall while copying page with userfaultfd. {}",
+                    "Unexpected error from madvise() with MADV_DONTNEED. {}",
                     err
                 ));
             }
         }
     }
+    if !shared.forked {
+        budget::evicted(shared.budget_id, &evictions);
+    }
+    kept
+}
 
-    for eviction_page in evictions.into_iter() {
-        let evict_offset = eviction_page * *PAGESIZE_USIZE + ptr_u64 as usize;
+// Takes a few loaded pages out of the mapping to find out whether they are still being used, at
+// most once every ACCESS_SAMPLE_INTERVAL_MS. This is done when a fault has been handled; a mapping
+// that doesn't fault doesn't load anything and has no reason to evict.
+//
+// How a page is taken out depends on the mapping:
+//
+//   - With a page cache the page is unmapped and stays in the cache. Touching it is a minor fault.
+//   - Otherwise a copy of the page is kept and the page is unmapped. Touching it is a fault that
+//     copies it back. This costs a page of memory for each sampled page, up to
+//     access_sample_size pages, and doesn't depend on swap or anything else of the kernel's.
+//   - Modified pages of writable mappings only exist in our memory and are not taken out. They
+//     are write-protected again instead, so only writes to them are noticed. (Pages that haven't
+//     been modified are write-protected anyway, and are taken out like the others.)
+//
+// Pages that are touched are reported to the handler with MMapHandler::accessed(). Pages are
+// picked at random from what the memory budget counts as loaded, so sampled pages still count.
+fn sample_accesses(shared: &MMapShared) {
+    let count = shared.options.access_sample_size;
+    if count == 0 || shared.forked {
+        return;
+    }
+    {
+        let mut last_sample = shared.last_sample.lock().unwrap();
+        if last_sample.elapsed() < Duration::from_millis(ACCESS_SAMPLE_INTERVAL_MS) {
+            return;
+        }
+        *last_sample = Instant::now();
+    }
+
+    let _alive = match shared.lock_alive() {
+        Some(alive) => alive,
+        None => return,
+    };
+    let page_size = shared.page_size;
+    // Same order as evict_pages(). Holding the dirty lock keeps pages from being modified while
+    // they are copied.
+    let mut sampled = shared.sampled.lock().unwrap();
+    let dirty = shared.dirty.read().unwrap();
+    let pinned = shared.pinned.read().unwrap();
+    for page in budget::sample(shared.budget_id, count) {
+        if pinned.contains(&page) || sampled.contains_key(&page) {
+            continue;
+        }
+        // Pages split up by mremap() are left alone.
+        let address = match page_address(shared, page) {
+            Some(address) => address,
+            None => continue,
+        };
+        if dirty.contains(&page) {
+            write_protect(shared.ufd, address, page_size, true);
+            continue;
+        }
+        let contents = if shared.options.page_cache.is_some() {
+            None
+        } else {
+            // Reading a page that isn't there would fault, and the fault would wait for the lock
+            // we are holding. Evictions wait for it too, so only the application can take the
+            // page away between here and the copy.
+            if resident(address) != Some(true) {
+                continue;
+            }
+            let contents =
+                unsafe { slice::from_raw_parts(address as *const u8, page_size as usize) };
+            Some(contents.to_vec())
+        };
         let ret = unsafe {
             libc::madvise(
-                evict_offset as *mut c_void,
-                *PAGESIZE_USIZE,
+                address as *mut c_void,
+                page_size as size_t,
                 libc::MADV_DONTNEED,
             )
         };
-        if ret == -1 {
+        if ret == 0 {
+            sampled.insert(page, contents);
+        }
+    }
+}
+
+// Tells the handler and the memory budget that [offset, offset+len) of the mapping has been used.
+fn accessed<M: MMapHandler>(shared: &MMapShared, mmap_state: &M, offset: u64, len: u64) {
+    if !shared.forked {
+        let start_page = (offset / shared.page_size) as usize;
+        let end_page = ((offset + len + shared.page_size - 1) / shared.page_size) as usize;
+        budget::touched(shared.budget_id, start_page, end_page);
+    }
+    mmap_state.accessed(offset, len);
+}
+
+// Where a page of the mapping is, if it is in one piece (mremap() can split pages up).
+fn page_address(shared: &MMapShared, page: usize) -> Option<u64> {
+    let page_size = shared.page_size;
+    let pieces = shared
+        .segments
+        .lock()
+        .unwrap()
+        .addresses_of(page as u64 * page_size, page_size);
+    match pieces.as_slice() {
+        [(address, _, len)] if *len == page_size => Some(*address),
+        _ => None,
+    }
+}
+
+// Whether the page at `address` is in memory, or None if it isn't mapped. A huge page is there or
+// not as a whole so looking at its first normal page is enough.
+fn resident(address: u64) -> Option<bool> {
+    let mut resident: u8 = 0;
+    let ret = unsafe {
+        libc::mincore(
+            address as *mut c_void,
+            *PAGESIZE_U64 as size_t,
+            &mut resident,
+        )
+    };
+    if ret == -1 {
+        return None;
+    }
+    Some(resident & 1 != 0)
+}
+
+// Pages put in where sample_accesses() took pages out replace them.
+fn forget_samples(shared: &MMapShared, offset: u64, len: u64) {
+    let mut sampled = shared.sampled.lock().unwrap();
+    if sampled.is_empty() {
+        return;
+    }
+    let page_size = shared.page_size;
+    let start_page = (offset / page_size) as usize;
+    let end_page = ((offset + len + page_size - 1) / page_size) as usize;
+    let pages: Vec<usize> = sampled
+        .range(start_page..end_page)
+        .map(|(page, _)| *page)
+        .collect();
+    for page in pages {
+        sampled.remove(&page);
+    }
+}
+
+fn copy_pages(ufd: c_int, src: u64, dst: u64, len: u64, write_protect: bool, page_size: u64) {
+    let mut uffdio_copy = uffdio_copy::new();
+    uffdio_copy.src = src;
+    uffdio_copy.dst = dst;
+    uffdio_copy.len = len;
+    uffdio_copy.mode = if write_protect { UFFDIO_COPY_MODE_WP } else { 0 };
+    loop {
+        uffdio_copy.copy = 0;
+        if unsafe { libc::ioctl(ufd, UFFDIO_COPY as u64, &uffdio_copy) } == -1 {
             let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN {
+                // Part of the range may have been copied; carry on from where the kernel stopped.
+                if uffdio_copy.copy > 0 {
+                    let copied = uffdio_copy.copy as u64;
+                    uffdio_copy.src += copied;
+                    uffdio_copy.dst += copied;
+                    uffdio_copy.len -= copied;
+                }
+                continue;
+            }
+            // EEXIST isn't even documented as possible return from UFFDIO_COPY.
+            //
+            // I think you get this when the pages are already loaded. Seems like tests pass (all
+            // data is there properly) even when you get this so I'm hoping really hard it's fine
+            // to ignore EEXIST.
+            //
+            // The kernel stops at the first page that is there, so skip over it and carry on with
+            // the rest of the range.
+            if err == libc::EEXIST {
+                if uffdio_copy.len > page_size {
+                    uffdio_copy.src += page_size;
+                    uffdio_copy.dst += page_size;
+                    uffdio_copy.len -= page_size;
+                    continue;
+                }
+                break;
+            }
+            // The range is not (all) registered with us anymore; it was moved or unmapped under
+            // us, or mprotect() split it into several areas. Do what can still be done page by
+            // page and wake up whoever is waiting for the rest so they can fault again.
+            if err == libc::ENOENT {
+                if uffdio_copy.len > page_size {
+                    let mut page = 0;
+                    while page < uffdio_copy.len {
+                        copy_pages(
+                            ufd,
+                            uffdio_copy.src + page,
+                            uffdio_copy.dst + page,
+                            page_size,
+                            write_protect,
+                            page_size,
+                        );
+                        page += page_size;
+                    }
+                } else {
+                    wake(ufd, uffdio_copy.dst, uffdio_copy.len);
+                }
+                break;
+            }
+            // The process is gone. This happens with forked children.
+            if err == libc::ESRCH {
+                break;
+            }
+            if err == libc::ENOMEM && page_size != *PAGESIZE_U64 {
+                panic!("Ran out of huge pages while copying page with userfaultfd. Set aside more huge pages (vm.nr_hugepages) or have the handler keep fewer pages loaded.");
+            }
             panic!(format!(
-                "Unexpected error from madvise() with MADV_FREE. {}",
+                "Unexpected error from ioctl() syscall while copying page with userfaultfd. {}",
                 err
             ));
         }
+        break;
     }
 }
 
-impl<M> MMap<M> {
+// Maps pages that are in the page cache into a range of the mapping.
+fn continue_pages(ufd: c_int, start: u64, len: u64, page_size: u64) {
+    let mut cont = uffdio_continue {
+        range: uffdio_range { start, len },
+        mode: 0,
+        mapped: 0,
+    };
+    loop {
+        if unsafe { libc::ioctl(ufd, UFFDIO_CONTINUE as u64, &mut cont) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN {
+                // Part of the range may have been mapped; carry on from where the kernel stopped.
+                if cont.mapped > 0 {
+                    cont.range.start += cont.mapped as u64;
+                    cont.range.len -= cont.mapped as u64;
+                }
+                cont.mapped = 0;
+                continue;
+            }
+            // Already mapped. The kernel stops at the first such page so go through the rest one
+            // page at a time.
+            if err == libc::EEXIST {
+                if cont.range.len > page_size {
+                    let mut page_start = cont.range.start;
+                    while page_start < cont.range.start + cont.range.len {
+                        continue_pages(ufd, page_start, page_size, page_size);
+                        page_start += page_size;
+                    }
+                }
+                break;
+            }
+            // Moved or unmapped under us (ENOENT) or evicted from the cache in the meantime
+            // (EFAULT). Whoever is waiting faults again.
+            if err == libc::ENOENT || err == libc::EFAULT {
+                wake(ufd, cont.range.start, cont.range.len);
+                break;
+            }
+            if err == libc::ESRCH {
+                break;
+            }
+            panic!(
+                "Unexpected error from ioctl() syscall while mapping page from page cache with userfaultfd. {}",
+                err
+            );
+        }
+        break;
+    }
+}
+
+// Wakes up threads waiting for pages in a range without resolving anything. They fault again.
+fn wake(ufd: c_int, start: u64, len: u64) {
+    let range = uffdio_range { start, len };
+    unsafe {
+        libc::ioctl(ufd, UFFDIO_WAKE as u64, &range);
+    }
+}
+
+// Sets or clears write protection on a range. Clearing it wakes up whoever was waiting to write.
+fn write_protect(ufd: c_int, start: u64, len: u64, protect: bool) {
+    let wp = uffdio_writeprotect {
+        range: uffdio_range { start, len },
+        mode: if protect {
+            UFFDIO_WRITEPROTECT_MODE_WP
+        } else {
+            0
+        },
+    };
+    loop {
+        if unsafe { libc::ioctl(ufd, UFFDIO_WRITEPROTECT as u64, &wp) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN {
+                continue;
+            }
+            // The page got evicted (or unmapped) under us. The writer will fault it back in. ESRCH
+            // means the process is gone.
+            if err == libc::ENOENT || err == libc::ESRCH {
+                break;
+            }
+            panic!(
+                "Unexpected error from ioctl() syscall while write-protecting pages with userfaultfd. {}",
+                err
+            );
+        }
+        break;
+    }
+}
+
+// Fills a page aligned range of the mapping with zeroes. Fails with an errno if the zeroes can't be
+// written to the page cache.
+fn zero_fill(shared: &MMapShared, start: u64, len: u64) -> Result<(), c_int> {
+    let ufd = shared.ufd;
+    let writable = shared.options.mode != MMapMode::ReadOnly;
+    let page_size = shared.page_size;
+    // Zeroes go into the page cache like any other pages. The zero page could be put in the
+    // cache too, but not over a page that somebody else has put there in the meantime.
+    if let Some(ref cache) = shared.options.page_cache {
+        let offset = shared.segments.lock().unwrap().offset_of(start);
+        if let Some(offset) = offset {
+            cache.write(offset, &vec![0; len as usize])?;
+            continue_pages(ufd, start, len, page_size);
+            record_loaded(shared, offset, len);
+            return Ok(());
+        }
+    }
+    // The zero page would not tell us about writes to it, and there is no zero page for huge
+    // pages; copy in real pages instead.
+    if writable || page_size != *PAGESIZE_U64 {
+        let page = MMapPages::new(len);
+        copy_pages(
+            ufd,
+            page.vehicle_page as u64,
+            start,
+            len,
+            writable,
+            page_size,
+        );
+        let offset = shared.segments.lock().unwrap().offset_of(start);
+        if let Some(offset) = offset {
+            record_loaded(shared, offset, len);
+        }
+    } else {
+        resolve_with_zeropage(ufd, start, len);
+    }
+    Ok(())
+}
+
+// Maps the zero page over a range.
+fn resolve_with_zeropage(ufd: c_int, start: u64, len: u64) {
+    let mut zeropage = uffdio_zeropage {
+        range: uffdio_range { start, len },
+        mode: 0,
+        zeropage: 0,
+    };
+    loop {
+        if unsafe { libc::ioctl(ufd, UFFDIO_ZEROPAGE as u64, &mut zeropage) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN {
+                // Part of the range may have been filled; carry on from where the kernel stopped.
+                if zeropage.zeropage > 0 {
+                    zeropage.range.start += zeropage.zeropage as u64;
+                    zeropage.range.len -= zeropage.zeropage as u64;
+                }
+                zeropage.zeropage = 0;
+                continue;
+            }
+            // Someone else filled in a page in the meantime. The kernel stops at the first such
+            // page so go through the rest one page at a time.
+            if err == libc::EEXIST {
+                if zeropage.range.len > *PAGESIZE_U64 {
+                    let mut page_start = zeropage.range.start;
+                    while page_start < zeropage.range.start + zeropage.range.len {
+                        resolve_with_zeropage(ufd, page_start, *PAGESIZE_U64);
+                        page_start += *PAGESIZE_U64;
+                    }
+                }
+                break;
+            }
+            // Moved or unmapped under us, or the process is gone.
+            if err == libc::ENOENT {
+                wake(ufd, zeropage.range.start, zeropage.range.len);
+                break;
+            }
+            if err == libc::ESRCH {
+                break;
+            }
+            panic!(
+                "Unexpected error from ioctl() syscall while zero-filling page with userfaultfd. {}",
+                err
+            );
+        }
+        break;
+    }
+}
+
+// Resolves a fault by poisoning the faulting page. The faulting thread (and anyone else touching
+// the page later) gets SIGBUS.
+fn resolve_with_poison(ufd: c_int, offset_ptr: u64, page_size: u64) {
+    let mut poison = uffdio_poison {
+        range: uffdio_range {
+            start: offset_ptr,
+            len: page_size,
+        },
+        mode: 0,
+        updated: 0,
+    };
+    loop {
+        if unsafe { libc::ioctl(ufd, UFFDIO_POISON as u64, &mut poison) } == -1 {
+            let err: c_int = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN {
+                continue;
+            }
+            if err == libc::EEXIST || err == libc::ESRCH {
+                break;
+            }
+            if err == libc::ENOENT {
+                wake(ufd, offset_ptr, page_size);
+                break;
+            }
+            panic!(
+                "Unexpected error from ioctl() syscall while poisoning page with userfaultfd. {}",
+                err
+            );
+        }
+        break;
+    }
+}
+
+impl<M: MMapHandler> MMap<M> {
+    // Writes modified pages back through the handler. Does nothing for read-only mappings or if
+    // nothing has been modified since the last flush. Pages written to while the flush is running
+    // will be picked up by the next one.
+    pub fn flush(&self) -> Result<(), M::Failure> {
+        if self.shared.options.mode != MMapMode::WriteBack {
+            return Ok(());
+        }
+        let _flushing = self.flush_lock.lock().unwrap();
+
+        // Take the dirty set and write-protect the pages again while holding the lock. Anything
+        // written from this point on faults and becomes dirty again.
+        let flushed_pages: BTreeSet<usize> = {
+            let segments = self.shared.segments.lock().unwrap().clone();
+            let mut dirty = self.shared.dirty.write().unwrap();
+            let page_size = self.shared.page_size;
+            for page in dirty.iter() {
+                let page_offset = *page as u64 * page_size;
+                for (address, _, len) in segments.addresses_of(page_offset, page_size) {
+                    write_protect(self.shared.ufd, address, len, true);
+                }
+            }
+            mem::replace(&mut *dirty, BTreeSet::new())
+        };
+        if flushed_pages.is_empty() {
+            return Ok(());
+        }
+
+        let ranges = pages_to_ranges(&flushed_pages, self.sz_unrounded, self.page_size());
+        match self.mmap_state.write_back(self.as_slice(), &ranges) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                // Still dirty, try again next time.
+                let mut dirty = self.shared.dirty.write().unwrap();
+                dirty.extend(flushed_pages.into_iter());
+                Err(err)
+            }
+        }
+    }
+
+    // Returns the pages that have been modified as (start, end) byte ranges. For
+    // MMapMode::WriteBack this is what has been modified since the last flush, for
+    // MMapMode::Private everything that has been modified since the mapping was made.
+    pub fn dirty_ranges(&self) -> Vec<(usize, usize)> {
+        let dirty = self.shared.dirty.read().unwrap();
+        pages_to_ranges(&dirty, self.sz_unrounded, self.page_size())
+    }
+
+    // Where the mapping is. This follows the mapping if it's moved with mremap(). A mapping that
+    // has been split up or partially unmapped is no longer in one place and this panics.
     pub fn as_ptr<T>(&self) -> *const T {
-        self.ptr_u64 as *const T
+        match self.shared.segments.lock().unwrap().base(self.sz as u64) {
+            Some(base) => base as *const T,
+            None => panic!("MMap::as_ptr called on a mapping that has been split up or partially unmapped."),
+        }
     }
 
     pub fn as_slice<T>(&self) -> &[T] {
@@ -419,10 +1969,394 @@ impl<M> MMap<M> {
         if rem != 0 {
             panic!(format!("MMap::as_slice called with type parameter that does not evenly divide the file size. File size is {}, type size is {}, remainder is {}.", self.sz_unrounded, typesize, rem));
         }
-        unsafe { slice::from_raw_parts(self.ptr_u64 as *const T, self.sz_unrounded / typesize) }
+        unsafe { slice::from_raw_parts(self.as_ptr::<T>(), self.sz_unrounded / typesize) }
     }
 
     pub fn len(&self) -> usize {
         self.sz_unrounded
     }
+
+    // Size of the pages the mapping is made of, see MMapOptions::huge_pages.
+    pub fn page_size(&self) -> usize {
+        self.shared.page_size as usize
+    }
+
+    // How many bytes of the mapping are loaded, as counted against the memory budget (see
+    // set_memory_budget()). Pages that have been zero-filled with the zero page take no memory and
+    // are not counted.
+    pub fn resident_size(&self) -> usize {
+        budget::usage(self.shared.budget_id)
+    }
+}
+
+impl<M: MMapHandler + Send + Sync> MMap<M> {
+    // Tells the handler how a range of the mapping is going to be used, like madvise(). The range
+    // is in bytes from the start of the mapping and is widened to whole pages. This does not wait
+    // for anything; Advice::WillNeed only gets the pages coming.
+    pub fn advise(&self, range: Range<usize>, advice: Advice) {
+        // Pages are served from the process that made the mapping.
+        if unsafe { libc::getpid() } != self.pid {
+            return;
+        }
+        let (start, end) = match self.page_range(&range) {
+            Some(pages) => pages,
+            None => return,
+        };
+
+        let page_size = self.page_size();
+        let mut resolution = self.mmap_state.advise(
+            (start * page_size) as u64,
+            ((end - start) * page_size) as u64,
+            advice,
+        );
+        if advice == Advice::DontNeed {
+            resolution.evictions.extend(start..end);
+        }
+
+        for (zero_offset, zero_len) in resolution.zero_ranges {
+            let pieces = self
+                .shared
+                .segments
+                .lock()
+                .unwrap()
+                .addresses_of(zero_offset, zero_len);
+            // What can't be filled now is faulted in when it's needed.
+            for (address, _, len) in pieces {
+                let _ = zero_fill(&self.shared, address, len);
+            }
+        }
+        let readahead = resolution
+            .readahead
+            .into_iter()
+            .flat_map(|(offset, len)| missing_ranges(&self.shared, offset, len))
+            .collect();
+        schedule_readahead(&self.shared, &self.mmap_state, readahead);
+        evict_handler_pages(&self.shared, &self.mmap_state, resolution.evictions);
+    }
+
+    // Starts bringing in a range of the mapping in the background. Same as advising
+    // Advice::WillNeed.
+    pub fn prefetch(&self, range: Range<usize>) {
+        self.advise(range, Advice::WillNeed)
+    }
+
+    // Drops a range of the mapping from memory right away; it's brought in again when it's
+    // touched. Modified and pinned pages stay. Same as advising Advice::DontNeed.
+    pub fn evict(&self, range: Range<usize>) {
+        self.advise(range, Advice::DontNeed)
+    }
+
+    // Keeps a range of the mapping in memory until it's unpinned, and starts bringing in whatever
+    // of it isn't there yet. The range is widened to whole pages. Fails with ENOMEM if that would
+    // take the pinned pages over MMapOptions::pin_limit; nothing is pinned then.
+    pub fn pin(&self, range: Range<usize>) -> Result<(), c_int> {
+        if unsafe { libc::getpid() } != self.pid {
+            return Ok(());
+        }
+        let (start, end) = match self.page_range(&range) {
+            Some(pages) => pages,
+            None => return Ok(()),
+        };
+        let page_size = self.page_size();
+        {
+            let mut pinned = self.shared.pinned.write().unwrap();
+            let new_pages = (start..end).filter(|page| !pinned.contains(page)).count();
+            if (pinned.len() + new_pages) * page_size > self.shared.options.pin_limit {
+                return Err(libc::ENOMEM);
+            }
+            pinned.extend(start..end);
+        }
+        self.mmap_state.pin(
+            (start * page_size) as u64,
+            ((end - start) * page_size) as u64,
+        );
+        self.prefetch(range);
+        Ok(())
+    }
+
+    // Lets a range of the mapping be evicted again.
+    pub fn unpin(&self, range: Range<usize>) {
+        if unsafe { libc::getpid() } != self.pid {
+            return;
+        }
+        let (start, end) = match self.page_range(&range) {
+            Some(pages) => pages,
+            None => return,
+        };
+        {
+            let mut pinned = self.shared.pinned.write().unwrap();
+            for page in start..end {
+                pinned.remove(&page);
+            }
+        }
+        let page_size = self.page_size();
+        self.mmap_state.unpin(
+            (start * page_size) as u64,
+            ((end - start) * page_size) as u64,
+        );
+    }
+
+    // The handler, for tests to look at.
+    #[cfg(test)]
+    pub(crate) fn handler(&self) -> &M {
+        &self.mmap_state
+    }
+
+    // The read-ahead and eviction knobs the mapping goes by.
+    pub fn heuristics(&self) -> HeuristicsConfig {
+        self.heuristics.lock().unwrap().clone()
+    }
+
+    // Changes the read-ahead and eviction knobs of a live mapping. What has been loaded so far
+    // stays; a lower max_loaded_size is caught up with at the next fault. Fails with EINVAL, and
+    // changes nothing, if the config doesn't hold together (see HeuristicsConfig::consistent()).
+    pub fn set_heuristics(&self, config: HeuristicsConfig) -> Result<(), c_int> {
+        if unsafe { libc::getpid() } != self.pid {
+            return Ok(());
+        }
+        if !config.consistent(self.page_size()) {
+            return Err(libc::EINVAL);
+        }
+        let mut heuristics = self.heuristics.lock().unwrap();
+        self.mmap_state.set_heuristics(&config);
+        *heuristics = config;
+        Ok(())
+    }
+
+    // Whether a mapping made with `options` would behave like this one, so that whoever asked for
+    // it can be handed this one instead (see registry.rs). Heuristics are compared with what the
+    // mapping goes by now.
+    pub(crate) fn same_options(&self, options: &MMapOptions) -> bool {
+        let ours = &self.shared.options;
+        let same_cache = match (&ours.page_cache, &options.page_cache) {
+            (Some(ours), Some(theirs)) => Arc::ptr_eq(ours, theirs),
+            (None, None) => true,
+            _ => false,
+        };
+        ours.error_policy == options.error_policy
+            && ours.mode == options.mode
+            && ours.pin_limit == options.pin_limit
+            && ours.huge_pages == options.huge_pages
+            && same_cache
+            && ours.access_sample_size == options.access_sample_size
+            && self.heuristics().same(&options.heuristics)
+    }
+
+    // Returns the pages a byte range touches, as (first page, last page + 1), clamped to the
+    // mapping. None if that's no pages at all.
+    fn page_range(&self, range: &Range<usize>) -> Option<(usize, usize)> {
+        let page_size = self.page_size();
+        let start = round_down_to(range.start, page_size) / page_size;
+        let end = cmp::min(round_up_to(range.end, page_size), self.sz) / page_size;
+        if start < end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+// Future returned by MMap::ensure_resident().
+pub struct Resident<F> {
+    state: Arc<Mutex<ResidentState<F>>>,
+}
+
+struct ResidentState<F> {
+    // Population jobs still running.
+    jobs: usize,
+    failure: Option<F>,
+    waker: Option<Waker>,
+}
+
+impl<F> Resident<F> {
+    fn new() -> Self {
+        Resident {
+            state: Arc::new(Mutex::new(ResidentState {
+                jobs: 0,
+                failure: None,
+                waker: None,
+            })),
+        }
+    }
+}
+
+impl<F> Future for Resident<F> {
+    type Output = Result<(), F>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(failure) = state.failure.take() {
+            return Poll::Ready(Err(failure));
+        }
+        if state.jobs == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<M: MMapHandler + Send + Sync> MMap<M>
+where
+    M::Failure: Send,
+{
+    // Brings a byte range of the mapping into memory on the worker pool, without blocking the
+    // calling thread. The returned future resolves once every page that was missing has been put
+    // in, or with the handler's failure. Population starts right away, not when the future is
+    // first polled.
+    //
+    // The pages are evicted again like any others so read them soon (or pin() them). Handlers
+    // that can't populate (see MMapHandler::supports_populate()) fail the future with the error
+    // their populate() gives.
+    pub fn ensure_resident(&self, range: Range<usize>) -> Resident<M::Failure> {
+        let resident = Resident::new();
+        // A forked child can't populate anything itself; it has to fault.
+        if unsafe { libc::getpid() } != self.pid {
+            return resident;
+        }
+        // Nothing to fetch past the end of the data.
+        let range = range.start..cmp::min(range.end, self.sz_unrounded);
+        let (start, end) = match self.page_range(&range) {
+            Some(pages) => pages,
+            None => return resident,
+        };
+        let offset = (start * self.page_size()) as u64;
+        let len = ((end - start) * self.page_size()) as u64;
+        if missing_ranges(&self.shared, offset, len).is_empty() {
+            return resident;
+        }
+        if !M::supports_populate() {
+            if let Err(failure) = self.mmap_state.populate(offset, len) {
+                resident.state.lock().unwrap().failure = Some(failure);
+            }
+            return resident;
+        }
+
+        // Let the handler do its bookkeeping and cut up the range like it does for read-ahead.
+        // Handlers that don't get one piece per missing run.
+        let resolution = self.mmap_state.advise(offset, len, Advice::WillNeed);
+        let pieces = if resolution.readahead.is_empty() {
+            vec![(offset, len)]
+        } else {
+            resolution.readahead
+        };
+        for (piece_offset, piece_len) in pieces {
+            for (missing_offset, missing_len) in
+                missing_ranges(&self.shared, piece_offset, piece_len)
+            {
+                let mut stream = Stream::new(
+                    &self.shared,
+                    missing_offset,
+                    missing_offset + missing_len,
+                    false,
+                );
+                let shared = self.shared.clone();
+                let mmap_state = self.mmap_state.clone();
+                let state = resident.state.clone();
+                state.lock().unwrap().jobs += 1;
+                reactor::spawn(move || {
+                    let result = populate_stream(&shared, &mmap_state, &mut stream);
+                    let (start, end) = (stream.start, stream.end);
+                    drop(stream);
+                    let result = result.and_then(|()| {
+                        populate_remaining(&shared, &mmap_state, start, end - start)
+                    });
+                    let mut state = state.lock().unwrap();
+                    state.jobs -= 1;
+                    // Pages that didn't make it into the page cache are left to be faulted in,
+                    // where the mapping's error policy takes care of them.
+                    if let Err(InstallFailure::Handler(failure)) = result {
+                        state.failure.get_or_insert(failure);
+                    }
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                });
+            }
+        }
+        evict_handler_pages(&self.shared, &self.mmap_state, resolution.evictions);
+        resident
+    }
+}
+
+// Brings in what is still missing of [offset, offset+len) after a populate. The handler may have
+// handed over less than it was asked for (some other job had the start of the range and covered
+// only part of it), so this goes again until nothing is missing or a round makes no progress
+// (pages being evicted as fast as they come in).
+fn populate_remaining<M: MMapHandler>(
+    shared: &Arc<MMapShared>,
+    mmap_state: &M,
+    offset: u64,
+    len: u64,
+) -> Result<(), InstallFailure<M::Failure>> {
+    let mut missing_before = len + 1;
+    loop {
+        let missing = missing_ranges(shared, offset, len);
+        let missing_len: u64 = missing.iter().map(|(_, len)| *len).sum();
+        if missing_len == 0 || missing_len >= missing_before {
+            return Ok(());
+        }
+        missing_before = missing_len;
+        for (missing_offset, missing_len) in missing {
+            let mut stream =
+                Stream::new(shared, missing_offset, missing_offset + missing_len, false);
+            populate_stream(shared, mmap_state, &mut stream)?;
+        }
+    }
+}
+
+// The parts of [offset, offset+len) of a mapping that are not in memory, as (offset, length)
+// ranges.
+fn missing_ranges(shared: &MMapShared, offset: u64, len: u64) -> Vec<(u64, u64)> {
+    let pieces = shared.segments.lock().unwrap().addresses_of(offset, len);
+    // Pages sample_accesses() has taken out are put back as soon as they are touched.
+    let sampled = shared.sampled.lock().unwrap();
+    let page_size = shared.page_size;
+    // mincore() reports on normal pages, even for huge pages. A huge page is there or not as a
+    // whole so looking at its first normal page is enough.
+    let step = (page_size / *PAGESIZE_U64) as usize;
+    let mut missing: Vec<(u64, u64)> = vec![];
+    for (address, piece_offset, piece_len) in pieces {
+        let mut resident: Vec<u8> = vec![0; (piece_len / *PAGESIZE_U64) as usize];
+        let ret = unsafe {
+            libc::mincore(
+                address as *mut c_void,
+                piece_len as size_t,
+                resident.as_mut_ptr(),
+            )
+        };
+        // Unmapped under us. Nothing to bring in there.
+        if ret == -1 {
+            continue;
+        }
+        for (page, flags) in resident.iter().step_by(step).enumerate() {
+            let page_offset = piece_offset + page as u64 * page_size;
+            if flags & 1 != 0 || sampled.contains_key(&((page_offset / page_size) as usize)) {
+                continue;
+            }
+            match missing.last_mut() {
+                Some(last) if last.0 + last.1 == page_offset => last.1 += page_size,
+                _ => missing.push((page_offset, page_size)),
+            }
+        }
+    }
+    missing
+}
+
+// Turns a set of page numbers into sorted (start, end) byte ranges, merging adjacent pages and
+// clamping to the size of the mapping.
+fn pages_to_ranges(pages: &BTreeSet<usize>, sz: usize, page_size: usize) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for page in pages.iter() {
+        let start = *page * page_size;
+        let end = cmp::min(start + page_size, sz);
+        if start >= end {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
 }
@@ -5,113 +5,273 @@
  *
  * byte = (offset * 13) & 0xFF
  *
- * It uses the same read-ahead heuristics as userfaultfd_s3.
+ * It uses the same read-ahead heuristics as userfaultfd_s3, hands pages over in the same growing
+ * pieces as userfaultfd_s3 streams them and leaves the same part of the read-ahead to the
+ * background.
+ *
+ * Writable mappings are supported but the written data goes nowhere.
+ *
+ * Pages past the end (only the one page of a zero sized mapping) are handed out as zero ranges.
+ *
+ * What it has been told was accessed and what it has evicted is kept, so that tests can look.
  */
 
-use crate::heuristics::PageHeuristics;
-use crate::mmaputil::{round_up_to_pagesize, MMapPages, PAGESIZE_U64, PAGESIZE_USIZE};
-use crate::userfaultfd::MMapHandler;
+use crate::heuristics::{HeuristicsConfig, PageHeuristics};
+use crate::mmaputil::{round_up_to, MMapPages, StreamChunks};
+use crate::userfaultfd::{Advice, FaultRequest, FaultResolution, MMapHandler, PageRun};
+use std::cmp;
 use std::collections::BTreeSet;
+use std::mem;
 use std::sync::{Arc, RwLock};
 
 #[derive(Clone)]
 pub struct MMapDummy {
     state: Arc<RwLock<MMapDummyState>>,
     sz: usize,
+    page_size: usize,
 }
 
 struct MMapDummyState {
     heuristics: PageHeuristics,
+    accessed: BTreeSet<usize>,
+    evicted: BTreeSet<usize>,
 }
 
 pub struct DummyPageIterator {
-    base_page: MMapPages,
+    base_page: Option<MMapPages>,
     cursor: usize,
     offset: usize,
+    chunks: StreamChunks,
 }
 
 impl Iterator for DummyPageIterator {
-    type Item = MMapPages;
+    type Item = Result<PageRun, ()>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cursor < self.base_page.mmapped_size as usize {
-            let slice = self.base_page.as_mut_slice();
-            for i in self.cursor..self.cursor + *PAGESIZE_USIZE {
+        let base_page = self.base_page.as_mut()?;
+        if self.cursor < base_page.mmapped_size as usize {
+            let chunk = self
+                .chunks
+                .next_size(base_page.mmapped_size as usize - self.cursor);
+            let slice = base_page.as_mut_slice();
+            for i in self.cursor..self.cursor + chunk {
                 slice[i] = (((i + self.offset) * 13) & 0xFF) as u8;
             }
-            let ret = unsafe {
-                MMapPages {
-                    vehicle_page: self.base_page.vehicle_page.add(self.cursor),
-                    mmapped_size: *PAGESIZE_U64,
-                    do_unmap: false,
-                }
+            let ret = PageRun {
+                offset: (self.offset + self.cursor) as u64,
+                pages: unsafe {
+                    MMapPages {
+                        vehicle_page: base_page.vehicle_page.add(self.cursor),
+                        mmapped_size: chunk as u64,
+                        do_unmap: false,
+                    }
+                },
             };
-            self.cursor += *PAGESIZE_USIZE;
-            Some(ret)
+            self.cursor += chunk;
+            Some(Ok(ret))
         } else {
             None
         }
     }
 }
 
+impl MMapDummy {
+    // Pages accessed() has been told about.
+    pub fn accessed_pages(&self) -> BTreeSet<usize> {
+        self.state.read().unwrap().accessed.clone()
+    }
+
+    // Pages the heuristics have evicted, and forgets them.
+    pub fn take_evicted_pages(&self) -> BTreeSet<usize> {
+        mem::replace(&mut self.state.write().unwrap().evicted, BTreeSet::new())
+    }
+
+    fn pages(&self, offset: usize, len: usize) -> DummyPageIterator {
+        DummyPageIterator {
+            base_page: if len > 0 {
+                Some(MMapPages::with_page_size(len as u64, self.page_size))
+            } else {
+                None
+            },
+            cursor: 0,
+            offset,
+            chunks: StreamChunks::new(self.page_size),
+        }
+    }
+}
+
 impl MMapHandler for MMapDummy {
     type Argument = usize;
     type Failure = ();
     type PageIterator = DummyPageIterator;
 
-    fn new(size: Self::Argument) -> Result<(Self, usize), Self::Failure> {
+    fn new(size: Self::Argument, page_size: usize) -> Result<(Self, usize), Self::Failure> {
         Ok((
             MMapDummy {
                 sz: size,
                 state: Arc::new(RwLock::new(MMapDummyState {
-                    heuristics: PageHeuristics::new(),
+                    heuristics: PageHeuristics::new(page_size),
+                    accessed: BTreeSet::new(),
+                    evicted: BTreeSet::new(),
                 })),
+                page_size,
             },
             size,
         ))
     }
 
     fn handle_userfault(
-        self,
-        offset: u64,
-    ) -> Result<(Self::PageIterator, BTreeSet<usize>), Self::Failure> {
-        let offset = offset as usize;
+        &self,
+        request: &FaultRequest,
+    ) -> Result<FaultResolution<Self::PageIterator>, Self::Failure> {
+        let offset = request.offset as usize;
+        let page_size = self.page_size;
+
+        if offset >= self.sz {
+            let evictions = {
+                let mut stw = self.state.write().unwrap();
+                let page = offset / page_size;
+                stw.heuristics.mark_pages_as_read(page, page + 1);
+                stw.heuristics.evict_pages_if_needed2()
+            };
+            let mut resolution = FaultResolution::new(
+                DummyPageIterator {
+                    base_page: None,
+                    cursor: 0,
+                    offset,
+                    chunks: StreamChunks::new(page_size),
+                },
+                evictions,
+            );
+            resolution
+                .zero_ranges
+                .push((offset as u64, page_size as u64));
+            return Ok(resolution);
+        }
+
         let actual_read_sz = {
             let mut stw = self.state.write().unwrap();
-            stw.heuristics.readahead_heuristic(offset, *PAGESIZE_USIZE)
+            stw.heuristics
+                .readahead_heuristic(request.thread_id, offset, page_size)
         };
 
-        let len = round_up_to_pagesize(if offset + actual_read_sz > self.sz {
+        let actual_read_sz = if offset + actual_read_sz > self.sz {
             self.sz - offset
         } else {
             actual_read_sz
-        });
-
-        let page = MMapPages::new(len as u64);
+        };
+        let (sync_read_sz, mut background) = self
+            .state
+            .read()
+            .unwrap()
+            .heuristics
+            .split_readahead(offset, actual_read_sz);
 
         let evictions = {
             let mut stw = self.state.write().unwrap();
             stw.heuristics.mark_pages_as_read(
-                offset / *PAGESIZE_USIZE,
-                (offset + page.mmapped_size as usize) / *PAGESIZE_USIZE,
+                offset / page_size,
+                (offset + round_up_to(actual_read_sz, page_size)) / page_size,
             );
-            stw.heuristics.evict_pages_if_needed2()
+            // Faults heading somewhere the slices don't cover, like backwards or in strides.
+            background.extend(stw.heuristics.stride_readahead(request.thread_id, self.sz));
+            let evictions = stw.heuristics.evict_pages_if_needed2();
+            stw.evicted.extend(evictions.iter());
+            evictions
         };
 
-        Ok((
-            DummyPageIterator {
-                base_page: page,
-                cursor: 0,
-                offset,
-            },
-            evictions,
-        ))
+        let pages = self.pages(offset, sync_read_sz);
+        let pages_len = round_up_to(sync_read_sz, page_size) as u64;
+        let mut resolution = FaultResolution::new(pages, evictions);
+        resolution.pages_len = pages_len;
+        resolution.readahead = background
+            .into_iter()
+            .map(|(offset, len)| (offset as u64, round_up_to(len, page_size) as u64))
+            .collect();
+        Ok(resolution)
+    }
+
+    fn supports_populate() -> bool {
+        true
+    }
+
+    fn populate(&self, offset: u64, len: u64) -> Result<Self::PageIterator, Self::Failure> {
+        let offset = offset as usize;
+        let len = cmp::min(len as usize, self.sz.saturating_sub(offset));
+        Ok(self.pages(offset, len))
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> FaultResolution<()> {
+        let offset = offset as usize;
+        let len = cmp::min(len as usize, self.sz.saturating_sub(offset));
+        let mut resolution = FaultResolution::new((), BTreeSet::new());
+        let mut stw = self.state.write().unwrap();
+        resolution.readahead = stw
+            .heuristics
+            .advise(offset, len, advice, &mut resolution.evictions)
+            .into_iter()
+            .map(|(offset, len)| (offset as u64, round_up_to(len, self.page_size) as u64))
+            .collect();
+        resolution
+    }
+
+    fn pin(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.pin_pages(start_page, end_page);
+    }
+
+    fn unpin(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.unpin_pages(start_page, end_page);
+    }
+
+    fn set_heuristics(&self, config: &HeuristicsConfig) {
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.set_config(config);
+    }
+
+    fn accessed(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.accessed(start_page, end_page);
+        stw.accessed.extend(start_page..end_page);
+    }
+
+    fn kept(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.kept(start_page, end_page);
+    }
+
+    fn budget_evicted(&self, pages: &BTreeSet<usize>) {
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.budget_evicted(pages);
+    }
+
+    fn supports_write_back() -> bool {
+        true
+    }
+
+    fn write_back(&self, _contents: &[u8], _dirty: &[(usize, usize)]) -> Result<(), Self::Failure> {
+        Ok(())
+    }
+
+    // The contents only depend on the size.
+    fn identity(&self) -> Option<String> {
+        Some(format!("dummy {}", self.sz))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::{EvictionPolicyFactory, ReadaheadPolicyFactory};
     use crate::userfaultfd::*;
     use rand::{seq::SliceRandom, thread_rng};
 
@@ -172,10 +332,452 @@ mod tests {
         }
     }
 
+    #[test]
+    fn many_mappings_test() {
+        // These all share one reactor thread and one worker pool.
+        let mappings: Vec<MMap<MMapDummy>> = (0..500)
+            .map(|_| mmap_with_userfault(4096 * 4).unwrap())
+            .collect();
+        for mmapped in mappings.iter() {
+            let slice: &[u8] = mmapped.as_slice();
+            for i in (0..slice.len()).step_by(1000) {
+                expect_byte(slice[i], i);
+            }
+        }
+    }
+
+    #[test]
+    fn writeback_dirty_tracking_test() {
+        let mut options = MMapOptions::new();
+        options.mode = MMapMode::WriteBack;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault_options(4096 * 64, options).unwrap();
+        let ptr = mmapped.as_ptr::<u8>() as *mut u8;
+        assert_eq!(mmapped.dirty_ranges(), vec![]);
+
+        // Reading does not make anything dirty.
+        expect_byte(mmapped.as_slice()[4096 * 3], 4096 * 3);
+        assert_eq!(mmapped.dirty_ranges(), vec![]);
+
+        unsafe {
+            *ptr.add(4096 * 3 + 5) = 123;
+            *ptr.add(4096 * 4) = 124;
+            *ptr.add(4096 * 10 + 100) = 125;
+        }
+        assert_eq!(
+            mmapped.dirty_ranges(),
+            vec![(4096 * 3, 4096 * 5), (4096 * 10, 4096 * 11)]
+        );
+        let slice: &[u8] = mmapped.as_slice();
+        assert_eq!(slice[4096 * 3 + 5], 123);
+        expect_byte(slice[4096 * 3 + 6], 4096 * 3 + 6);
+
+        mmapped.flush().unwrap();
+        assert_eq!(mmapped.dirty_ranges(), vec![]);
+        assert_eq!(mmapped.as_slice::<u8>()[4096 * 3 + 5], 123);
+
+        // Pages are write-protected again after flushing.
+        unsafe {
+            *ptr.add(4096 * 10) = 1;
+        }
+        assert_eq!(mmapped.dirty_ranges(), vec![(4096 * 10, 4096 * 11)]);
+    }
+
+    #[test]
+    fn private_mapping_test() {
+        let mut options = MMapOptions::new();
+        options.mode = MMapMode::Private;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault_options(4096 * 40000, options).unwrap();
+        let ptr = mmapped.as_ptr::<u8>() as *mut u8;
+        unsafe {
+            *ptr.add(4096 * 2) = 200;
+        }
+        assert_eq!(mmapped.dirty_ranges(), vec![(4096 * 2, 4096 * 3)]);
+
+        // Read through the whole thing so that plenty of pages get evicted. The modified page
+        // must survive that.
+        let slice: &[u8] = mmapped.as_slice();
+        for i in 4096 * 3..slice.len() {
+            expect_byte(slice[i], i);
+        }
+        assert_eq!(slice[4096 * 2], 200);
+        expect_byte(slice[4096 * 2 + 1], 4096 * 2 + 1);
+
+        // Flushing a private mapping does nothing.
+        mmapped.flush().unwrap();
+        assert_eq!(mmapped.dirty_ranges(), vec![(4096 * 2, 4096 * 3)]);
+    }
+
+    #[test]
+    fn forked_child_test() {
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(4096 * 64).unwrap();
+        let slice: &[u8] = mmapped.as_slice();
+        expect_byte(slice[0], 0);
+
+        // The child's copy of the mapping is served by our reactor thread. No panicking in the
+        // child; just report through the exit status.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            for i in 0..slice.len() {
+                if slice[i] != ((i * 13) & 0xFF) as u8 {
+                    unsafe { libc::_exit(1) };
+                }
+            }
+            unsafe { libc::_exit(0) };
+        }
+        assert!(pid > 0);
+        let mut status: libc::c_int = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        unsafe {
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+
+        for i in 0..slice.len() {
+            expect_byte(slice[i], i);
+        }
+    }
+
+    #[test]
+    fn mremap_test() {
+        let len = 4096 * 64;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(len).unwrap();
+        expect_byte(mmapped.as_slice()[4096], 4096);
+
+        let old_ptr = mmapped.as_ptr::<u8>();
+        let new_ptr = unsafe {
+            let reserved = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(reserved, libc::MAP_FAILED);
+            libc::mremap(
+                old_ptr as *mut libc::c_void,
+                len,
+                len,
+                libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+                reserved,
+            )
+        };
+        assert_ne!(new_ptr, libc::MAP_FAILED);
+
+        // The mapping follows, both the pages that were already there and the ones that weren't.
+        assert_eq!(mmapped.as_ptr::<u8>(), new_ptr as *const u8);
+        let slice: &[u8] = mmapped.as_slice();
+        for i in 0..slice.len() {
+            expect_byte(slice[i], i);
+        }
+    }
+
+    #[test]
+    fn partial_munmap_test() {
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(4096 * 64).unwrap();
+        let ptr = mmapped.as_ptr::<u8>();
+        unsafe {
+            assert_eq!(
+                libc::munmap(ptr.add(4096 * 10) as *mut libc::c_void, 4096 * 10),
+                0
+            );
+        }
+
+        // What's left on both sides still works. Dropping the mapping only unmaps what's left.
+        for i in (0..4096 * 10).chain(4096 * 20..4096 * 64) {
+            expect_byte(unsafe { *ptr.add(i) }, i);
+        }
+    }
+
+    fn resident_pages(ptr: *const u8, len: usize) -> usize {
+        let mut resident = vec![0u8; len / 4096];
+        assert_eq!(
+            unsafe { libc::mincore(ptr as *mut libc::c_void, len, resident.as_mut_ptr()) },
+            0
+        );
+        resident.iter().filter(|flags| **flags & 1 != 0).count()
+    }
+
+    #[test]
+    fn advise_test() {
+        let len = 4096 * 1024;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(len).unwrap();
+        let ptr = mmapped.as_ptr::<u8>();
+
+        // Prefetching comes in without touching anything.
+        mmapped.prefetch(4096 * 10..4096 * 600);
+        let mut waited = 0;
+        while resident_pages(ptr, len) < 590 {
+            assert!(waited < 5000, "prefetched pages never arrived");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            waited += 10;
+        }
+        assert_eq!(resident_pages(ptr, 4096 * 10), 0);
+        let slice: &[u8] = mmapped.as_slice();
+        for i in 4096 * 10..4096 * 600 {
+            expect_byte(slice[i], i);
+        }
+
+        mmapped.advise(0..len, Advice::DontNeed);
+        assert_eq!(resident_pages(ptr, len), 0);
+
+        // Random access brings in just the page that was touched.
+        mmapped.advise(0..len, Advice::Random);
+        for i in 0..4096 * 64 {
+            expect_byte(slice[i], i);
+        }
+        assert_eq!(resident_pages(ptr, len), 64);
+
+        // Sequential access reads ahead from the first page on.
+        mmapped.advise(0..len, Advice::Sequential);
+        expect_byte(slice[4096 * 700], 4096 * 700);
+        assert!(resident_pages(unsafe { ptr.add(4096 * 700) }, 4096 * 64) > 1);
+    }
+
+    #[test]
+    fn ensure_resident_test() {
+        let len = 4096 * 5000;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(len).unwrap();
+        let ptr = mmapped.as_ptr::<u8>();
+        expect_byte(mmapped.as_slice()[4096 * 20], 4096 * 20);
+
+        futures::executor::block_on(mmapped.ensure_resident(4096 * 10..4096 * 3000 + 1)).unwrap();
+        assert_eq!(
+            resident_pages(unsafe { ptr.add(4096 * 10) }, 4096 * 2991),
+            2991
+        );
+        // Already there.
+        futures::executor::block_on(mmapped.ensure_resident(4096 * 10..4096 * 20)).unwrap();
+        // Nothing past the end.
+        futures::executor::block_on(mmapped.ensure_resident(len..len * 2)).unwrap();
+    }
+
+    #[test]
+    fn pin_test() {
+        let len = 4096 * 40000;
+        let mut options = MMapOptions::new();
+        options.pin_limit = 4096 * 100;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault_options(len, options).unwrap();
+        let ptr = mmapped.as_ptr::<u8>();
+
+        assert_eq!(mmapped.pin(0..4096 * 101), Err(libc::ENOMEM));
+        mmapped.pin(100..4096 * 10).unwrap();
+        // Overlapping pins only count once.
+        mmapped.pin(0..4096 * 100).unwrap();
+        assert_eq!(mmapped.pin(4096 * 100..4096 * 101), Err(libc::ENOMEM));
+
+        // Read through everything so that lots of pages get evicted. The pinned ones stay, even
+        // when asked to go.
+        let slice: &[u8] = mmapped.as_slice();
+        for i in 0..len {
+            expect_byte(slice[i], i);
+        }
+        mmapped.evict(0..4096 * 200);
+        assert_eq!(resident_pages(ptr, 4096 * 200), 100);
+        assert!(resident_pages(ptr, len) < 40000);
+
+        mmapped.unpin(0..4096 * 50);
+        mmapped.evict(0..4096 * 100);
+        assert_eq!(resident_pages(ptr, 4096 * 100), 50);
+        assert_eq!(resident_pages(unsafe { ptr.add(4096 * 50) }, 4096 * 50), 50);
+    }
+
+    // The budget itself is process-wide and would get in the way of the other tests; this only
+    // checks that the usage it goes by is right.
+    #[test]
+    fn resident_size_test() {
+        let len = 4096 * 1024;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(len).unwrap();
+        assert_eq!(mmapped.resident_size(), 0);
+
+        mmapped.advise(0..len, Advice::Random);
+        let slice: &[u8] = mmapped.as_slice();
+        for i in (0..4096 * 64).step_by(4096) {
+            expect_byte(slice[i], i);
+        }
+        assert_eq!(mmapped.resident_size(), 4096 * 64);
+        assert!(crate::budget::memory_used() >= 4096 * 64);
+
+        mmapped.evict(0..4096 * 32);
+        assert_eq!(mmapped.resident_size(), 4096 * 32);
+    }
+
+    #[test]
+    fn access_sampling_test() {
+        let len = 4096 * 1024;
+        let mut options = MMapOptions::new();
+        options.access_sample_size = 1024;
+        // Small enough to scan past quickly, with a policy that cares about use.
+        options.heuristics.readahead_policy = ReadaheadPolicyFactory::none();
+        options.heuristics.eviction_policy = EvictionPolicyFactory::clock();
+        options.heuristics.max_loaded_size = 4096 * 256;
+        options.heuristics.evict_below_max_loaded_size = 4096;
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault_options(len, options).unwrap();
+        mmapped.advise(0..len, Advice::Random);
+        let slice: &[u8] = mmapped.as_slice();
+        for i in (0..4096 * 64).step_by(4096) {
+            expect_byte(slice[i], i);
+        }
+
+        // The next fault after a while takes some pages out. They come back as they were when
+        // they are used again, and still count as loaded in the meantime.
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        expect_byte(slice[4096 * 100], 4096 * 100);
+        assert_eq!(mmapped.resident_size(), 4096 * 65);
+        for i in 0..4096 * 64 {
+            expect_byte(slice[i], i);
+        }
+        assert_eq!(mmapped.resident_size(), 4096 * 65);
+        let accessed = mmapped.handler().accessed_pages();
+        assert!(!accessed.is_empty());
+        assert!(accessed.iter().all(|page| *page < 64));
+
+        // A scan that pushes out more than 65 pages. Page 100 was read once and goes; the pages
+        // found in use stay.
+        mmapped.handler().take_evicted_pages();
+        for i in (4096 * 200..4096 * 500).step_by(4096) {
+            expect_byte(slice[i], i);
+        }
+        let evicted = mmapped.handler().take_evicted_pages();
+        assert!(evicted.len() > 65);
+        assert!(evicted.contains(&100));
+        assert!(accessed.iter().all(|page| !evicted.contains(page)));
+    }
+
+    // Hands over the faulting page and the page two pages further, and remembers what it was
+    // asked.
+    #[derive(Clone)]
+    struct Scatter {
+        requests: Arc<RwLock<Vec<FaultRequest>>>,
+    }
+
+    impl MMapHandler for Scatter {
+        type Argument = Arc<RwLock<Vec<FaultRequest>>>;
+        type Failure = ();
+        type PageIterator = Vec<Result<PageRun, ()>>;
+
+        fn new(
+            requests: Self::Argument,
+            _page_size: usize,
+        ) -> Result<(Self, usize), Self::Failure> {
+            Ok((Scatter { requests }, 4096 * 16))
+        }
+
+        fn handle_userfault(
+            &self,
+            request: &FaultRequest,
+        ) -> Result<FaultResolution<Self::PageIterator>, Self::Failure> {
+            self.requests.write().unwrap().push(request.clone());
+            let runs = [request.offset, request.offset + 4096 * 2]
+                .iter()
+                .map(|offset| {
+                    let mut pages = MMapPages::new(4096);
+                    for (i, byte) in pages.as_mut_slice().iter_mut().enumerate() {
+                        *byte = (((*offset as usize + i) * 13) & 0xFF) as u8;
+                    }
+                    Ok(PageRun {
+                        offset: *offset,
+                        pages,
+                    })
+                })
+                .collect();
+            Ok(FaultResolution::new(runs, BTreeSet::new()))
+        }
+
+        fn populate(&self, _offset: u64, _len: u64) -> Result<Self::PageIterator, ()> {
+            Err(())
+        }
+
+        fn write_back(&self, _contents: &[u8], _dirty: &[(usize, usize)]) -> Result<(), ()> {
+            Err(())
+        }
+    }
+
+    #[test]
+    fn scattered_runs_test() {
+        let requests = Arc::new(RwLock::new(vec![]));
+        let mut options = MMapOptions::new();
+        options.mode = MMapMode::Private;
+        let mmapped: MMap<Scatter> =
+            mmap_with_userfault_options(requests.clone(), options).unwrap();
+        let ptr = mmapped.as_ptr::<u8>() as *mut u8;
+
+        expect_byte(unsafe { *ptr.add(4096 + 7) }, 4096 + 7);
+        // Came with the first fault.
+        expect_byte(unsafe { *ptr.add(4096 * 3) }, 4096 * 3);
+        unsafe {
+            *ptr.add(4096 * 8 + 1) = 1;
+        }
+
+        let requests = requests.read().unwrap();
+        assert_eq!(requests.len(), 2);
+        let thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+        assert_eq!(requests[0].offset, 4096);
+        assert_eq!(requests[0].address, ptr as u64 + 4096 + 7);
+        assert!(!requests[0].write);
+        assert_eq!(requests[0].thread_id, thread_id);
+        assert_eq!(requests[1].offset, 4096 * 8);
+        assert!(requests[1].write);
+    }
+
     #[test]
     fn zero_page_test() {
         let mmapped: MMap<MMapDummy> = mmap_with_userfault(0).unwrap();
         let slice: &[u8] = mmapped.as_slice();
         assert_eq!(slice.len(), 0);
+        // There is still one page mapped behind the pointer and it is filled with zeroes.
+        let byte = unsafe { *mmapped.as_ptr::<u8>().add(100) };
+        assert_eq!(byte, 0);
+    }
+
+    // Huge pages that have been set aside and are not in use.
+    fn free_huge_pages() -> usize {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap();
+        meminfo
+            .lines()
+            .find(|line| line.starts_with("HugePages_Free:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|free| free.parse().ok())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn huge_pages_test() {
+        let huge_page = match *crate::mmaputil::HUGE_PAGESIZE_USIZE {
+            Some(huge_page) => huge_page,
+            None => return,
+        };
+        // Nine pages for the mapping (the last one only partly used).
+        if free_huge_pages() < 9 {
+            eprintln!("huge_pages_test: not enough huge pages set aside, skipping.");
+            return;
+        }
+        let mut options = MMapOptions::new();
+        options.huge_pages = true;
+        let mmapped: MMap<MMapDummy> =
+            mmap_with_userfault_options(huge_page * 8 + 10, options).unwrap();
+        assert_eq!(mmapped.page_size(), huge_page);
+        let ptr = mmapped.as_ptr::<u8>();
+
+        // One fault brings in a whole huge page.
+        expect_byte(unsafe { *ptr.add(huge_page * 2 + 5) }, huge_page * 2 + 5);
+        assert_eq!(
+            resident_pages(unsafe { ptr.add(huge_page * 2) }, huge_page),
+            huge_page / 4096
+        );
+
+        let slice: &[u8] = mmapped.as_slice();
+        for offset in (0..slice.len()).step_by(4099) {
+            expect_byte(slice[offset], offset);
+        }
+        expect_byte(slice[huge_page * 8 + 9], huge_page * 8 + 9);
+
+        // Evicting part of a huge page evicts all of it.
+        mmapped.evict(huge_page * 3 + 100..huge_page * 3 + 200);
+        assert_eq!(
+            resident_pages(unsafe { ptr.add(huge_page * 3) }, huge_page),
+            0
+        );
+        expect_byte(slice[huge_page * 3 + 1], huge_page * 3 + 1);
     }
 }
@@ -3,25 +3,37 @@
  * There is a simple read-ahead heuristic to make it so that scans are reasonably fast. Look into
  * heuristics.rs for more details on that.
  *
- * TODO items:
- *   * When a large read-ahead is initiated, no pages are returned to the user until the entire
- *     read is done. I noticed when I changed this to return pages immediately, things overall will
- *     slow down rather than speed up. Possible solutions: return N pages at a time.
+ * Read-ahead is streamed: pages are handed over while the GET is still going, the faulting page
+ * first on its own and then in growing pieces (see StreamChunks in mmaputil.rs). Handing over every
+ * page as soon as it arrives turned out slower than waiting for the whole read.
  *
- *   * In scenarios with multiple threads, I think it's possible to have multiple S3 downloads
- *     going on at the same time on the same file with overlapping regions. This is wasted bandwidth. Possible solutions: keep track of which downloads are going on and if there is an overlapping one, wait for the overlapping download to complete while downloading only the non-overlapping part. Return pages when both downloads are complete.
+ * Only the start of a big read-ahead is downloaded for the fault itself. The rest is handed back as
+ * background read-ahead and downloaded with separate GETs on the worker pool (see populate()).
  *
+ * Reading past the end of the object (which only happens with empty objects; we still have to map
+ * one page for them) is answered with zero pages without asking S3.
+ *
+ * Writable mappings are written back with a multipart upload. Parts that have not been modified
+ * are copied over on the S3 side with UploadPartCopy so only the modified parts are uploaded.
+ *
+ * Downloads in progress are tracked in an InFlight registry (see inflight.rs). A fault only
+ * downloads what nobody else is downloading already; a fault on a page that is being downloaded
+ * waits for that download instead of starting its own.
  */
 
-use crate::heuristics::PageHeuristics;
-use crate::mmaputil::{round_up_to_pagesize, MMapPages, PAGESIZE_USIZE};
-use crate::userfaultfd::MMapHandler;
-use libc::c_void;
+use crate::diskcache::{disk_cache, object_id, CachedObject, CachedRange, DiskCache};
+use crate::heuristics::{HeuristicsConfig, PageHeuristics};
+use crate::inflight::{InFlight, InFlightClaim};
+use crate::mmaputil::{round_up_to, MMapPages, StreamChunks};
+use crate::userfaultfd::{Advice, FaultRequest, FaultResolution, MMapHandler, PageRun};
 use regex::Regex;
 use rusoto_core::{region::ParseRegionError, Region};
 use rusoto_s3::{
-    GetBucketLocationError, GetBucketLocationRequest, GetObjectError, GetObjectRequest,
-    HeadObjectError, HeadObjectRequest, S3Client, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadError, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CreateMultipartUploadError,
+    CreateMultipartUploadRequest, GetBucketLocationError, GetBucketLocationRequest, GetObjectError,
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, PutObjectError, PutObjectRequest,
+    S3Client, UploadPartCopyError, UploadPartCopyRequest, UploadPartError, UploadPartRequest, S3,
 };
 use std::cmp;
 use std::collections::BTreeSet;
@@ -31,6 +43,11 @@ use std::io::Read;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 
+// Parts of a multipart upload must be at least 5MB (except the last one) and there can be at most
+// 10000 of them. Parts are this large unless the object is so big we'd go over the part limit.
+const WRITE_BACK_PART_SIZE: usize = 16 * 1024 * 1024;
+const MAX_PARTS: usize = 10000;
+
 lazy_static! {
     // this splits s3 url to bucket and key
     static ref s3split_re: Regex = Regex::new("^s3://([^/]+)/(.+)$").unwrap();
@@ -60,14 +77,22 @@ fn split_s3_url(url: &str) -> Option<(String, String)> {
 #[derive(Clone)]
 pub struct MMapS3 {
     state: Arc<RwLock<MMapS3State>>,
+    page_size: usize,
 }
 
 struct MMapS3State {
     bucket_name: String,
     key_name: String,
-    s3client: S3Client,
+    s3client: Arc<S3Client>,
     s3objectsize: usize,
+    // ETag of the object as we know it. Writing back checks that nobody has replaced the object
+    // since.
+    etag: Option<String>,
     heuristics: PageHeuristics,
+    inflight: InFlight,
+    // Where downloads go through, if anywhere (see diskcache.rs). Objects without an ETag are not
+    // cached.
+    disk_cache: Option<Arc<DiskCache>>,
 }
 
 #[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
@@ -81,6 +106,8 @@ pub enum S3Failure {
     IOError,                  // I/O error while downloading from S3
     Unknown,                  // Error we can't quite categorize
     PartialRead,              // We made a GET request but the returned body seems incomplete
+    ObjectModified,           // Object was replaced in S3 by someone else while we were writing back
+    NotCached,                // Offline and what we need is not in the disk cache
 }
 
 impl From<GetBucketLocationError> for S3Failure {
@@ -111,6 +138,9 @@ impl From<HeadObjectError> for S3Failure {
                 S3Failure::S3PermissionError
             }
             HeadObjectError::Unknown(resp) if resp.status.as_u16() == 404 => S3Failure::S3NotFound,
+            HeadObjectError::Unknown(resp) if resp.status.as_u16() == 412 => {
+                S3Failure::ObjectModified
+            }
             _ => S3Failure::Unknown,
         }
     }
@@ -125,13 +155,39 @@ impl From<GetObjectError> for S3Failure {
             }
             GetObjectError::Unknown(resp) if resp.status.as_u16() == 404 => S3Failure::S3NotFound,
             err => {
-                println!("{:?}", err);
+                debug!("GetObject failed: {:?}", err);
                 S3Failure::Unknown
             }
         }
     }
 }
 
+// The upload related errors all look the same so they share the conversion.
+macro_rules! upload_error_to_s3failure {
+    ($error:ident) => {
+        impl From<$error> for S3Failure {
+            fn from(err: $error) -> Self {
+                match err {
+                    $error::Unknown(resp) if resp.status.as_u16() == 403 => {
+                        S3Failure::S3PermissionError
+                    }
+                    $error::Unknown(resp) if resp.status.as_u16() == 404 => S3Failure::S3NotFound,
+                    $error::Unknown(resp) if resp.status.as_u16() == 412 => {
+                        S3Failure::ObjectModified
+                    }
+                    _ => S3Failure::Unknown,
+                }
+            }
+        }
+    };
+}
+
+upload_error_to_s3failure!(CreateMultipartUploadError);
+upload_error_to_s3failure!(UploadPartError);
+upload_error_to_s3failure!(UploadPartCopyError);
+upload_error_to_s3failure!(CompleteMultipartUploadError);
+upload_error_to_s3failure!(PutObjectError);
+
 impl From<io::Error> for S3Failure {
     fn from(_: io::Error) -> Self {
         S3Failure::IOError
@@ -141,14 +197,33 @@ impl From<io::Error> for S3Failure {
 impl MMapHandler for MMapS3 {
     type Argument = String;
     type Failure = S3Failure;
-    type PageIterator = Vec<MMapPages>;
+    type PageIterator = S3PageStream;
 
-    fn new(url: Self::Argument) -> Result<(Self, usize), Self::Failure> {
+    fn new(url: Self::Argument, page_size: usize) -> Result<(Self, usize), Self::Failure> {
         let (bucket_name, key_name) = match split_s3_url(&url) {
             None => return Err(S3Failure::InvalidS3Url),
             Some((bucket_name, key_name)) => (bucket_name, key_name),
         };
 
+        let disk_cache = disk_cache();
+        if let Some(ref cache) = disk_cache {
+            if cache.is_offline() {
+                let object = match cache.lookup_object(&bucket_name, &key_name) {
+                    None => return Err(S3Failure::NotCached),
+                    Some(object) => object,
+                };
+                return Ok(MMapS3::from_parts(
+                    get_some_s3client(),
+                    bucket_name,
+                    key_name,
+                    object.size,
+                    Some(object.etag),
+                    disk_cache.clone(),
+                    page_size,
+                ));
+            }
+        }
+
         let s3client = get_some_s3client();
         let location = s3client
             .get_bucket_location(GetBucketLocationRequest {
@@ -172,114 +247,525 @@ impl MMapHandler for MMapS3 {
             Some(cl) => cl,
         };
 
-        // MMapping 0 bytes doesn't work so round it up one byte.
-        let content_length = if content_length == 0 {
-            1
-        } else {
-            content_length
-        };
+        // Remembered so it can be opened offline later.
+        if let (Some(ref cache), Some(ref etag)) = (&disk_cache, &hob.e_tag) {
+            let object = CachedObject {
+                etag: etag.clone(),
+                size: content_length as usize,
+            };
+            cache.remember_object(&bucket_name, &key_name, &object);
+        }
 
-        Ok((
-            MMapS3 {
-                state: Arc::new(RwLock::new(MMapS3State {
-                    s3client,
-                    bucket_name,
-                    key_name,
-                    s3objectsize: content_length as usize,
-                    heuristics: PageHeuristics::new(),
-                })),
-            },
+        Ok(MMapS3::from_parts(
+            s3client,
+            bucket_name,
+            key_name,
             content_length as usize,
+            hob.e_tag,
+            disk_cache,
+            page_size,
         ))
     }
 
     fn handle_userfault(
-        self,
-        offset: u64,
-    ) -> Result<(Self::PageIterator, BTreeSet<usize>), Self::Failure> {
-        let offset = offset as usize;
+        &self,
+        request: &FaultRequest,
+    ) -> Result<FaultResolution<Self::PageIterator>, Self::Failure> {
+        let offset = request.offset as usize;
+        let page_size = self.page_size;
+
+        // Past the end of the object. Nothing to download.
+        if offset >= self.state.read().unwrap().s3objectsize {
+            let evictions = {
+                let mut stw = self.state.write().unwrap();
+                let page = offset / page_size;
+                stw.heuristics.mark_pages_as_read(page, page + 1);
+                stw.heuristics.evict_pages_if_needed2()
+            };
+            let mut resolution = FaultResolution::new(S3PageStream::empty(page_size), evictions);
+            resolution
+                .zero_ranges
+                .push((offset as u64, page_size as u64));
+            return Ok(resolution);
+        }
+
         // Figure out how much we should actually read.
         // This will be just pagesize if we don't do any read-ahead.
         let actual_read_sz = {
             let mut stw = self.state.write().unwrap();
-            stw.heuristics.readahead_heuristic(offset, *PAGESIZE_USIZE)
+            stw.heuristics
+                .readahead_heuristic(request.thread_id, offset, page_size)
         };
 
-        assert!((actual_read_sz % *PAGESIZE_USIZE) == 0);
-
-        let page = {
-            let st = self.state.read().unwrap();
-            // Don't read more data than there is in the S3 object.
-            let len = if offset + actual_read_sz > st.s3objectsize as usize {
-                st.s3objectsize - offset
-            } else {
-                actual_read_sz
-            };
-            // TODO: there's quite a bit of copying involved when downloading from S3.
-            // There are probably some clever ways to download directly to mmapped pages.
-            //
-            // In here we have 'data' in its own vector, which we copy.
-            let data: Vec<u8> = fetch_range(
-                &st.s3client,
-                st.bucket_name.clone(),
-                st.key_name.clone(),
-                offset as usize,
-                len,
-            )?;
-            let page = MMapPages::new(cmp::min(
-                round_up_to_pagesize(len) as u64,
-                actual_read_sz as u64,
-            ));
-            unsafe {
-                libc::memcpy(
-                    page.vehicle_page as *mut c_void,
-                    data.as_ptr() as *const c_void,
-                    len,
-                );
-            };
-            page
+        assert!((actual_read_sz % page_size) == 0);
+
+        // Don't read more data than there is in the S3 object.
+        let objectsize = self.state.read().unwrap().s3objectsize;
+        let actual_read_sz = cmp::min(actual_read_sz, objectsize - offset);
+
+        // Only the first bit is downloaded while the faulting thread waits. The rest is downloaded
+        // in the background.
+        let (sync_read_sz, mut background) = self
+            .state
+            .read()
+            .unwrap()
+            .heuristics
+            .split_readahead(offset, actual_read_sz);
+
+        let stream = match self.download(offset, sync_read_sz)? {
+            Some(stream) => stream,
+            // The faulting page was being downloaded and we waited for it.
+            None => {
+                return Ok(FaultResolution::new(
+                    S3PageStream::empty(page_size),
+                    BTreeSet::new(),
+                ))
+            }
         };
 
         let mut evictions = BTreeSet::new();
 
-        // Mark the pages as read.
+        // Mark the pages as read, including the ones that are read in the background. They are
+        // not all there yet but will be soon.
         {
             let mut stw = self.state.write().unwrap();
             stw.heuristics.mark_pages_as_read(
-                offset / *PAGESIZE_USIZE,
-                (offset + page.mmapped_size as usize) / *PAGESIZE_USIZE,
+                offset / page_size,
+                (offset + round_up_to(actual_read_sz, page_size)) / page_size,
             );
+            // Faults heading somewhere the slices don't cover, like backwards or in strides.
+            background.extend(
+                stw.heuristics
+                    .stride_readahead(request.thread_id, objectsize),
+            );
+            // do we have too many pages loaded? evict pages if need to.
             stw.heuristics.evict_pages_if_needed(&mut evictions);
+        }
 
-            // do we have too many pages loaded? evict pages if need to.
+        let readahead = background
+            .into_iter()
+            .map(|(offset, len)| (offset as u64, round_up_to(len, page_size) as u64))
+            .collect();
+
+        let pages_len = round_up_to(stream.remaining, page_size) as u64;
+        let mut resolution = FaultResolution::new(stream, evictions);
+        resolution.pages_len = pages_len;
+        resolution.readahead = readahead;
+        Ok(resolution)
+    }
+
+    fn supports_populate() -> bool {
+        true
+    }
+
+    fn populate(&self, offset: u64, len: u64) -> Result<Self::PageIterator, Self::Failure> {
+        let offset = offset as usize;
+        let objectsize = self.state.read().unwrap().s3objectsize;
+        if offset >= objectsize {
+            return Ok(S3PageStream::empty(self.page_size));
+        }
+        let len = cmp::min(len as usize, objectsize - offset);
+        Ok(self
+            .download(offset, len)?
+            .unwrap_or_else(|| S3PageStream::empty(self.page_size)))
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> FaultResolution<()> {
+        let offset = offset as usize;
+        let objectsize = self.state.read().unwrap().s3objectsize;
+        let len = cmp::min(len as usize, objectsize.saturating_sub(offset));
+        let mut resolution = FaultResolution::new((), BTreeSet::new());
+        let mut stw = self.state.write().unwrap();
+        resolution.readahead = stw
+            .heuristics
+            .advise(offset, len, advice, &mut resolution.evictions)
+            .into_iter()
+            .map(|(offset, len)| (offset as u64, round_up_to(len, self.page_size) as u64))
+            .collect();
+        resolution
+    }
+
+    fn pin(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.pin_pages(start_page, end_page);
+    }
+
+    fn unpin(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.unpin_pages(start_page, end_page);
+    }
+
+    fn set_heuristics(&self, config: &HeuristicsConfig) {
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.set_config(config);
+    }
+
+    fn accessed(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.accessed(start_page, end_page);
+    }
+
+    fn kept(&self, offset: u64, len: u64) {
+        let start_page = offset as usize / self.page_size;
+        let end_page = (offset + len) as usize / self.page_size;
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.kept(start_page, end_page);
+    }
+
+    fn budget_evicted(&self, pages: &BTreeSet<usize>) {
+        let mut stw = self.state.write().unwrap();
+        stw.heuristics.budget_evicted(pages);
+    }
+
+    fn supports_write_back() -> bool {
+        true
+    }
+
+    fn write_back(&self, contents: &[u8], dirty: &[(usize, usize)]) -> Result<(), Self::Failure> {
+        // Don't hold the lock while uploading. Reading 'contents' can fault and the fault handler
+        // needs the lock.
+        let (s3client, bucket, key, etag) = {
+            let st = self.state.read().unwrap();
+            (
+                st.s3client.clone(),
+                st.bucket_name.clone(),
+                st.key_name.clone(),
+                st.etag.clone(),
+            )
+        };
+
+        let new_etag = upload_object(&s3client, &bucket, &key, etag, contents, dirty)?;
+
+        let mut stw = self.state.write().unwrap();
+        if let (Some(ref cache), Some(ref etag)) = (&stw.disk_cache, &new_etag) {
+            let object = CachedObject {
+                etag: etag.clone(),
+                size: stw.s3objectsize,
+            };
+            cache.remember_object(&stw.bucket_name, &stw.key_name, &object);
+        }
+        stw.etag = new_etag;
+        Ok(())
+    }
+
+    // Without an ETag we can't tell whether two mappings see the same version of the object.
+    fn identity(&self) -> Option<String> {
+        let st = self.state.read().unwrap();
+        st.etag
+            .as_ref()
+            .map(|etag| format!("{} s3://{}/{}", etag, st.bucket_name, st.key_name))
+    }
+}
+
+// Uploads 'contents' as the new version of the object, re-using the parts that are not touched by
+// any of the 'dirty' ranges from the existing object. Returns the ETag of the new object.
+fn upload_object(
+    s3client: &S3Client,
+    bucket: &str,
+    key: &str,
+    etag: Option<String>,
+    contents: &[u8],
+    dirty: &[(usize, usize)],
+) -> Result<Option<String>, S3Failure> {
+    // PUT takes no If-Match, and a multipart upload whose parts are all modified copies nothing
+    // that would be checked, so check up front that the object is still the one we mapped. This
+    // leaves a window between the check and the upload, but catches all but a racing writer.
+    if let Some(etag) = etag.as_ref() {
+        s3client
+            .head_object(HeadObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                if_match: Some(etag.clone()),
+                ..Default::default()
+            })
+            .sync()?;
+    }
+
+    // Small enough for one part; no point doing a multipart upload.
+    if contents.len() <= write_back_part_size(contents.len()) {
+        let result = s3client
+            .put_object(PutObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                body: Some(contents.to_vec().into()),
+                ..Default::default()
+            })
+            .sync()?;
+        return Ok(result.e_tag);
+    }
+
+    let upload = s3client
+        .create_multipart_upload(CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .sync()?;
+    let upload_id = match upload.upload_id {
+        None => return Err(S3Failure::Unknown),
+        Some(upload_id) => upload_id,
+    };
+
+    match upload_parts(s3client, bucket, key, &upload_id, etag, contents, dirty) {
+        Ok(new_etag) => Ok(new_etag),
+        Err(err) => {
+            // Best effort; if this fails too the bucket's lifecycle rules will have to clean up.
+            let _ = s3client
+                .abort_multipart_upload(AbortMultipartUploadRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id,
+                    ..Default::default()
+                })
+                .sync();
+            Err(err)
         }
-        Ok((vec![page], evictions))
     }
 }
 
+fn upload_parts(
+    s3client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    etag: Option<String>,
+    contents: &[u8],
+    dirty: &[(usize, usize)],
+) -> Result<Option<String>, S3Failure> {
+    let part_size = write_back_part_size(contents.len());
+    let copy_source = format!("{}/{}", bucket, encode_copy_source_key(key));
+    let mut parts = Vec::new();
+
+    for (idx, start) in (0..contents.len()).step_by(part_size).enumerate() {
+        let end = cmp::min(start + part_size, contents.len());
+        let part_number = (idx + 1) as i64;
+        let modified = dirty.iter().any(|&(dstart, dend)| dstart < end && dend > start);
+
+        let part_etag = if modified {
+            s3client
+                .upload_part(UploadPartRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    part_number,
+                    content_length: Some((end - start) as i64),
+                    body: Some(contents[start..end].to_vec().into()),
+                    ..Default::default()
+                })
+                .sync()?
+                .e_tag
+        } else {
+            s3client
+                .upload_part_copy(UploadPartCopyRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    part_number,
+                    copy_source: copy_source.clone(),
+                    copy_source_range: Some(format!("bytes={}-{}", start, end - 1)),
+                    copy_source_if_match: etag.clone(),
+                    ..Default::default()
+                })
+                .sync()?
+                .copy_part_result
+                .and_then(|result| result.e_tag)
+        };
+
+        parts.push(CompletedPart {
+            e_tag: part_etag,
+            part_number: Some(part_number),
+        });
+    }
+
+    let result = s3client
+        .complete_multipart_upload(CompleteMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        })
+        .sync()?;
+    Ok(result.e_tag)
+}
+
+fn write_back_part_size(object_size: usize) -> usize {
+    cmp::max(WRITE_BACK_PART_SIZE, (object_size + MAX_PARTS - 1) / MAX_PARTS)
+}
+
+// x-amz-copy-source wants the key URL-encoded, slashes excluded.
+fn encode_copy_source_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
 // This is a utility function that fetches a range of bytes from an S3 object.
-fn fetch_range(
+// Starts a ranged GET and returns the body to read it from.
+fn open_range(
     s3client: &S3Client,
     bucket: String,
     key: String,
     offset: usize,
     len: usize,
-) -> Result<Vec<u8>, S3Failure> {
+) -> Result<Box<dyn Read + Send>, S3Failure> {
     let mut gob = GetObjectRequest::default();
     gob.bucket = bucket;
     gob.key = key;
     gob.range = Some(format!("bytes={}-{}", offset, offset + len - 1));
 
     let result = s3client.get_object(gob).sync()?;
-    let body = match result.body {
-        None => return Err(S3Failure::NoBodyReturned),
-        Some(body) => body,
-    };
+    match result.body {
+        None => Err(S3Failure::NoBodyReturned),
+        Some(body) => Ok(Box::new(body.into_blocking_read())),
+    }
+}
+
+impl MMapS3 {
+    fn from_parts(
+        s3client: S3Client,
+        bucket_name: String,
+        key_name: String,
+        s3objectsize: usize,
+        etag: Option<String>,
+        disk_cache: Option<Arc<DiskCache>>,
+        page_size: usize,
+    ) -> (Self, usize) {
+        let disk_cache = if etag.is_some() { disk_cache } else { None };
+        (
+            MMapS3 {
+                state: Arc::new(RwLock::new(MMapS3State {
+                    s3client: Arc::new(s3client),
+                    bucket_name,
+                    key_name,
+                    s3objectsize,
+                    etag,
+                    heuristics: PageHeuristics::new(page_size),
+                    inflight: InFlight::new(),
+                    disk_cache,
+                })),
+                page_size,
+            },
+            s3objectsize,
+        )
+    }
 
-    let mut v = Vec::new();
-    body.into_blocking_read().read_to_end(&mut v)?;
-    if v.len() != len {
-        return Err(S3Failure::PartialRead);
+    // Starts downloading [offset, offset+len), or the part of it nobody else is downloading yet.
+    // Returns None if somebody else was already downloading `offset`; by then they are done.
+    fn download(&self, offset: usize, len: usize) -> Result<Option<S3PageStream>, S3Failure> {
+        // Don't hold the state lock while waiting; whoever we wait for needs it.
+        let inflight = self.state.read().unwrap().inflight.clone();
+        let claim = match inflight.claim(offset, offset + len) {
+            Some(claim) => claim,
+            None => return Ok(None),
+        };
+        let len = claim.end - offset;
+
+        let st = self.state.read().unwrap();
+        // This returns as soon as S3 starts sending; the data is read as it's handed over.
+        let body: Box<dyn Read + Send> = match (&st.disk_cache, &st.etag) {
+            (Some(cache), Some(etag)) => {
+                let s3client = st.s3client.clone();
+                let bucket_name = st.bucket_name.clone();
+                let key_name = st.key_name.clone();
+                Box::new(CachedRange::open(
+                    cache.clone(),
+                    object_id(&st.bucket_name, &st.key_name, etag),
+                    st.s3objectsize,
+                    offset,
+                    len,
+                    move |offset, len| {
+                        open_range(
+                            &s3client,
+                            bucket_name.clone(),
+                            key_name.clone(),
+                            offset,
+                            len,
+                        )
+                    },
+                )?)
+            }
+            _ => open_range(
+                &st.s3client,
+                st.bucket_name.clone(),
+                st.key_name.clone(),
+                offset,
+                len,
+            )?,
+        };
+        Ok(Some(S3PageStream {
+            body: Some(body),
+            offset,
+            remaining: len,
+            page_size: self.page_size,
+            chunks: StreamChunks::new(self.page_size),
+            _claim: Some(claim),
+        }))
+    }
+}
+
+// Hands over the body of a ranged GET as it comes in.
+pub struct S3PageStream {
+    body: Option<Box<dyn Read + Send>>,
+    // Where in the object the next piece goes, and how many bytes are still to come.
+    offset: usize,
+    remaining: usize,
+    page_size: usize,
+    chunks: StreamChunks,
+    // Released when the stream is dropped, which is after the last page has been copied in.
+    _claim: Option<InFlightClaim>,
+}
+
+impl S3PageStream {
+    fn empty(page_size: usize) -> Self {
+        S3PageStream {
+            body: None,
+            offset: 0,
+            remaining: 0,
+            page_size,
+            chunks: StreamChunks::new(page_size),
+            _claim: None,
+        }
+    }
+}
+
+impl Iterator for S3PageStream {
+    type Item = Result<PageRun, S3Failure>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let body = self.body.as_mut()?;
+        let len = self.chunks.next_size(self.remaining);
+        // The last piece can end in the middle of a page. The rest of that page stays zero.
+        let mut page = MMapPages::with_page_size(len as u64, self.page_size);
+        if let Err(err) = body.read_exact(&mut page.as_mut_slice()[..len]) {
+            self.body = None;
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                return Some(Err(S3Failure::PartialRead));
+            }
+            return Some(Err(S3Failure::from(err)));
+        }
+        let run = PageRun {
+            offset: self.offset as u64,
+            pages: page,
+        };
+        self.offset += len;
+        self.remaining -= len;
+        Some(Ok(run))
     }
-    Ok(v)
 }
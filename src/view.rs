@@ -0,0 +1,185 @@
+/*
+ * This module implements typed views over the bytes of a mapping.
+ *
+ * MMap::as_slice() takes any type and trusts the caller to know what they are doing. The views here
+ * only hand out types that are fine with any bit pattern (Pod) and check bounds, alignment and
+ * that the range holds whole elements, returning ViewError instead of panicking.
+ *
+ * Integers in files usually have a fixed byte order that need not be ours. read_le() and
+ * read_be() read one from any offset, aligned or not, and convert it.
+ *
+ * Record files (a header followed by an array of fixed size records) can be taken apart in one go
+ * with view_with_header().
+ */
+
+use crate::userfaultfd::{MMap, MMapHandler};
+use std::mem;
+use std::ops::Range;
+use std::ptr;
+use std::slice;
+
+// Types that can be made out of any bytes: every bit pattern is a valid value and there is no
+// padding. Implement this for your own #[repr(C)] structs made of Pod fields (mind the padding).
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for u128 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for i128 {}
+unsafe impl Pod for isize {}
+unsafe impl Pod for f32 {}
+unsafe impl Pod for f64 {}
+
+// Integers that can be converted from a byte order.
+pub trait Endian: Pod {
+    fn from_le(self) -> Self;
+    fn from_be(self) -> Self;
+}
+
+macro_rules! endian_impl {
+    ($($t:ty),*) => {
+        $(
+            impl Endian for $t {
+                fn from_le(self) -> Self {
+                    <$t>::from_le(self)
+                }
+                fn from_be(self) -> Self {
+                    <$t>::from_be(self)
+                }
+            }
+        )*
+    };
+}
+
+endian_impl!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ViewError {
+    OutOfBounds,    // The range goes past the end of the mapping.
+    Misaligned,     // The range does not start at a multiple of the type's alignment.
+    PartialElement, // The range is not a whole number of elements.
+}
+
+// Views `bytes` as a slice of T.
+pub fn view<T: Pod>(bytes: &[u8]) -> Result<&[T], ViewError> {
+    let size = mem::size_of::<T>();
+    if size == 0 || bytes.len() % size != 0 {
+        return Err(ViewError::PartialElement);
+    }
+    if bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+        return Err(ViewError::Misaligned);
+    }
+    Ok(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, bytes.len() / size) })
+}
+
+// Views the start of `bytes` as a H and the rest as a slice of T. The array starts right after the
+// header; there is no padding in between.
+pub fn view_with_header<H: Pod, T: Pod>(bytes: &[u8]) -> Result<(&H, &[T]), ViewError> {
+    if bytes.len() < mem::size_of::<H>() {
+        return Err(ViewError::OutOfBounds);
+    }
+    let (header, array) = bytes.split_at(mem::size_of::<H>());
+    let header = view::<H>(header)?;
+    Ok((&header[0], view::<T>(array)?))
+}
+
+// Reads a little endian integer from any offset.
+pub fn read_le<T: Endian>(bytes: &[u8], offset: usize) -> Result<T, ViewError> {
+    Ok(read_unaligned::<T>(bytes, offset)?.from_le())
+}
+
+// Reads a big endian integer from any offset.
+pub fn read_be<T: Endian>(bytes: &[u8], offset: usize) -> Result<T, ViewError> {
+    Ok(read_unaligned::<T>(bytes, offset)?.from_be())
+}
+
+fn read_unaligned<T: Pod>(bytes: &[u8], offset: usize) -> Result<T, ViewError> {
+    let bytes = subslice(bytes, offset..offset.saturating_add(mem::size_of::<T>()))?;
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+fn subslice(bytes: &[u8], range: Range<usize>) -> Result<&[u8], ViewError> {
+    if range.start > range.end || range.end > bytes.len() {
+        return Err(ViewError::OutOfBounds);
+    }
+    Ok(&bytes[range])
+}
+
+// Same as the functions above, over a byte range of the mapping. Like as_slice(), these panic if
+// the mapping has been split up.
+impl<M: MMapHandler> MMap<M> {
+    pub fn view<T: Pod>(&self, range: Range<usize>) -> Result<&[T], ViewError> {
+        view::<T>(subslice(self.as_slice::<u8>(), range)?)
+    }
+
+    pub fn view_with_header<H: Pod, T: Pod>(
+        &self,
+        range: Range<usize>,
+    ) -> Result<(&H, &[T]), ViewError> {
+        view_with_header::<H, T>(subslice(self.as_slice::<u8>(), range)?)
+    }
+
+    pub fn read_le<T: Endian>(&self, offset: usize) -> Result<T, ViewError> {
+        read_le::<T>(self.as_slice::<u8>(), offset)
+    }
+
+    pub fn read_be<T: Endian>(&self, offset: usize) -> Result<T, ViewError> {
+        read_be::<T>(self.as_slice::<u8>(), offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userfaultfd::mmap_with_userfault;
+    use crate::userfaultfd_dummy::MMapDummy;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    #[repr(C)]
+    struct Header {
+        magic: u32,
+        count: u32,
+    }
+
+    unsafe impl Pod for Header {}
+
+    #[test]
+    fn view_checks() {
+        let words: Vec<u64> = vec![0x0807060504030201, 0x100f0e0d0c0b0a09];
+        let bytes = unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, 16) };
+
+        assert_eq!(view::<u64>(bytes), Ok(&words[..]));
+        assert_eq!(view::<u32>(&bytes[4..12]).map(|s| s.len()), Ok(2));
+        assert_eq!(view::<u32>(&bytes[1..5]), Err(ViewError::Misaligned));
+        assert_eq!(view::<u32>(&bytes[0..6]), Err(ViewError::PartialElement));
+
+        let (header, array) = view_with_header::<Header, u16>(bytes).unwrap();
+        assert_eq!(header.magic, u32::from_le(0x04030201));
+        assert_eq!(array.len(), 4);
+        assert_eq!(
+            view_with_header::<Header, u16>(&bytes[..4]),
+            Err(ViewError::OutOfBounds)
+        );
+
+        assert_eq!(read_le::<u16>(bytes, 1), Ok(0x0302));
+        assert_eq!(read_be::<u32>(bytes, 3), Ok(0x04050607));
+        assert_eq!(read_le::<u32>(bytes, 13), Err(ViewError::OutOfBounds));
+    }
+
+    #[test]
+    fn mmap_views() {
+        let mmapped: MMap<MMapDummy> = mmap_with_userfault(4096 * 2).unwrap();
+        // byte = (offset * 13) & 0xFF
+        assert_eq!(mmapped.read_be::<u16>(1), Ok(((13 << 8) | 26) as u16));
+        assert_eq!(mmapped.view::<u8>(4096..4098), Ok(&[0u8, 13][..]));
+        assert_eq!(mmapped.view::<u32>(0..4096 * 3), Err(ViewError::OutOfBounds));
+        assert_eq!(mmapped.view::<u64>(0..4096 * 2).map(|s| s.len()), Ok(1024));
+    }
+}